@@ -14,6 +14,9 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+// failure_derive generates its impls inside a named const block.
+#![allow(non_local_definitions)]
+
 use core::result;
 use failure::Fail;
 use std::io::{Error as IOError, ErrorKind as IOKind};
@@ -27,14 +30,49 @@ pub enum XcpError {
     #[fail(display = "Unknown file-type: {:?}", path)]
     UnknownFiletype { path: PathBuf },
 
+    #[fail(display = "Invalid arguments: {}", msg)]
+    InvalidArguments { msg: &'static str },
+
     #[fail(display = "Invalid source: {}", msg)]
     InvalidSource { msg: &'static str },
 
     #[fail(display = "Invalid destination: {}", msg)]
     InvalidDestination { msg: &'static str },
 
-    #[fail(display = "Destination Exists: {:?}", path)]
-    DestinationExists { msg: &'static str, path: PathBuf },
+    #[fail(display = "{:?} collides with {:?} on a case-insensitive destination", path, existing)]
+    CaseCollision { path: PathBuf, existing: PathBuf },
+
+    #[fail(display = "Out of space writing {:?} after {} bytes", path, written)]
+    OutOfSpace { path: PathBuf, written: u64 },
+
+    #[fail(display = "Copy failed after {} bytes: {}", copied, source)]
+    PartialCopy { copied: u64, #[fail(cause)] source: IOError },
+
+    #[fail(display = "Copy cancelled after {} bytes", copied)]
+    Cancelled { copied: u64 },
+
+    #[fail(display = "Copy of {:?} failed verification after {} attempts", path, attempts)]
+    VerifyFailed { path: PathBuf, attempts: u32 },
+
+    #[fail(display = "Creating device node {:?} requires privilege; use --device-contents to \
+                      copy the device's contents instead", path)]
+    DevicePermission { path: PathBuf },
+
+    #[fail(display = "Source {:?} is encrypted and its key isn't loaded; unlock it to copy \
+                      its contents", path)]
+    EncryptedSource { path: PathBuf },
+
+    #[fail(display = "The destination filesystem doesn't support ACLs")]
+    AclsUnsupported,
+
+    #[fail(display = "Refusing to overwrite or remove {:?}, which is in use by xcp itself", path)]
+    SelfOverwrite { path: PathBuf },
+
+    #[fail(display = "Malformed entry in map file {:?} at line {}", path, line)]
+    InvalidMapFile { path: PathBuf, line: usize },
+
+    #[fail(display = "{} entries couldn't be planned", count)]
+    PlanFailed { count: usize },
 
     #[fail(display = "Early shutdown: {:?}", msg)]
     EarlyShutdown { msg: &'static str },
@@ -44,5 +82,31 @@ pub fn io_err(kind: IOKind, desc: &str) -> Error {
     IOError::new(kind, desc).into()
 }
 
+/// Wrap an error from a copy loop with the number of bytes copied
+/// before it occurred. Errors that aren't IO errors are returned
+/// unchanged, and a `PartialCopy` has `base` added to its count, so
+/// nested loops can report the total.
+pub fn partial_copy(err: Error, base: u64) -> Error {
+    let err = match err.downcast::<XcpError>() {
+        Ok(XcpError::PartialCopy { copied, source }) => {
+            return XcpError::PartialCopy { copied: base + copied, source }.into();
+        }
+        Ok(xerr) => return xerr.into(),
+        Err(err) => err,
+    };
+    match err.downcast::<IOError>() {
+        Ok(source) => XcpError::PartialCopy { copied: base, source }.into(),
+        Err(err) => err,
+    }
+}
+
+/// The number of bytes copied before a failure, if known.
+pub fn copied_before(err: &Error) -> Option<u64> {
+    match err.downcast_ref::<XcpError>() {
+        Some(XcpError::PartialCopy { copied, .. }) => Some(*copied),
+        _ => None,
+    }
+}
+
 pub use failure::Error;
 pub type Result<T> = result::Result<T, Error>;
@@ -15,20 +15,34 @@
  */
 
 mod errors;
+mod manifest;
+mod mapfile;
 mod operations;
+mod options;
 mod os;
+mod plan;
 mod progress;
+#[cfg(feature = "selinux")]
+mod selinux;
+#[cfg(all(feature = "numa", target_os = "linux"))]
+mod numa;
+mod throttle;
 mod utils;
+mod wire;
 
 use log::info;
-use simplelog::{Config, LevelFilter, SimpleLogger, TermLogger};
+use simplelog::{Config, LevelFilter, SimpleLogger, TermLogger, WriteLogger};
+use std::io;
 use std::io::ErrorKind as IOKind;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 use crate::errors::{io_err, Result, XcpError};
-use crate::operations::{copy_single_file, copy_all};
-use crate::utils::expand_globs;
+use crate::operations::{copy_all, copy_mapped, copy_single_file, copy_stdio};
+use crate::options::{
+    CaseCollision, CopyMethodChain, Fsync, NameList, Owner, Preserve, Sparse, SymlinkMode,
+};
+use crate::utils::{expand_globs, parse_mode, parse_size, parse_timestamp, target_path};
 
 
 #[derive(Clone, Debug, StructOpt)]
@@ -47,10 +61,27 @@ pub struct Opts {
     #[structopt(short = "r", long = "recursive")]
     recursive: bool,
 
-    /// Do not overwrite an existing file
+    /// Do not overwrite an existing file; the copy is skipped
     #[structopt(short = "n", long = "no-clobber")]
     noclobber: bool,
 
+    /// Only overwrite an existing file if the source was modified
+    /// more recently; equal modification times are not copied
+    #[structopt(short = "u", long = "update", conflicts_with = "noclobber")]
+    update: bool,
+
+    /// Recreate each source's full path under the destination
+    /// directory, as with `cp --parents`. Relative symlinks that would
+    /// no longer resolve at their new depth are rewritten.
+    #[structopt(long = "parents")]
+    parents: bool,
+
+    /// Treat the destination as a normal file rather than a directory
+    /// to copy into: a directory source's contents are copied to it,
+    /// and a destination that's a symlink is never followed.
+    #[structopt(short = "T", long = "no-target-directory", conflicts_with = "parents")]
+    no_target_directory: bool,
+
     /// Use .gitignore if present. NOTE: This is fairly basic at the
     /// moment, and only honours a .gitignore in the directory root
     /// for directory copies; global or sub-directory ignores are
@@ -62,11 +93,239 @@ pub struct Opts {
     #[structopt(long = "no-progress")]
     noprogress: bool,
 
-    #[structopt(raw(required = "true", min_values = "1"))]
-    source_list: Vec<String>,
+    /// How to copy symlinks; one of `follow` (copy the target's
+    /// contents), `copy` (recreate the link) or `skip`. Defaults to
+    /// `copy` for recursive copies and `follow` otherwise, as with
+    /// `cp`.
+    #[structopt(long = "symlinks", parse(try_from_str))]
+    symlinks: Option<SymlinkMode>,
+
+    /// What to do if two source paths differ only by case and the
+    /// destination filesystem is case-insensitive; one of `error` or
+    /// `rename`.
+    #[structopt(long = "on-case-collision", default_value = "error", parse(try_from_str))]
+    on_case_collision: CaseCollision,
+
+    /// Record completed files in a manifest, skipping any already
+    /// recorded there whose source hasn't changed since. This allows
+    /// an interrupted recursive copy to be restarted.
+    #[structopt(long = "manifest", parse(from_os_str))]
+    manifest: Option<PathBuf>,
+
+    /// Copy the files listed in this file, one `SOURCE<TAB>DEST` pair
+    /// per line, each to its own destination. Parent directories of
+    /// the destinations are created as needed. No other paths may be
+    /// given.
+    #[structopt(long = "map-file", parse(from_os_str), conflicts_with = "paths")]
+    map_file: Option<PathBuf>,
+
+    /// Comma-separated list of attributes to preserve. Supported are
+    /// `mode` (the default), `ownership`, `acl`, `timestamps`, `xattr`, `links`
+    /// (recreate hardlinks between copied files), `flags` (inode flags
+    /// such as immutable; Linux only) and `context`, which applies the
+    /// SELinux context the policy assigns to the destination (requires
+    /// the `selinux` feature).
+    #[structopt(long = "preserve", default_value = "mode", parse(try_from_str))]
+    preserve: Preserve,
+
+    /// Give every copied file this mode (in octal), rather than the
+    /// source's.
+    #[structopt(long = "uniform-mode", value_name = "MODE",
+                parse(try_from_str = "parse_mode"))]
+    uniform_mode: Option<u32>,
+
+    /// Give every copied directory this mode (in octal).
+    #[structopt(long = "uniform-dir-mode", value_name = "MODE",
+                parse(try_from_str = "parse_mode"))]
+    uniform_dir_mode: Option<u32>,
+
+    /// Give every copied file and directory this owner, as `USER`,
+    /// `USER:GROUP` or `:GROUP`.
+    #[structopt(long = "uniform-owner", value_name = "OWNER", parse(try_from_str))]
+    uniform_owner: Option<Owner>,
+
+    /// Comma-separated list of extended attributes to copy; others
+    /// are skipped. Implies `--preserve=xattr`.
+    #[structopt(long = "xattr-name", value_name = "NAMES", parse(try_from_str))]
+    xattr_name: Option<NameList>,
+
+    /// Comma-separated list of extended attributes not to copy.
+    #[structopt(long = "xattr-exclude", value_name = "NAMES", parse(try_from_str))]
+    xattr_exclude: Option<NameList>,
+
+    /// Set the access and modification times of copied files,
+    /// directories and symlinks to a fixed value, either RFC 3339
+    /// (e.g. `2020-01-01T00:00:00Z`) or `@` followed by seconds since
+    /// the epoch. Overrides `--preserve=timestamps`.
+    #[structopt(long = "mtime", value_name = "TIME",
+                parse(try_from_str = "parse_timestamp"))]
+    mtime: Option<i64>,
+
+    /// Whether to preserve holes in sparse files; one of `auto`
+    /// (if the source appears sparse), `always` or `never`.
+    #[structopt(long = "sparse", default_value = "auto", parse(try_from_str))]
+    sparse: Sparse,
+
+    /// When copying sparsely, only preserve holes of at least this
+    /// size; smaller ones are filled in. Accepts K, M and G suffixes.
+    #[structopt(long = "min-hole-size", value_name = "SIZE",
+                parse(try_from_str = "parse_size"))]
+    min_hole_size: Option<u64>,
+
+    /// Copy the contents of block and character devices, rather than
+    /// recreating the device node.
+    #[structopt(long = "device-contents")]
+    device_contents: bool,
+
+    /// Copy the data written to named pipes (FIFOs), waiting for a
+    /// writer, rather than recreating the pipe.
+    #[structopt(long = "fifo-contents")]
+    fifo_contents: bool,
+
+    /// Allocate the destination's blocks before copying, which can
+    /// reduce fragmentation.
+    #[structopt(long = "preallocate")]
+    preallocate: bool,
+
+    /// Drop copied files from the page cache once they're complete,
+    /// so large copies don't evict more useful data. Most effective
+    /// with `--fsync=file`, as only pages already written to disk can
+    /// be dropped immediately.
+    #[structopt(long = "drop-cache")]
+    drop_cache: bool,
+
+    /// Don't write blocks that are entirely zero, leaving holes in the
+    /// destination instead. This catches runs of zeros in sources
+    /// that aren't themselves sparse, at the cost of scanning the
+    /// data in userspace.
+    #[structopt(long = "skip-zeros")]
+    skip_zeros: bool,
+
+    /// Copy so that the same source always gives an identical tree:
+    /// entries are copied in sorted order, modes are normalised to
+    /// 0644 or 0755, and everything is given a fixed mtime
+    /// (`SOURCE_DATE_EPOCH`, or the epoch) and, when run as root, owner
+    /// `0:0`. An explicit `--mtime` or `--uniform-*` option is
+    /// respected.
+    #[structopt(long = "reproducible")]
+    reproducible: bool,
+
+    /// Report the number of extents in each copied file, warning
+    /// about those with more than this many.
+    #[structopt(long = "report-fragmentation", value_name = "EXTENTS")]
+    report_fragmentation: Option<u64>,
+
+    /// Sync copied files to disk; one of `file` (each file as it is
+    /// copied), `batch` (files in groups, which is faster for many
+    /// small files) or `syncfs` (the whole destination filesystem
+    /// once the copy completes).
+    #[structopt(long = "fsync", value_name = "MODE", raw(require_equals = "true"))]
+    fsync: Option<Fsync>,
+
+    /// Report what would be copied, and how, without writing anything.
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// Copy each file to a temporary name alongside the destination
+    /// and rename it into place once complete, so a failed copy never
+    /// leaves a partial file or clobbers an existing one. Recursive
+    /// copies do this by default for files that don't exist yet, but
+    /// write existing files in place so hardlinks to them are kept.
+    #[structopt(long = "atomic")]
+    atomic: bool,
+
+    /// Write files in place, even for recursive copies.
+    #[structopt(long = "no-atomic", conflicts_with = "atomic")]
+    no_atomic: bool,
+
+    /// If the destination is shorter than the source, assume it's an
+    /// interrupted copy and continue from where it left off. The
+    /// copy is restarted if the source has been modified since.
+    /// Files are always written in place when resuming.
+    #[structopt(long = "resume")]
+    resume: bool,
+
+    /// As --resume, but also check the existing destination matches
+    /// the start of the source before resuming.
+    #[structopt(long = "verify-resume")]
+    verify_resume: bool,
+
+    /// Don't copy any data; instead apply the sources' mode, owner,
+    /// timestamps and extended attributes to destination files that
+    /// already have the same size and mtime. Other files are skipped.
+    #[structopt(long = "metadata-only")]
+    metadata_only: bool,
+
+    /// As --metadata-only, but compare the file contents rather than
+    /// the size and mtime.
+    #[structopt(long = "verify-metadata-only")]
+    verify_metadata_only: bool,
+
+    /// Compare each copied file against its source, and re-copy it up
+    /// to this many times if they differ. Files that never match are
+    /// reported as errors.
+    #[structopt(long = "self-heal", value_name = "ATTEMPTS")]
+    self_heal: Option<u32>,
+
+    /// Retry a failed write up to this many times, with exponential
+    /// backoff, if the error is transient (EAGAIN, ESTALE or EBUSY, as
+    /// seen on NFS). Other errors still fail the copy immediately.
+    #[structopt(long = "retries", value_name = "ATTEMPTS")]
+    retries: Option<u32>,
+
+    /// Limit the write rate to this many bytes per second. Accepts
+    /// K, M and G suffixes.
+    #[structopt(long = "bwlimit", parse(try_from_str = "parse_size"))]
+    bwlimit: Option<u64>,
+
+    /// Comma-separated list of copy methods to try, in order. Valid
+    /// methods are `reflink`, `mmap` (used for files under 64KB, where
+    /// it saves syscalls), `cfr` (copy_file_range) and `userspace`.
+    #[structopt(long = "methods", default_value = "reflink,cfr,userspace",
+                parse(try_from_str))]
+    methods: CopyMethodChain,
 
-    #[structopt(parse(from_os_str))]
-    dest: PathBuf,
+    /// With a destination of `-`, write xcp's wire format to stdout,
+    /// which preserves holes in sparse files; with a source of `-`,
+    /// read the wire format from stdin.
+    #[structopt(long = "wire")]
+    wire: bool,
+
+    /// The source(s) followed by the destination. These are parsed as
+    /// a single list as clap can't reliably separate a
+    /// multiple-value positional from a trailing one when options
+    /// with values are present. A single source or destination of `-`
+    /// reads from stdin or writes to stdout.
+    #[structopt(raw(required_unless = "\"map_file\"", min_values = "2"))]
+    paths: Vec<String>,
+}
+
+impl Opts {
+    fn symlink_mode(&self) -> SymlinkMode {
+        match self.symlinks {
+            Some(mode) => mode,
+            None if self.recursive => SymlinkMode::Copy,
+            None => SymlinkMode::Follow,
+        }
+    }
+}
+
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// Copies into a destination directory that's a symlink go to the
+/// directory it points to. That is resolved up-front so that e.g.
+/// depth calculations for `--parents`, and the filesystem the copy
+/// methods are probed on, refer to the real directory rather than to
+/// the path the link is at.
+fn resolve_dest_dir(dest: PathBuf) -> Result<PathBuf> {
+    if !is_symlink(&dest) || !dest.is_dir() {
+        return Ok(dest);
+    }
+    let real = dest.canonicalize()?;
+    info!("Destination {:?} is a symlink to {:?}", dest, real);
+    Ok(real)
 }
 
 fn main() -> Result<()> {
@@ -78,33 +337,111 @@ fn main() -> Result<()> {
         2 => LevelFilter::Debug,
         _ => LevelFilter::Trace,
     };
-    TermLogger::init(log_level, Config::default())
-        .or_else(|_| SimpleLogger::init(log_level, Config::default()))?;
+    // Keep stdout clean when it's the copy destination.
+    if opts.paths.last().map(String::as_str) == Some("-") {
+        WriteLogger::init(log_level, Config::default(), io::stderr())?;
+    } else {
+        TermLogger::init(log_level, Config::default())
+            .or_else(|_| SimpleLogger::init(log_level, Config::default()))?;
+    }
+
+    if opts.preserve.context && !cfg!(feature = "selinux") {
+        return Err(XcpError::InvalidArguments {
+            msg: "--preserve=context requires xcp to be built with the selinux feature",
+        }.into());
+    }
+
+    if let Some(ref map_file) = opts.map_file {
+        info!("Copying files listed in {:?}", map_file);
+        return copy_mapped(map_file, &opts);
+    }
+
+    let (dest, source_list) = opts.paths.split_last()
+        .ok_or(XcpError::InvalidArguments { msg: "Insufficient arguments" })?;
+
+    if source_list.iter().any(|s| s == "-") || dest == "-" {
+        if source_list.len() > 1 {
+            return Err(XcpError::InvalidArguments {
+                msg: "Only a single source may be used with stdin/stdout",
+            }.into());
+        }
+        if opts.wire && source_list[0] == "-" && dest == "-" {
+            return Err(XcpError::InvalidArguments {
+                msg: "--wire requires a source or destination that isn't stdin/stdout",
+            }.into());
+        }
+        if opts.dry_run {
+            return Err(XcpError::InvalidArguments {
+                msg: "--dry-run can't be used with stdin/stdout",
+            }.into());
+        }
+        info!("Copying {} to {}", source_list[0], dest);
+        return copy_stdio(&source_list[0], dest, &opts);
+    }
+
+    let dest = PathBuf::from(dest);
+    let dest = if opts.no_target_directory {
+        if source_list.len() > 1 {
+            return Err(XcpError::InvalidArguments {
+                msg: "--no-target-directory takes a single source",
+            }.into());
+        }
+        dest
+    } else {
+        resolve_dest_dir(dest)?
+    };
 
     // Do this check before expansion otherwise it could result in
     // unexpected behaviour when the a glob expands to a single file.
-    if opts.source_list.len() > 1 && !opts.dest.is_dir() {
+    if source_list.len() > 1 && !dest.is_dir() {
         return Err(XcpError::InvalidDestination {
             msg: "Multiple sources and destination is not a directory.",
         }
         .into());
     }
 
-    let sources = expand_globs(&opts.source_list)?;
+    let sources = expand_globs(source_list)?;
+    // A file copied to e.g. `missing/` doesn't get a directory created
+    // for it.
+    if sources.len() == 1 && !sources[0].is_dir() && !opts.no_target_directory {
+        target_path(&sources[0], &dest)?;
+    }
     if sources.is_empty() {
         return Err(io_err(IOKind::NotFound, "No source files found."));
 
-    } else if sources.len() == 1 && opts.dest.is_file() {
+    } else if opts.parents && !dest.is_dir() {
+        return Err(XcpError::InvalidDestination {
+            msg: "--parents requires the destination to be a directory",
+        }.into());
+
+    } else if opts.no_target_directory && sources.len() > 1 {
+        return Err(XcpError::InvalidArguments {
+            msg: "--no-target-directory takes a single source",
+        }.into());
+
+    } else if opts.no_target_directory && !sources[0].is_dir() && dest.is_dir() {
+        return Err(XcpError::InvalidDestination {
+            msg: "Cannot overwrite a directory with a file",
+        }.into());
+
+    } else if opts.no_target_directory && is_symlink(&dest) && sources[0].is_dir() {
+        return Err(XcpError::InvalidDestination {
+            msg: "Destination is a symlink, which --no-target-directory doesn't follow",
+        }.into());
+
+    } else if sources.len() == 1 && dest.is_file() {
         // Special case; rename/overwrite.
-        info!("Copying file {:?} to {:?}", sources[0], opts.dest);
-        copy_single_file(&sources[0], &opts)?;
+        info!("Copying file {:?} to {:?}", sources[0], dest);
+        copy_single_file(&sources[0], &dest, &opts)?;
 
     } else {
 
         // Sanity-check all sources up-front
         for source in &sources {
-            info!("Copying source {:?} to {:?}", source, opts.dest);
-            if !source.exists() {
+            info!("Copying source {:?} to {:?}", source, dest);
+            // Don't dereference here; a broken link is a valid source
+            // unless we're following links.
+            if source.symlink_metadata().is_err() {
                 return Err(io_err(IOKind::NotFound, "Source does not exist."));
             }
 
@@ -114,14 +451,14 @@ fn main() -> Result<()> {
                 }.into())
             }
 
-            if opts.dest.exists() && !opts.dest.is_dir() {
+            if dest.exists() && !dest.is_dir() {
                 return Err(XcpError::InvalidDestination {
                     msg: "Source is directory but target exists and is not a directory",
                 }.into());
             }
         }
 
-        copy_all(sources, &opts)?;
+        copy_all(sources, &dest, &opts)?;
     }
 
     Ok(())
@@ -0,0 +1,124 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{read, File, Metadata, OpenOptions};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::Result;
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct Stamp {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl Stamp {
+    fn from_meta(meta: &Metadata) -> Stamp {
+        Stamp {
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+        }
+    }
+}
+
+/// A persistent record of completed file copies, allowing an
+/// interrupted tree copy to be restarted without recopying files that
+/// were already finished. Each record holds the size and mtime of the
+/// source at the time it was copied, followed by its path. As paths
+/// may contain any byte but NUL, records are NUL-terminated:
+///
+/// ```text
+/// <size>\t<mtime>\t<mtime_nsec>\t<path>\0
+/// ```
+///
+/// Entries are appended and flushed as each file completes, so the
+/// manifest is always valid up to the point of interruption.
+pub struct Manifest {
+    entries: HashMap<PathBuf, Stamp>,
+    out: File,
+}
+
+fn parse_record(record: &[u8]) -> Option<(PathBuf, Stamp)> {
+    let mut fields = record.splitn(4, |b| *b == b'\t');
+    let mut num = || -> Option<i64> {
+        std::str::from_utf8(fields.next()?).ok()?.parse().ok()
+    };
+    let size = num()? as u64;
+    let mtime = num()?;
+    let mtime_nsec = num()?;
+    let path = PathBuf::from(OsStr::from_bytes(fields.next()?));
+
+    Some((path, Stamp { size, mtime, mtime_nsec }))
+}
+
+impl Manifest {
+    /// Load a manifest from a previous run if one exists, and open it
+    /// for appending.
+    pub fn open(path: &Path) -> Result<Manifest> {
+        let mut entries = HashMap::new();
+
+        if path.exists() {
+            let data = read(path)?;
+            let mut records = data.split(|b| *b == 0);
+            // Anything after the last NUL is a partial write from an
+            // interrupted run.
+            if records.next_back().is_some_and(|r| !r.is_empty()) {
+                warn!("Ignoring incomplete manifest entry in {:?}", path);
+            }
+            for record in records {
+                match parse_record(record) {
+                    Some((file, stamp)) => { entries.insert(file, stamp); }
+                    None => warn!("Ignoring malformed manifest entry in {:?}", path),
+                }
+            }
+            debug!("Loaded {} manifest entries from {:?}", entries.len(), path);
+        }
+
+        let out = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Manifest { entries, out })
+    }
+
+    /// Whether the source was already copied by a previous run and
+    /// hasn't changed since.
+    pub fn is_current(&self, source: &Path, meta: &Metadata) -> bool {
+        self.entries.get(source) == Some(&Stamp::from_meta(meta))
+    }
+
+    /// Record a completed copy of `source`.
+    pub fn record(&mut self, source: &Path, meta: &Metadata) -> Result<()> {
+        let stamp = Stamp::from_meta(meta);
+        write!(self.out, "{}\t{}\t{}\t", stamp.size, stamp.mtime, stamp.mtime_nsec)?;
+        self.out.write_all(source.as_os_str().as_bytes())?;
+        self.out.write_all(b"\0")?;
+        self.out.flush()?;
+
+        self.entries.insert(source.to_path_buf(), stamp);
+        Ok(())
+    }
+}
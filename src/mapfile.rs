@@ -0,0 +1,84 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::{Result, XcpError};
+
+
+fn parse_line(line: &[u8]) -> Option<(PathBuf, PathBuf)> {
+    let mut fields = line.splitn(2, |b| *b == b'\t');
+    let from = fields.next().filter(|f| !f.is_empty())?;
+    let to = fields.next().filter(|f| !f.is_empty())?;
+    Some((PathBuf::from(OsStr::from_bytes(from)), PathBuf::from(OsStr::from_bytes(to))))
+}
+
+/// Read a map file of source and destination pairs. Each line holds
+/// a source path and the path to copy it to, separated by a tab:
+///
+/// ```text
+/// <source>\t<destination>
+/// ```
+///
+/// Paths may contain any bytes other than tab and newline. Blank lines
+/// are ignored; any other line without both paths is an error.
+pub fn read_map_file(path: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut mapping = Vec::new();
+    for (n, line) in BufReader::new(File::open(path)?).split(b'\n').enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let pair = parse_line(&line).ok_or_else(|| XcpError::InvalidMapFile {
+            path: path.to_path_buf(),
+            line: n + 1,
+        })?;
+        mapping.push(pair);
+    }
+    Ok(mapping)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_map_file() -> Result<()> {
+        let dir = tempdir()?;
+        let map = dir.path().join("map.txt");
+        write(&map, "a.txt\tout/a.txt\n\nsrc/b c.txt\t/tmp/b.txt\n")?;
+
+        assert_eq!(read_map_file(&map)?, vec![
+            (PathBuf::from("a.txt"), PathBuf::from("out/a.txt")),
+            (PathBuf::from("src/b c.txt"), PathBuf::from("/tmp/b.txt")),
+        ]);
+
+        write(&map, "a.txt\tout/a.txt\nb.txt\n")?;
+        match read_map_file(&map).unwrap_err().downcast::<XcpError>()? {
+            XcpError::InvalidMapFile { line, .. } => assert_eq!(line, 2),
+            e => panic!("Expected InvalidMapFile, got {}", e),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Placement of copy workers on NUMA systems. Each worker is pinned
+//! to a core, and its memory policy set so the copy buffers it
+//! allocates come from that core's node rather than wherever the
+//! allocator first touched.
+
+use log::debug;
+use std::fs;
+use std::io;
+use std::mem;
+use std::ptr::null;
+
+use crate::errors::Result;
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+/// Allocate from the node of the CPU the thread is running on; see
+/// set_mempolicy(2).
+const MPOL_LOCAL: libc::c_int = 4;
+
+/// Parse a kernel CPU list, e.g. `0-3,8,10-11`.
+fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut ends = range.splitn(2, '-');
+        let start: usize = ends.next()?.parse().ok()?;
+        let end = match ends.next() {
+            Some(end) => end.parse().ok()?,
+            None => start,
+        };
+        cpus.extend(start..=end);
+    }
+    Some(cpus)
+}
+
+/// The NUMA nodes with CPUs, as (node, CPUs) in node order. Nodes
+/// with only memory are left out, as workers can't run on them.
+fn topology() -> Result<Vec<(usize, Vec<usize>)>> {
+    let mut nodes = Vec::new();
+    for entry in fs::read_dir(NODE_DIR)? {
+        let entry = entry?;
+        let node = match entry.file_name().to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|n| n.parse().ok())
+        {
+            Some(node) => node,
+            None => continue,
+        };
+        let cpus = parse_cpulist(&fs::read_to_string(entry.path().join("cpulist"))?)
+            .unwrap_or_default();
+        if !cpus.is_empty() {
+            nodes.push((node, cpus));
+        }
+    }
+    nodes.sort();
+    Ok(nodes)
+}
+
+/// Choose the (node, CPU) for the `worker`th worker. Workers are
+/// spread across nodes first, so each has its own memory bandwidth,
+/// and then across the CPUs within each node. There's nothing to
+/// choose with fewer than two nodes.
+fn select_cpu(nodes: &[(usize, Vec<usize>)], worker: usize) -> Option<(usize, usize)> {
+    if nodes.len() < 2 {
+        return None;
+    }
+    let (node, ref cpus) = nodes[worker % nodes.len()];
+    Some((node, cpus[(worker / nodes.len()) % cpus.len()]))
+}
+
+/// Pin the calling thread, the `worker`th copy worker, to a CPU and
+/// make its allocations local to that CPU's node. This is a no-op on
+/// systems without multiple NUMA nodes.
+pub fn pin_worker(worker: usize) -> Result<()> {
+    let nodes = match topology() {
+        Ok(nodes) => nodes,
+        // No NUMA support in the kernel.
+        Err(_) if !std::path::Path::new(NODE_DIR).exists() => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let (node, cpu) = match select_cpu(&nodes, worker) {
+        Some(selected) => selected,
+        None => return Ok(()),
+    };
+
+    debug!("Pinning worker {} to CPU {} on node {}", worker, cpu, node);
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if libc::syscall(libc::SYS_set_mempolicy, MPOL_LOCAL, null::<libc::c_ulong>(), 0) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(parse_cpulist("0-3,8,10-11\n"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpulist("\n"), Some(vec![]));
+        assert_eq!(parse_cpulist("0-x"), None);
+    }
+
+    #[test]
+    fn test_select_cpu() {
+        let nodes = vec![(0, vec![0, 1]), (2, vec![4, 5])];
+        let selected = (0..5).map(|w| select_cpu(&nodes, w)).collect::<Vec<_>>();
+        assert_eq!(selected, vec![Some((0, 0)), Some((2, 4)), Some((0, 1)), Some((2, 5)),
+                                  Some((0, 0))]);
+
+        assert_eq!(select_cpu(&[(0, vec![0, 1, 2, 3])], 1), None);
+        assert_eq!(select_cpu(&[], 0), None);
+    }
+
+    #[test]
+    fn test_pin_worker() -> Result<()> {
+        let nodes = topology().unwrap_or_default();
+        let expected = select_cpu(&nodes, 1).map(|(_, cpu)| cpu);
+
+        let pinned = thread::spawn(|| -> Result<Vec<usize>> {
+            pin_worker(1)?;
+            let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+            let r = unsafe {
+                libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set)
+            };
+            assert_eq!(r, 0);
+            Ok((0..libc::CPU_SETSIZE as usize)
+               .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+               .collect())
+        }).join().unwrap()?;
+
+        // Without multiple nodes the worker is left unpinned.
+        if let Some(cpu) = expected {
+            assert_eq!(pinned, vec![cpu]);
+        }
+
+        Ok(())
+    }
+}
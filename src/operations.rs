@@ -15,342 +15,3661 @@
  */
 
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use log::{debug, error, info};
+use log::{debug, error, info, trace, warn};
 use std::cmp;
-use std::fs::{create_dir_all, read_link, File};
-use std::io::ErrorKind as IOKind;
-use std::os::unix::fs::symlink;
-use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, remove_file, rename, File, Metadata, OpenOptions, Permissions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, ErrorKind as IOKind, Seek, SeekFrom};
+use std::mem::ManuallyDrop;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, OnceLock};
 use std::thread;
+use std::time::Duration;
 use walkdir::{DirEntry, WalkDir};
 
-use crate::errors::{io_err, Result, XcpError};
-use crate::os::{allocate_file, copy_file_bytes, probably_sparse, lseek, Wence, SeekOff};
+use crate::errors::{copied_before, io_err, partial_copy, Error, Result, XcpError};
+use crate::manifest::Manifest;
+use crate::mapfile::read_map_file;
+use crate::options::{
+    Atomic, CaseCollision, Clobber, CopyMethod, CopyOptions, Fsync, InspectFn, MetadataOnly,
+    Resume, Sparse, SymlinkMode,
+};
+use crate::os::{
+    allocate_file, allocate_range, cfr_supported, chown, copy_acls,
+    copy_bytes_inspected, copy_bytes_skip_zeros, copy_bytes_uspace, copy_file_bytes_checked,
+    copy_inode_flags, copy_small_mmap, copy_xattrs, cursor, drop_cache, errno, extent_count,
+    fchown, fiemap, fstat, is_device, is_encrypted, is_fifo, is_reflink_unsupported, is_regular,
+    is_small_file, is_transient, is_unsupported, linkat, lseek, mapped_files,
+    meta_probably_sparse, missing_key, mkfifo, mknod, preallocate, readlink, reflink,
+    set_link_times, set_times, symlinkat, syncfs, Extent, SeekOff, Wence, MMAP_THRESHOLD,
+};
+#[cfg(target_os = "macos")]
+use crate::os::clone_file;
+use crate::plan::{predict_method, CopyPlan, Decision, PlannedCopy};
 use crate::progress::{
-    iprogress_bar, BatchUpdater, NopUpdater, ProgressBar, ProgressUpdater, StatusUpdate, Updater,
-    BATCH_DEFAULT,
+    iprogress_bar, BatchUpdater, CopyObserver, CopyStats, NopObserver, NopUpdater, ObserverUpdater,
+    ProgressBar, ProgressUpdater, StatusUpdate, Updater, BATCH_DEFAULT,
 };
-use crate::utils::{FileType, ToFileType};
+use crate::throttle::{copy_bytes_throttled, Throttle};
+use crate::utils::{
+    absolute, is_case_insensitive, normalize, relative_path, target_path, FileType, ToFileType,
+};
+use crate::wire;
 use crate::Opts;
 
 
 #[derive(Debug)]
 enum Operation {
-    Copy(PathBuf, PathBuf),
+    /// Copy a file, with the progress size it was scanned with.
+    Copy(PathBuf, PathBuf, u64),
     Link(PathBuf, PathBuf),
-    CreateDir(PathBuf),
+    /// Create a directory, with the source directory it copies.
+    CreateDir(PathBuf, PathBuf),
     End,
 }
 
+/// How a file's data was actually copied, after any fallback.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CopyKind {
+    /// The data was shared with a reflink, so nothing was copied.
+    Reflinked,
+    CopyFileRange,
+    /// Copied through a userspace buffer or mmap.
+    ReadWrite,
+    /// Copied by the hole-preserving copier.
+    Sparse,
+    /// No data was copied; a device node was recreated, or only the
+    /// metadata synced.
+    Metadata,
+    /// The destination was left as it was, e.g. with `--no-clobber`.
+    Skipped,
+}
+
+
+/// Copy from the descriptor cursors with each of `methods` in turn
+/// until one works, returning the number of bytes copied and the
+/// method that copied them. `copy` returns `None` for methods that
+/// don't apply. Only errors meaning the method isn't supported here
+/// (see `is_unsupported()`) fall through to the next method, with the
+/// cursors rewound; anything else is returned immediately.
+fn copy_with_fallback<I, F>(infd: &File, outfd: &File, methods: I,
+                            mut copy: F) -> Result<(u64, CopyMethod)>
+    where I: Iterator<Item = CopyMethod>,
+          F: FnMut(CopyMethod) -> Result<Option<u64>>
+{
+    let (inpos, outpos) = (cursor(infd)?, cursor(outfd)?);
+    let mut last_err = None;
+
+    for method in methods {
+        match copy(method) {
+            Ok(Some(n)) => {
+                trace!("{:?}: copied {} bytes", method, n);
+                return Ok((n, method));
+            }
+            Ok(None) => {}
+            Err(e) if is_unsupported(&e) => {
+                debug!("{:?} not supported, trying next method: {}", method, e);
+                lseek(infd, inpos as i64, Wence::Set)?;
+                lseek(outfd, outpos as i64, Wence::Set)?;
+                last_err = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io_err(IOKind::Other, "No byte-copy method available.")))
+}
+
+/// Copy up to `bytes` from the descriptor cursors using the first
+/// byte-copy method in the chain that's supported, returning the
+/// number of bytes copied and the method used. If the source is
+/// `small` (see `is_small_file()`) copy_file_range is passed over for
+/// the userspace copy, where that's in the chain. Data copied is
+/// passed to `inspect` rather than `opts.inspect`, so the caller can
+/// wrap it.
+fn copy_chunk(infd: &File, outfd: &File, bytes: u64, small: bool, opts: &CopyOptions,
+              stats: &mut CopyStats, inspect: Option<&InspectFn>) -> Result<(u64, CopyMethod)> {
+    let inpos = cursor(infd)?;
+    // Finding zeros means looking at the data.
+    if opts.skip_zeros {
+        let n = copy_bytes_skip_zeros(infd, outfd, bytes, inpos, stats, inspect)?;
+        return Ok((n, CopyMethod::Userspace));
+    }
+    // As does passing it to the inspector.
+    if let Some(inspect) = inspect {
+        let n = copy_bytes_inspected(infd, outfd, bytes, inpos, stats, inspect)?;
+        return Ok((n, CopyMethod::Userspace));
+    }
+
+    let skip_cfr = small && opts.methods.contains(CopyMethod::Userspace);
+    let methods = opts.methods.byte_methods()
+        .filter(|m| !(skip_cfr && *m == CopyMethod::CopyFileRange));
+    copy_with_fallback(infd, outfd, methods, |method| match method {
+        CopyMethod::CopyFileRange if !cfr_supported(infd, outfd)? => Ok(None),
+        CopyMethod::CopyFileRange => copy_file_bytes_checked(infd, outfd, bytes).map(Some),
+        CopyMethod::Userspace => copy_bytes_uspace(infd, outfd, bytes, stats).map(Some),
+        CopyMethod::Reflink | CopyMethod::Mmap => Ok(None),
+    })
+}
+
+/// The delay before the first retry of a transient error; doubled
+/// after each.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Copy `bytes` from the descriptor cursors with `copy`, retrying up
+/// to `retries` times if it fails with a transient error (see
+/// `is_transient()`). A failed attempt may have copied part of the
+/// chunk, so the cursors are reset before each retry, and the bytes it
+/// counted into `stats` are taken back out; the time spent is kept.
+fn copy_bytes_retrying<F>(infd: &File, outfd: &File, bytes: u64, retries: u32,
+                          stats: &mut CopyStats, mut copy: F) -> Result<u64>
+    where F: FnMut(&File, &File, u64, &mut CopyStats) -> Result<u64>
+{
+    let (inpos, outpos) = (cursor(infd)?, cursor(outfd)?);
+    let (read_bytes, write_bytes) = (stats.read_bytes, stats.write_bytes);
+    let mut delay = RETRY_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match copy(infd, outfd, bytes, stats) {
+            Err(ref e) if attempt < retries && is_transient(e) => {
+                warn!("Transient error while copying, retrying in {:?}: {}", delay, e);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+                stats.read_bytes = read_bytes;
+                stats.write_bytes = write_bytes;
+                (&*infd).seek(SeekFrom::Start(inpos))?;
+                (&*outfd).seek(SeekFrom::Start(outpos))?;
+            }
+            r => return r,
+        }
+    }
+}
+
+/// Wrap `inspect` so that data at offsets below the highest one seen
+/// so far isn't passed on again, as when a chunk is retried after
+/// being partly copied.
+fn inspect_once(inspect: Arc<InspectFn>) -> impl Fn(&[u8], u64) + Send + Sync {
+    let seen = AtomicU64::new(0);
+    move |buf, off| {
+        let end = off + buf.len() as u64;
+        let from = cmp::max(off, seen.load(Ordering::Relaxed));
+        if from < end {
+            inspect(&buf[(from - off) as usize..], from);
+            seen.store(end, Ordering::Relaxed);
+        }
+    }
+}
 
-/// Copy len bytes from whereever the descriptor cursors are set.
-fn copy_range(infd: &File, outfd: &File, len: u64, updates: &mut BatchUpdater) -> Result<u64> {
+/// Copy len bytes from whereever the descriptor cursors are set,
+/// returning the number copied and how. On failure the error is an
+/// `XcpError::PartialCopy` carrying the number of bytes copied; a
+/// source that ends early (e.g. because it was truncated during the
+/// copy) fails with `UnexpectedEof`.
+fn copy_range(infd: &File, outfd: &File, len: u64, opts: &CopyOptions,
+              throttle: &mut Option<Throttle>, stats: &mut CopyStats,
+              updates: &mut BatchUpdater) -> Result<(u64, CopyKind)>
+{
+    let small = is_small_file(&infd.metadata()?);
     let mut written = 0u64;
+    let mut driver = None;
+    let inspect_once = opts.inspect.as_ref().map(|i| inspect_once(i.0.clone()));
+    let inspect = inspect_once.as_ref().map(|i| i as &InspectFn);
     while written < len {
         let bytes_to_copy = cmp::min(len - written, updates.batch_size);
-        let result = copy_file_bytes(&infd, &outfd, bytes_to_copy)?;
+        let mut chunk = |i: &File, o: &File, b: u64, stats: &mut CopyStats| {
+            let (n, method) = copy_chunk(i, o, b, small, opts, stats, inspect)?;
+            if driver != Some(method) {
+                debug!("Copying with {:?}", method);
+                driver = Some(method);
+            }
+            Ok(n)
+        };
+        let mut copy = |i: &File, o: &File, b: u64| match opts.retries {
+            Some(retries) => copy_bytes_retrying(i, o, b, retries, stats, &mut chunk),
+            None => chunk(i, o, b, stats),
+        };
+        let result = match throttle {
+            Some(throttle) => copy_bytes_throttled(infd, outfd, bytes_to_copy, throttle, copy),
+            None => copy(infd, outfd, bytes_to_copy),
+        }.map_err(|e| partial_copy(e, written))?;
+        if result == 0 {
+            let eof = io_err(IOKind::UnexpectedEof, "Source ended before the expected length.");
+            return Err(partial_copy(eof, written));
+        }
         written += result;
         updates.update(Ok(result))?;
     }
 
-    Ok(written)
+    let kind = match driver {
+        Some(CopyMethod::CopyFileRange) => CopyKind::CopyFileRange,
+        _ => CopyKind::ReadWrite,
+    };
+    Ok((written, kind))
 }
 
-fn next_sparse_segments(fd: &File, pos: u64) -> Result<(u64, u64)> {
+/// The next (start, end) range of data at or after `pos`, or `None`
+/// if the rest of the file is a hole.
+fn next_sparse_segment(fd: &File, pos: u64, len: u64) -> Result<Option<(u64, u64)>> {
     let next_data = match lseek(fd, pos as i64, Wence::Data)? {
-        SeekOff::Offset(off) => off,
-        SeekOff::EOF => fd.metadata()?.len()
+        SeekOff::Offset(off) if off < len => off,
+        // Either a trailing hole, or the file has been truncated
+        // since `len` was taken.
+        SeekOff::Offset(_) | SeekOff::NoData | SeekOff::EOF => return Ok(None),
     };
     let next_hole = match lseek(fd, next_data as i64, Wence::Hole)? {
-        SeekOff::Offset(off) => off,
-        SeekOff::EOF => fd.metadata()?.len()
+        SeekOff::Offset(off) => cmp::min(off, len),
+        SeekOff::NoData | SeekOff::EOF => len,
     };
 
-    Ok((next_data, next_hole))
+    Ok(Some((next_data, next_hole)))
 }
 
-fn copy_sparse(infd: &File, outfd: &File, updates: &mut BatchUpdater) -> Result<u64> {
-    let len = infd.metadata()?.len();
-    allocate_file(&outfd, len)?;
-
+fn lseek_segments(fd: &File, len: u64) -> Result<Vec<(u64, u64)>> {
+    let mut segments = Vec::new();
     let mut pos = 0;
 
     while pos < len {
-        let (next_data, next_hole) = next_sparse_segments(infd, pos)?;
-        lseek(infd, next_data as i64, Wence::Set)?;  // FIXME: EOF (but shouldn't happen)
-        lseek(outfd, next_data as i64, Wence::Set)?;
-
-        let _written = copy_range(infd, outfd, next_hole - next_data, updates)?;
-        pos = next_hole;
+        match next_sparse_segment(fd, pos, len)? {
+            Some((start, end)) if end > start => {
+                segments.push((start, end));
+                pos = end;
+            }
+            _ => break,
+        }
     }
 
-    Ok(len)
+    Ok(segments)
 }
 
-fn copy_file(from: &Path, to: &Path, updates: &mut BatchUpdater) -> Result<u64> {
-    let infd = File::open(from)?;
-    let outfd = File::create(to)?;
+/// Find the (start, end) ranges of a file that contain data. FIEMAP
+/// is preferred as SEEK_DATA/SEEK_HOLE can be imprecise on some
+/// filesystems; we fall back to lseek where it's unsupported.
+pub fn data_segments(fd: &File, len: u64) -> Result<Vec<(u64, u64)>> {
+    match fiemap(fd) {
+        Ok(extents) => Ok(extents.iter()
+                          .filter(|e| e.logical < len)
+                          .map(|e| (e.logical, cmp::min(e.end(), len)))
+                          .collect()),
+        Err(e) => {
+            debug!("FIEMAP not available ({}), falling back to lseek", e);
+            lseek_segments(fd, len)
+        }
+    }
+}
 
-    let total = if probably_sparse(&infd)? {
-        debug!("File {:?} is sparse", from);
-        copy_sparse(&infd, &outfd, updates)?
+/// (start, end) byte ranges of a file.
+type Segments = Vec<(u64, u64)>;
 
-    } else {
-        let len = infd.metadata()?.len();
-        copy_range(&infd, &outfd, len, updates)?
+/// As `data_segments()`, but with the extents that are allocated but
+/// unwritten (e.g. by fallocate(2)) returned separately, as (data,
+/// unwritten). Only FIEMAP distinguishes these; with the lseek
+/// fallback they're included in the data.
+fn written_segments(fd: &File, len: u64) -> Result<(Segments, Segments)> {
+    let extents = match fiemap(fd) {
+        Ok(extents) => extents,
+        Err(e) => {
+            debug!("FIEMAP not available ({}), falling back to lseek", e);
+            return Ok((lseek_segments(fd, len)?, Vec::new()));
+        }
     };
-
-    outfd.set_permissions(infd.metadata()?.permissions())?;
-    Ok(total)
+    let (unwritten, written): (Vec<_>, Vec<_>) = extents.iter()
+        .filter(|e| e.logical < len)
+        .partition(|e| e.is_unwritten());
+    let range = |e: &&Extent| (e.logical, cmp::min(e.end(), len));
+    Ok((written.iter().map(range).collect(), unwritten.iter().map(range).collect()))
 }
 
+/// Merge data segments separated by holes smaller than `min_hole`,
+/// so those holes are copied densely. Holes at the start and end of
+/// the file are treated the same way.
+fn merge_small_holes(segments: Vec<(u64, u64)>, len: u64, min_hole: u64) -> Vec<(u64, u64)> {
+    if min_hole == 0 {
+        return segments;
+    }
 
-fn copy_worker(work: mpsc::Receiver<Operation>, mut updates: BatchUpdater) -> Result<()> {
-    debug!("Starting copy worker {:?}", thread::current().id());
-    for op in work {
-        debug!("Received operation {:?}", op);
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(segments.len());
+    for (start, end) in segments {
+        match merged.last_mut() {
+            Some(last) if start.saturating_sub(last.1) < min_hole => last.1 = end,
+            None if start < min_hole => merged.push((0, end)),
+            _ => merged.push((start, end)),
+        }
+    }
+    match merged.last_mut() {
+        Some(last) if len - last.1 < min_hole => last.1 = len,
+        None if len > 0 && len < min_hole => merged.push((0, len)),
+        _ => {}
+    }
+    merged
+}
 
-        // FIXME: If we implement parallel copies (which may improve
-        // performance on some SSD configurations) we should also
-        // created the parent directory, and the dir-create operation
-        // could be out of order.
-        match op {
-            Operation::Copy(from, to) => {
-                info!("Worker: Copy {:?} -> {:?}", from, to);
-                // copy_file sends back its own updates, but we should
-                // send back any errors as they may have occured
-                // before the copy started..
-                let r = copy_file(&from, &to, &mut updates);
-                if r.is_err() {
-                    updates.update(r)?;
-                }
-            }
+/// Copy only the data extents of the source, leaving holes in the
+/// destination. The file can end with a hole, so the destination's
+/// length is always set from the source's rather than from where the
+/// last extent ends.
+///
+/// Extents the source has allocated but not written read as zeros,
+/// but aren't holes; these are allocated in the destination rather
+/// than copied, so e.g. VM images keep their layout.
+fn copy_sparse(infd: &File, outfd: &File, opts: &CopyOptions,
+               throttle: &mut Option<Throttle>, stats: &mut CopyStats,
+               updates: &mut BatchUpdater) -> Result<u64>
+{
+    let len = fstat(infd)?.st_size as u64;
+    allocate_file(outfd, len)?;
 
-            Operation::Link(from, to) => {
-                info!("Worker: Symlink {:?} -> {:?}", from, to);
-                let _r = symlink(&from, &to);
+    let (data, mut unwritten) = written_segments(infd, len)?;
+    let data = merge_small_holes(data, len, opts.min_hole_size);
+    // Any that were merged into the data are copied with it.
+    unwritten.retain(|&(start, end)| !data.iter().any(|&(ds, de)| ds <= start && end <= de));
+    for &(start, end) in &unwritten {
+        if let Err(e) = allocate_range(outfd, start, end - start) {
+            if !is_unsupported(&e) {
+                return Err(e);
             }
+            debug!("Can't allocate unwritten extents ({}); leaving holes", e);
+            break;
+        }
+    }
+    updates.update(Ok(unwritten.iter().map(|(start, end)| end - start).sum()))?;
 
-            Operation::CreateDir(dir) => {
-                info!("Worker: Creating directory: {:?}", dir);
-                create_dir_all(&dir)?;
-                updates.update(Ok(dir.metadata()?.len()))?;
-            }
+    let mut copied = 0;
+    for (start, end) in data {
+        lseek(infd, start as i64, Wence::Set)?;
+        lseek(outfd, start as i64, Wence::Set)?;
 
-            Operation::End => {
-                info!("Worker received shutdown command.");
-                break;
-            }
-        }
+        copied += copy_range(infd, outfd, end - start, opts, throttle, stats, updates)
+            .map_err(|e| partial_copy(e, copied))?.0;
     }
-    debug!("Copy worker {:?} shutting down", thread::current().id());
-    Ok(())
+    allocate_file(outfd, len)?;
+
+    Ok(len)
 }
 
+/// Compare the first `len` bytes of the two files.
+fn prefix_matches(infd: &File, outfd: &File, len: u64) -> Result<bool> {
+    let mut inbuf = vec![0u8; cmp::min(len, 1024 * 1024) as usize];
+    let mut outbuf = inbuf.clone();
+    let mut off = 0;
 
-fn ignore_filter(entry: &DirEntry, ignore: &Option<Gitignore>) -> bool {
-    match ignore {
-        None => true,
-        Some(gi) => {
-            let path = entry.path();
-            let m = gi.matched(&path, path.is_dir());
-            !m.is_ignore()
+    while off < len {
+        let n = cmp::min(len - off, inbuf.len() as u64) as usize;
+        infd.read_exact_at(&mut inbuf[..n], off)?;
+        outfd.read_exact_at(&mut outbuf[..n], off)?;
+        if inbuf[..n] != outbuf[..n] {
+            return Ok(false);
         }
+        off += n as u64;
     }
+    Ok(true)
 }
 
-fn empty(path: &Path) -> bool {
-    *path == PathBuf::new()
-}
+/// If the destination looks like an interrupted copy of the source,
+/// return the offset to resume from. The destination must be shorter
+/// than the source, and the source must not have been modified since
+/// the destination was last written, otherwise the existing prefix
+/// may be stale. If `verify` is set the prefix is also compared
+/// against the source.
+fn resume_offset(infd: &File, outfd: &File, verify: bool) -> Result<Option<u64>> {
+    let (src, dst) = (fstat(infd)?, fstat(outfd)?);
 
-fn copy_source(
-    source: &PathBuf,
-    opts: &Opts,
-    work_tx: &mpsc::Sender<Operation>,
-    updates: &mut BatchUpdater,
-) -> Result<()> {
+    if dst.st_size == 0 || dst.st_size >= src.st_size {
+        return Ok(None);
+    }
+    if (src.st_mtime, src.st_mtime_nsec) > (dst.st_mtime, dst.st_mtime_nsec) {
+        debug!("Source modified since partial copy; not resuming");
+        return Ok(None);
+    }
+    if verify && !prefix_matches(infd, outfd, dst.st_size as u64)? {
+        debug!("Partial copy doesn't match source; not resuming");
+        return Ok(None);
+    }
 
-    let sourcedir = source.components().last().ok_or(XcpError::InvalidSource {
-        msg: "Failed to find source directory name.",
-    })?;
+    Ok(Some(dst.st_size as u64))
+}
 
-    let target_base = if opts.dest.exists() {
-        opts.dest.join(sourcedir)
-    } else {
-        opts.dest.clone()
-    };
-    debug!("Target base is {:?}", target_base);
+/// Whether a file should go through the hole-preserving copier.
+/// Under `Sparse::Auto` this follows `probably_sparse()`, which only
+/// looks at the ratio of allocated blocks to length, so a dense
+/// source is copied densely however large it is.
+fn is_sparse_copy(meta: &Metadata, sparse: Sparse) -> bool {
+    match sparse {
+        Sparse::Auto => meta_probably_sparse(meta),
+        Sparse::Always => true,
+        Sparse::Never => false,
+    }
+}
 
-    let gitignore = if opts.gitignore {
-        let mut builder = GitignoreBuilder::new(&source);
-        builder.add(&source.join(".gitignore"));
-        let ignore = builder.build()?;
-        Some(ignore)
+/// The number of bytes of progress a file accounts for. Holes aren't
+/// reported as progress by the sparse copier, so files that will be
+/// copied sparsely are measured by their allocated (physical) size
+/// rather than their length.
+fn progress_size(meta: &Metadata, sparse: Sparse) -> u64 {
+    if is_sparse_copy(meta, sparse) {
+        cmp::min(meta.blocks() * 512, meta.len())
     } else {
-        None
-    };
+        meta.len()
+    }
+}
 
-    for entry in WalkDir::new(&source).into_iter()
-        .filter_entry(|e| ignore_filter(e, &gitignore))
-    {
-        debug!("Got tree entry {:?}", entry);
-        let e = entry?;
-        let from = e.into_path();
-        let meta = from.symlink_metadata()?;
-        let path = from.strip_prefix(&source)?;
-        let target = if !empty(&path) {
-            target_base.join(&path)
-        } else {
-            target_base.clone()
-        };
+/// Copy the file contents according to the options, returning the
+/// number of bytes the destination represents and how they were
+/// copied.
+///
+/// A reflink is tried first, on the emptied destination. The clone
+/// replaces the destination's blocks, so anything allocated beforehand
+/// would be wasted, and some filesystems refuse to clone onto a file
+/// that has blocks. Preallocation (`opts.preallocate`) is therefore
+/// only done once we've fallen back to copying the bytes.
+fn copy_data(from: &Path, infd: &File, outfd: &File, opts: &CopyOptions,
+             throttle: &mut Option<Throttle>, stats: &mut CopyStats,
+             updates: &mut BatchUpdater) -> Result<(u64, CopyKind)>
+{
+    let methods = &opts.methods;
+    let len = infd.metadata()?.len();
 
-        if target.exists() && opts.noclobber {
-            work_tx.send(Operation::End)?;
-            updates.update(Err(XcpError::DestinationExists {
-                msg: "Destination file exists and --no-clobber is set.",
-                path: target }.into()))?;
-            return Err(XcpError::EarlyShutdown {
-                msg: "Path exists and --no-clobber set.",
-            }
-                       .into());
+    if opts.resume != Resume::Never {
+        if let Some(off) = resume_offset(infd, outfd, opts.resume == Resume::Verify)? {
+            info!("Resuming copy of {:?} at offset {}", from, off);
+            updates.update(Ok(off))?;
+            lseek(infd, off as i64, Wence::Set)?;
+            lseek(outfd, off as i64, Wence::Set)?;
+            let (copied, kind) = copy_range(infd, outfd, len - off, opts, throttle, stats, updates)
+                .map_err(|e| partial_copy(e, off))?;
+            return Ok((off + copied, kind));
         }
+    }
+    // Nothing may be allocated before the reflink; see above. Emptying
+    // an overwritten destination also means none of its old data can
+    // show through holes the copy doesn't write.
+    outfd.set_len(0)?;
 
-        match meta.file_type().to_enum() {
-            FileType::File => {
-                debug!("Send copy operation {:?} to {:?}", from, target);
-                updates.update(Ok(meta.len()))?;
-                work_tx.send(Operation::Copy(from, target))?;
+    if methods.contains(CopyMethod::Reflink) && !opts.needs_userspace() {
+        match reflink(infd, outfd) {
+            Ok(()) => {
+                debug!("Reflinked {:?}", from);
+                updates.update(Ok(progress_size(&infd.metadata()?, opts.sparse)))?;
+                return Ok((len, CopyKind::Reflinked));
             }
-
-            FileType::Symlink => {
-                let lfile = read_link(from)?;
-                debug!("Send symlink operation {:?} to {:?}", lfile, target);
-                work_tx.send(Operation::Link(lfile, target))?;
+            Err(e) => {
+                if !is_reflink_unsupported(&e) || methods.byte_methods().next().is_none() {
+                    return Err(e);
+                }
+                debug!("Reflink of {:?} not supported, trying next method: {}", from, e);
             }
+        }
+    }
 
-            FileType::Dir => {
-                debug!("Send create-dir operation {:?} to {:?}", from, target);
-                work_tx.send(Operation::CreateDir(target))?;
-                updates.update(Ok(from.metadata()?.len()))?;
+    if methods.contains(CopyMethod::Mmap) && len < MMAP_THRESHOLD && !opts.needs_userspace() {
+        match copy_small_mmap(infd, outfd, len) {
+            Ok(n) => {
+                debug!("Copied {:?} via mmap", from);
+                updates.update(Ok(n))?;
+                return Ok((n, CopyKind::ReadWrite));
             }
+            Err(e) => {
+                debug!("mmap copy of {:?} failed, trying next method: {}", from, e);
+                outfd.set_len(0)?;
+            }
+        }
+    }
 
-            FileType::Unknown => {
-                error!("Unknown filetype found; this should never happen!");
-                work_tx.send(Operation::End)?;
-                updates.update(Err(XcpError::UnknownFiletype { path: target }.into()))?;
+    let meta = infd.metadata()?;
+    let sparse = is_sparse_copy(&meta, opts.sparse);
+    if opts.sparse == Sparse::Auto {
+        info!("{:?} has {} of {} bytes allocated; copying {}", from,
+              meta.blocks() * 512, len, if sparse { "sparsely" } else { "densely" });
+    } else {
+        info!("Copying {:?} {} (--sparse={:?})", from,
+              if sparse { "sparsely" } else { "densely" }, opts.sparse);
+    }
+    if sparse {
+        copy_sparse(infd, outfd, opts, throttle, stats, updates).map(|n| (n, CopyKind::Sparse))
+    } else {
+        if opts.preallocate && !opts.skip_zeros {
+            if let Err(e) = preallocate(outfd, len) {
+                debug!("Failed to preallocate {:?}: {}", from, e);
             }
-        };
+        }
+        copy_range(infd, outfd, len, opts, throttle, stats, updates)
+    }
+}
+
+fn report_fragmentation(to: &Path, outfd: &File, threshold: u64) {
+    match extent_count(outfd) {
+        Ok(n) if n > threshold => warn!("{:?} is fragmented: {} extents", to, n),
+        Ok(n) => info!("{:?}: {} extents", to, n),
+        Err(e) => debug!("Failed to count extents of {:?}: {}", to, e),
     }
+}
+
+/// The device and inode numbers of the running executable and the
+/// other files mapped into the process, such as shared libraries.
+/// Overwriting these is a common accident (e.g. a deploy script
+/// copying over the xcp binary), and would crash the running copy.
+fn self_files() -> &'static [(u64, u64)] {
+    static FILES: OnceLock<Vec<(u64, u64)>> = OnceLock::new();
+    FILES.get_or_init(|| {
+        let mapped = mapped_files().unwrap_or_else(|e| {
+            warn!("Failed to list the files mapped by xcp: {}", e);
+            Vec::new()
+        });
+        let mut files = env::current_exe().into_iter()
+            .chain(mapped)
+            .filter_map(|path| path.metadata().ok())
+            .map(|meta| (meta.dev(), meta.ino()))
+            .collect::<Vec<(u64, u64)>>();
+        files.sort_unstable();
+        files.dedup();
+        files
+    })
+}
 
+/// Refuse to modify or remove `path` if it's one of the `self_files()`;
+/// `meta` should follow symlinks when writing through the path, and not
+/// when removing it.
+fn refuse_self(path: &Path, meta: &Metadata) -> Result<()> {
+    if self_files().contains(&(meta.dev(), meta.ino())) {
+        return Err(XcpError::SelfOverwrite { path: path.to_path_buf() }.into());
+    }
     Ok(())
 }
 
+/// Remove an existing destination that's being replaced.
+fn remove_existing(to: &Path) -> Result<()> {
+    refuse_self(to, &to.symlink_metadata()?)?;
+    remove_file(to)?;
+    Ok(())
+}
 
-fn tree_walker(
-    sources: Vec<PathBuf>,
-    opts: Opts,
-    work_tx: mpsc::Sender<Operation>,
-    mut updates: BatchUpdater,
-) -> Result<()> {
-    debug!("Starting walk worker {:?}", thread::current().id());
+/// Whether a source is recreated as a node at the destination rather
+/// than opened and copied: devices unless `device_contents` is set,
+/// FIFOs unless `fifo_contents` is set, and always sockets. Opening a
+/// FIFO would block until something writes to it.
+fn is_node(meta: &Metadata, opts: &CopyOptions) -> bool {
+    match meta.file_type().to_enum() {
+        FileType::Device => !opts.device_contents,
+        FileType::Fifo => !opts.fifo_contents,
+        FileType::Socket => true,
+        _ => false,
+    }
+}
 
-    for source in sources {
-        copy_source(&source, &opts, &work_tx, &mut updates)?;
+/// Create a node of the same type as the source at `to`. A device
+/// gets the same device number, which needs privilege; an EPERM is
+/// reported as `XcpError::DevicePermission`. A socket is created by
+/// binding to it, though nothing will be listening.
+fn make_node(to: &Path, meta: &Metadata) -> Result<()> {
+    match meta.file_type().to_enum() {
+        FileType::Fifo => mkfifo(to, meta.mode() & 0o7777),
+        FileType::Socket => UnixListener::bind(to).map(|_| ()).map_err(Into::into),
+        _ => mknod(to, meta.mode(), meta.rdev()).map_err(|e| match errno(&e) {
+            Some(libc::EPERM) => XcpError::DevicePermission { path: to.to_path_buf() }.into(),
+            _ => e,
+        }),
     }
-    work_tx.send(Operation::End)?;
-    debug!("Walk-worker finished: {:?}", thread::current().id());
-    Ok(())
 }
 
+/// Recreate a device, FIFO or socket at `to`, rather than copying its
+/// contents.
+fn copy_node(to: &Path, meta: &Metadata, opts: &CopyOptions, stats: &mut CopyStats,
+             updates: &mut BatchUpdater) -> Result<Option<u64>> {
+    if to.symlink_metadata().is_ok() {
+        if opts.clobber != Clobber::Overwrite {
+            return skip_existing(to, 0, stats, updates);
+        }
+        remove_existing(to)?;
+    }
 
-pub fn copy_all(sources: Vec<PathBuf>, opts: &Opts) -> Result<()> {
-    let (work_tx, work_rx) = mpsc::channel();
-    let (stat_tx, stat_rx) = mpsc::channel();
+    info!("Creating node {:?}", to);
+    make_node(to, meta)?;
 
-    let (pb, batch_size) = if opts.noprogress {
-        (ProgressBar::Nop, usize::max_value() as u64)
-    } else {
-        (iprogress_bar(0), BATCH_DEFAULT)
-    };
+    // As with apply_owner_and_mode(), but by path as opening a device
+    // node would open the device, and a FIFO would block.
+    if let Some(owner) = opts.uniform_owner {
+        chown(to, owner.uid, owner.gid)?;
+    } else if opts.preserve_owner {
+        chown(to, Some(meta.uid()), Some(meta.gid()))?;
+    }
+    // mknod and mkfifo apply the umask.
+    let mode = opts.uniform_mode
+        .or_else(|| if opts.preserve_mode { Some(meta.mode() & 0o7777) } else { None });
+    if let Some(mode) = mode {
+        std::fs::set_permissions(to, Permissions::from_mode(mode))?;
+    }
+    updates.update(Ok(0))?;
+    Ok(Some(0))
+}
 
-    let _copy_worker = {
-        let copy_stat = BatchUpdater {
-            sender: Box::new(stat_tx.clone()),
-            stat: StatusUpdate::Copied(0),
-            batch_size: batch_size,
-        };
-        thread::spawn(move || copy_worker(work_rx, copy_stat))
-    };
-    let _walk_worker = {
-        let topts = opts.clone();
-        let size_stat = BatchUpdater {
-            sender: Box::new(stat_tx),
-            stat: StatusUpdate::Size(0),
-            batch_size: batch_size,
-        };
-        thread::spawn(move || tree_walker(sources, topts, work_tx, size_stat))
-    };
+/// Give `fd` the source's owner and group, ignoring a lack of
+/// privilege to do so.
+fn copy_owner(fd: &File, to: &Path, meta: &Metadata) -> Result<()> {
+    if let Err(e) = fchown(fd, Some(meta.uid()), Some(meta.gid())) {
+        if errno(&e) != Some(libc::EPERM) {
+            return Err(e);
+        }
+        debug!("Not permitted to change the owner of {:?}", to);
+    }
+    Ok(())
+}
 
-    let mut copied = 0;
-    let mut total = 0;
+/// Set the owner and then the mode of a copied file, from the uniform
+/// options or else the source's `meta` where `preserve_owner` and
+/// `preserve_mode` are set. The order matters: changing the owner
+/// clears the setuid and setgid bits (on Linux even when done by
+/// root), so applying the mode first would silently drop them.
+fn apply_owner_and_mode(fd: &File, to: &Path, meta: &Metadata, opts: &CopyOptions,
+                        preserve_owner: bool, preserve_mode: bool) -> Result<()> {
+    if let Some(owner) = opts.uniform_owner {
+        fchown(fd, owner.uid, owner.gid)?;
+    } else if preserve_owner {
+        copy_owner(fd, to, meta)?;
+    }
+    if let Some(mode) = opts.uniform_mode {
+        fd.set_permissions(Permissions::from_mode(mode))?;
+    } else if opts.normalize_mode {
+        let mode = if meta.mode() & 0o111 != 0 { 0o755 } else { 0o644 };
+        fd.set_permissions(Permissions::from_mode(mode))?;
+    } else if preserve_mode {
+        fd.set_permissions(Permissions::from_mode(meta.mode() & 0o7777))?;
+    }
+    Ok(())
+}
 
-    for stat in stat_rx {
-        match stat? {
-            StatusUpdate::Size(s) => {
-                total += s;
-                pb.set_size(total);
-            }
-            StatusUpdate::Copied(s) => {
-                copied += s;
-                pb.set_position(copied);
-            }
+/// Copy the source's POSIX ACLs to `to`, warning rather than failing
+/// if its filesystem doesn't support them.
+fn preserve_acls(infd: &File, outfd: &File, to: &Path) -> Result<()> {
+    match copy_acls(infd, outfd) {
+        Err(ref e) if matches!(e.downcast_ref(), Some(XcpError::AclsUnsupported)) => {
+            warn!("Not copying ACLs to {:?}: {}", to, e);
+            Ok(())
         }
+        r => r,
     }
-    // FIXME: We should probably join the threads and consume any errors.
+}
 
-    pb.end();
-    debug!("Copy complete");
+/// Copy the source's inode flags to `to` if `preserve_flags` is set.
+/// The immutable and append-only flags need privilege to set, and not
+/// every filesystem has inode flags, so either is only a warning. As
+/// an immutable file can't be written, renamed or have its metadata
+/// changed, this must be the last thing done to a copy.
+fn preserve_inode_flags(infd: &File, outfd: &File, to: &Path, opts: &CopyOptions) -> Result<()> {
+    if !opts.preserve_flags {
+        return Ok(());
+    }
+    match copy_inode_flags(infd, outfd) {
+        Err(ref e) if errno(e) == Some(libc::EPERM) || errno(e) == Some(libc::ENOTTY)
+            || errno(e) == Some(libc::EINVAL) || is_unsupported(e) =>
+        {
+            warn!("Not copying inode flags to {:?}: {}", to, e);
+            Ok(())
+        }
+        r => r,
+    }
+}
 
+/// Set the times of a copy to `opts.mtime`, or the source's if
+/// `preserve_timestamps` is set.
+fn apply_times(infd: &File, outfd: &File, opts: &CopyOptions) -> Result<()> {
+    if let Some(mtime) = opts.mtime {
+        let time = libc::timespec { tv_sec: mtime, tv_nsec: 0 };
+        set_times(outfd, time, time)?;
+    } else if opts.preserve_timestamps {
+        let st = fstat(infd)?;
+        set_times(outfd,
+                  libc::timespec { tv_sec: st.st_atime, tv_nsec: st.st_atime_nsec },
+                  libc::timespec { tv_sec: st.st_mtime, tv_nsec: st.st_mtime_nsec })?;
+    }
     Ok(())
 }
 
+/// Apply the owner, mode and times options to a copied directory.
+/// This must be done after its contents are copied, as writing them
+/// updates its mtime, and its mode may not allow them to be written.
+fn finish_dir(from: &Path, dir: &Path, opts: &CopyOptions) -> Result<()> {
+    let (infd, fd) = (File::open(from)?, File::open(dir)?);
+    let meta = infd.metadata()?;
+    if let Some(owner) = opts.uniform_owner {
+        fchown(&fd, owner.uid, owner.gid)?;
+    } else if opts.preserve_owner {
+        copy_owner(&fd, dir, &meta)?;
+    }
+    if let Some(mode) = opts.uniform_dir_mode {
+        fd.set_permissions(Permissions::from_mode(mode))?;
+    } else if opts.preserve_mode {
+        fd.set_permissions(Permissions::from_mode(meta.mode() & 0o7777))?;
+    }
+    apply_times(&infd, &fd, opts)
+}
+
+/// Whether the source's mtime is later than `to`'s, compared at
+/// nanosecond resolution, or `to` doesn't exist. As with `cp -u`,
+/// equal mtimes mean the source is *not* newer, so repeating an update
+/// copy is a no-op. This is a stat-then-act check, so a destination
+/// modified between the check and the copy will still be overwritten.
+fn source_is_newer(infd: &File, to: &Path) -> Result<bool> {
+    let outfd = match File::open(to) {
+        Ok(fd) => fd,
+        Err(ref e) if e.kind() == IOKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into()),
+    };
+    let (src, dest) = (fstat(infd)?, fstat(&outfd)?);
+    Ok((src.st_mtime, src.st_mtime_nsec) > (dest.st_mtime, dest.st_mtime_nsec))
+}
+
+/// Record a copy skipped because the destination exists. The bytes
+/// are still reported so the progress total adds up.
+fn skip_existing(to: &Path, len: u64, stats: &mut CopyStats,
+                 updates: &mut BatchUpdater) -> Result<Option<u64>> {
+    info!("Skipping existing destination {:?}", to);
+    stats.skipped += 1;
+    updates.update(Ok(len))?;
+    Ok(None)
+}
 
-pub fn copy_single_file(source: &PathBuf, opts: &Opts) -> Result<()> {
-    let dest = if opts.dest.is_dir() {
-        let fname = source.file_name().ok_or(XcpError::UnknownFilename)?;
-        opts.dest.join(fname)
-    } else {
-        opts.dest.clone()
+/// Whether `to` exists and looks identical to the source, according
+/// to `opts.metadata_only`.
+fn same_contents(from: &Path, infd: &File, to: &Path, mode: MetadataOnly) -> Result<bool> {
+    let dest = match to.symlink_metadata() {
+        Ok(meta) if meta.is_file() => meta,
+        Ok(_) => return Ok(false),
+        Err(ref e) if e.kind() == IOKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
     };
+    let src = infd.metadata()?;
+    match mode {
+        MetadataOnly::Verify => contents_match(from, to),
+        _ => Ok(src.len() == dest.len()
+                && (src.mtime(), src.mtime_nsec()) == (dest.mtime(), dest.mtime_nsec())),
+    }
+}
 
-    if dest.is_file() && opts.noclobber {
-        return Err(io_err(
-            IOKind::AlreadyExists,
-            "Destination file exists and --no-clobber is set.",
-        ));
+/// Apply the source's mode, owner, timestamps and xattrs to an
+/// existing destination with the same contents, without writing any
+/// data. Other destinations are skipped. As with `cp -p`, failing to
+/// copy the owner as a normal user isn't an error.
+fn sync_metadata(from: &Path, to: &Path, infd: &File, opts: &CopyOptions,
+                 stats: &mut CopyStats, updates: &mut BatchUpdater) -> Result<Option<u64>> {
+    let meta = infd.metadata()?;
+    if !same_contents(from, infd, to, opts.metadata_only)? {
+        warn!("{:?} doesn't match {:?}; not syncing metadata", to, from);
+        return skip_existing(to, progress_size(&meta, opts.sparse), stats, updates);
     }
 
+    info!("Syncing metadata of {:?} to {:?}", from, to);
+    let outfd = File::open(to)?;
+    apply_owner_and_mode(&outfd, to, &meta, opts, true, true)?;
+    copy_xattrs(infd, &outfd, |name| opts.xattrs.matches(name))?;
+    let st = fstat(infd)?;
+    set_times(&outfd,
+              libc::timespec { tv_sec: st.st_atime, tv_nsec: st.st_atime_nsec },
+              libc::timespec { tv_sec: st.st_mtime, tv_nsec: st.st_mtime_nsec })?;
+    preserve_inode_flags(infd, &outfd, to, opts)?;
 
-    let mut copy_stat = if opts.noprogress {
-        BatchUpdater {
-            sender: Box::new(NopUpdater {}),
-            stat: StatusUpdate::Copied(0),
-            batch_size: usize::max_value() as u64,
-        }
-    } else {
-        let size = source.metadata()?.len();
-        BatchUpdater {
-            sender: Box::new(ProgressUpdater {
-                pb: iprogress_bar(size),
-                written: 0,
-            }),
-            stat: StatusUpdate::Copied(0),
-            batch_size: BATCH_DEFAULT,
-        }
-    };
+    updates.update(Ok(progress_size(&meta, opts.sparse)))?;
+    Ok(Some(meta.len()))
+}
 
-    copy_file(source, &dest, &mut copy_stat)?;
+/// How many times opening a file is retried when out of file
+/// descriptors, and the delay before the first retry, which doubles
+/// after each.
+const OPEN_RETRIES: u32 = 8;
+const OPEN_BACKOFF: Duration = Duration::from_millis(1);
 
-    Ok(())
+/// Open a file, backing off and retrying if the process (EMFILE) or
+/// system (ENFILE) has run out of file descriptors, as other threads,
+/// such as the walker, may close theirs in the meantime.
+fn open_retrying(path: &Path, oo: &OpenOptions) -> io::Result<File> {
+    let mut delay = OPEN_BACKOFF;
+    for _ in 0..OPEN_RETRIES {
+        match oo.open(path) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EMFILE)
+                || e.raw_os_error() == Some(libc::ENFILE) =>
+            {
+                debug!("Out of file descriptors opening {:?}; retrying in {:?}", path, delay);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            r => return r,
+        }
+    }
+    oo.open(path)
+}
+
+/// Open a source file. An fscrypt-encrypted file can only be copied
+/// as plaintext, so one whose key isn't loaded is reported as
+/// `XcpError::EncryptedSource`.
+fn open_source(from: &Path) -> Result<File> {
+    let infd = open_retrying(from, OpenOptions::new().read(true)).map_err(|e| -> Error {
+        if missing_key(&e) {
+            XcpError::EncryptedSource { path: from.to_path_buf() }.into()
+        } else {
+            e.into()
+        }
+    })?;
+    if is_encrypted(&infd).unwrap_or(false) {
+        debug!("{:?} is encrypted; copying its plaintext", from);
+    }
+    Ok(infd)
+}
+
+/// The result of a copy that didn't copy any data.
+fn no_data(copied: Result<Option<u64>>) -> Result<Option<(u64, CopyKind)>> {
+    copied.map(|n| n.map(|n| (n, CopyKind::Metadata)))
+}
+
+/// Copy a single file, returning the number of bytes the destination
+/// represents and how they were copied, or `None` if it was skipped
+/// due to `opts.clobber`.
+fn copy_file_with_updates(from: &Path, to: &Path, opts: &CopyOptions,
+                          throttle: &mut Option<Throttle>,
+                          stats: &mut CopyStats,
+                          updates: &mut BatchUpdater) -> Result<Option<(u64, CopyKind)>>
+{
+    if opts.dry_run {
+        debug!("Dry run: {:?}", plan_file(from, to, opts)?);
+        return Ok(None);
+    }
+    if let Ok(meta) = to.metadata() {
+        refuse_self(to, &meta)?;
+    }
+    // Checked before opening the source, which would block for a FIFO.
+    let meta = from.metadata()?;
+    if is_node(&meta, opts) {
+        return no_data(copy_node(to, &meta, opts, stats, updates));
+    }
+    let infd = open_source(from)?;
+    let st = fstat(&infd)?;
+    // Devices and FIFOs can only be read from start to end.
+    let streamed = is_device(&st) || is_fifo(&st);
+    if opts.metadata_only != MetadataOnly::Never {
+        return no_data(sync_metadata(from, to, &infd, opts, stats, updates));
+    }
+
+    if opts.clobber == Clobber::Update && !source_is_newer(&infd, to)? {
+        let size = progress_size(&infd.metadata()?, opts.sparse);
+        return no_data(skip_existing(to, size, stats, updates));
+    }
+
+    if clone_new_file(from, to, opts) {
+        let outfd = open_retrying(to, OpenOptions::new().read(true).write(true))?;
+        let meta = infd.metadata()?;
+        let total = meta.len();
+        updates.update(Ok(progress_size(&meta, opts.sparse)))?;
+        finish_copy(to, &infd, &outfd, opts, stats)?;
+        preserve_inode_flags(&infd, &outfd, to, opts)?;
+        return Ok(Some((total, CopyKind::Reflinked)));
+    }
+
+    let atomic = match opts.atomic {
+        Atomic::Never => false,
+        Atomic::NewFiles => to.symlink_metadata().is_err(),
+        Atomic::Always => true,
+    };
+    if atomic && opts.resume == Resume::Never && !streamed {
+        return copy_via_temp(from, to, &infd, opts, throttle, stats, updates);
+    }
+    copy_in_place(from, to, &infd, opts, throttle, stats, updates)
+}
+
+/// Copy directly to `to`, rather than via a temporary file.
+fn copy_in_place(from: &Path, to: &Path, infd: &File, opts: &CopyOptions,
+                 throttle: &mut Option<Throttle>, stats: &mut CopyStats,
+                 updates: &mut BatchUpdater) -> Result<Option<(u64, CopyKind)>>
+{
+    let st = fstat(infd)?;
+    let streamed = is_device(&st) || is_fifo(&st);
+    let mut outopts = OpenOptions::new();
+    outopts.read(true).write(true);
+
+    // Note whether we created the destination, so we know whether
+    // it's ours to remove on failure. The exclusive create also makes
+    // the no-clobber check race-free.
+    let (outfd, created) = match open_retrying(to, outopts.clone().create_new(true)) {
+        Ok(fd) => (fd, true),
+        Err(ref e) if e.kind() == IOKind::AlreadyExists => {
+            if opts.clobber == Clobber::NoClobber {
+                let size = progress_size(&infd.metadata()?, opts.sparse);
+                return no_data(skip_existing(to, size, stats, updates));
+            }
+            (open_retrying(to, &outopts)?, false)
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let copied = if streamed {
+        debug!("Streaming contents of {:?}", from);
+        // The stream may be shorter than a file it overwrites. A
+        // device destination can't be truncated, nor needs to be.
+        if !created && is_regular(&outfd)? {
+            outfd.set_len(0)?;
+        }
+        copy_fd(infd, &outfd, opts, throttle, stats, updates).map(|n| (n, CopyKind::ReadWrite))
+    } else {
+        copy_data(from, infd, &outfd, opts, throttle, stats, updates)
+    };
+    let total = match copied {
+        Ok(total) => total,
+        Err(err) => return Err(abort_copy(to, &outfd, created, err)),
+    };
+
+    finish_copy(to, infd, &outfd, opts, stats)?;
+    preserve_inode_flags(infd, &outfd, to, opts)?;
+    Ok(Some(total))
+}
+
+/// The longest filename most filesystems allow, in bytes.
+const NAME_MAX: usize = 255;
+
+/// How many temporary names to try before giving up.
+const TEMP_ATTEMPTS: u32 = 16;
+
+/// A random suffix for a temporary name, so it won't match a file
+/// already there, e.g. another xcp's.
+fn temp_suffix() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(process::id());
+    format!(".xcp-{:016x}", hasher.finish())
+}
+
+/// The name a file is copied to before being renamed to `to`. It's
+/// hidden, and in the same directory so the rename can't cross
+/// filesystems. Long names are truncated to keep it within NAME_MAX.
+fn temp_path(to: &Path, suffix: &str) -> PathBuf {
+    let name = to.file_name().unwrap_or_default().as_bytes();
+    let keep = cmp::min(name.len(), NAME_MAX - 1 - suffix.len());
+    let mut tmp = b".".to_vec();
+    tmp.extend_from_slice(&name[..keep]);
+    tmp.extend_from_slice(suffix.as_bytes());
+    to.with_file_name(OsStr::from_bytes(&tmp))
+}
+
+/// Create a temporary file to copy `to` into, returning its path and
+/// descriptor. It's created exclusively, with another name tried if
+/// one is taken, so nothing already there is touched. Returns `None`
+/// if the filesystem's name limit is too low for a temporary name.
+fn create_temp(to: &Path) -> Result<Option<(PathBuf, File)>> {
+    let mut oo = OpenOptions::new();
+    oo.read(true).write(true).create_new(true);
+    for _ in 0..TEMP_ATTEMPTS {
+        let tmp = temp_path(to, &temp_suffix());
+        match open_retrying(&tmp, &oo) {
+            Ok(fd) => return Ok(Some((tmp, fd))),
+            Err(ref e) if e.kind() == IOKind::AlreadyExists => {
+                debug!("Temporary name {:?} is taken; trying another", tmp);
+            }
+            Err(ref e) if e.raw_os_error() == Some(libc::ENAMETOOLONG) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(io_err(IOKind::AlreadyExists, "No free temporary name for the destination."))
+}
+
+/// Copy to a temporary file and rename it to `to` once complete, so a
+/// copy that fails part-way never leaves a truncated file at `to` and
+/// any existing file there is left intact.
+fn copy_via_temp(from: &Path, to: &Path, infd: &File, opts: &CopyOptions,
+                 throttle: &mut Option<Throttle>, stats: &mut CopyStats,
+                 updates: &mut BatchUpdater) -> Result<Option<(u64, CopyKind)>>
+{
+    if opts.clobber == Clobber::NoClobber && to.symlink_metadata().is_ok() {
+        let size = progress_size(&infd.metadata()?, opts.sparse);
+        return no_data(skip_existing(to, size, stats, updates));
+    }
+
+    let (tmp, outfd) = match create_temp(to)? {
+        Some(temp) => temp,
+        // The filesystem's limit is lower than NAME_MAX.
+        None => {
+            debug!("No room for a temporary name for {:?}; copying in place", to);
+            return copy_in_place(from, to, infd, opts, throttle, stats, updates);
+        }
+    };
+
+    let total = copy_data(from, infd, &outfd, opts, throttle, stats, updates)
+        // Labelled (and reported) under its final name.
+        .and_then(|total| finish_copy(to, infd, &outfd, opts, stats).map(|_| total));
+    let total = match total {
+        Ok(total) => total,
+        Err(err) => {
+            debug!("Removing partial destination {:?}", tmp);
+            if let Err(e) = remove_file(&tmp) {
+                warn!("Failed to remove partial destination {:?}: {}", tmp, e);
+            }
+            return Err(abort_copy(to, &outfd, false, err));
+        }
+    };
+
+    // A hardlink can't replace an existing file, so keeps the
+    // no-clobber check race-free.
+    let placed = if opts.clobber == Clobber::NoClobber {
+        linkat(&tmp, to).and_then(|_| Ok(remove_file(&tmp)?))
+    } else {
+        rename(&tmp, to).map_err(Into::into)
+    };
+    match placed {
+        // Flags such as immutable would have blocked the rename.
+        Ok(()) => preserve_inode_flags(infd, &outfd, to, opts).map(|_| Some(total)),
+        Err(e) => {
+            if let Err(e) = remove_file(&tmp) {
+                warn!("Failed to remove temporary file {:?}: {}", tmp, e);
+            }
+            if errno(&e) == Some(libc::EEXIST) {
+                info!("Skipping existing destination {:?}", to);
+                stats.skipped += 1;
+                return Ok(None);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Apply the metadata options to a completed copy.
+fn finish_copy(to: &Path, infd: &File, outfd: &File, opts: &CopyOptions,
+               stats: &mut CopyStats) -> Result<()> {
+    let meta = infd.metadata()?;
+    apply_owner_and_mode(outfd, to, &meta, opts, opts.preserve_owner, opts.preserve_mode)?;
+    // An access ACL overrides the group bits of the mode, so is only
+    // copied once the mode is set.
+    if opts.preserve_acls && opts.uniform_mode.is_none() {
+        preserve_acls(infd, outfd, to)?;
+    }
+    if opts.preserve_xattrs {
+        copy_xattrs(infd, outfd, |name| opts.xattrs.matches(name))?;
+    }
+    apply_times(infd, outfd, opts)?;
+    #[cfg(feature = "selinux")]
+    {
+        if opts.preserve_context {
+            crate::selinux::relabel(outfd, to)?;
+        }
+    }
+    if opts.fsync == Fsync::File {
+        outfd.sync_all()?;
+        stats.synced += 1;
+    }
+    if opts.drop_cache {
+        if let Err(e) = drop_cache(outfd) {
+            debug!("Failed to drop {:?} from the page cache: {}", to, e);
+        }
+    }
+    if let Some(threshold) = opts.report_fragmentation {
+        report_fragmentation(to, outfd, threshold);
+    }
+    Ok(())
+}
+
+/// The number of files `Fsync::Batch` copies before syncing them.
+const FSYNC_BATCH: usize = 64;
+
+/// fsync a batch of copied files.
+fn sync_batch(files: &mut Vec<PathBuf>, stats: &mut CopyStats) -> Result<()> {
+    debug!("Syncing batch of {} files", files.len());
+    for file in files.drain(..) {
+        File::open(&file)?.sync_all()?;
+        stats.synced += 1;
+    }
+    Ok(())
+}
+
+/// clonefile(2) creates the destination itself, so on macOS the
+/// reflink method has to be tried before the destination is opened;
+/// it fails with EEXIST if the destination already exists. Note that
+/// the clone also carries over the source's mode and xattrs. Returns
+/// whether the file was cloned.
+#[cfg(target_os = "macos")]
+fn clone_new_file(from: &Path, to: &Path, opts: &CopyOptions) -> bool {
+    if !opts.methods.contains(CopyMethod::Reflink) || opts.needs_userspace() {
+        return false;
+    }
+    match clone_file(from, to) {
+        Ok(()) => {
+            debug!("Cloned {:?}", from);
+            true
+        }
+        Err(e) => {
+            debug!("Clone of {:?} failed, trying next method: {}", from, e);
+            false
+        }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn clone_new_file(_from: &Path, _to: &Path, _opts: &CopyOptions) -> bool {
+    false
+}
+
+/// Whether the destination's contents are identical to the source's.
+fn contents_match(from: &Path, to: &Path) -> Result<bool> {
+    let (infd, outfd) = (File::open(from)?, File::open(to)?);
+    let len = infd.metadata()?.len();
+    Ok(outfd.metadata()?.len() == len && prefix_matches(&infd, &outfd, len)?)
+}
+
+/// Copy a file, and if `--self-heal` is enabled check the result with
+/// `verify`, re-copying from scratch until it passes or the attempts
+/// run out. Progress is only reported for the first copy. Skipped
+/// copies aren't verified.
+fn copy_file_healing<V>(from: &Path, to: &Path, opts: &CopyOptions,
+                        throttle: &mut Option<Throttle>,
+                        stats: &mut CopyStats,
+                        updates: &mut BatchUpdater,
+                        mut verify: V) -> Result<Option<(u64, CopyKind)>>
+    where V: FnMut(&Path, &Path) -> Result<bool>
+{
+    let total = match copy_file_with_updates(from, to, opts, throttle, stats, updates)? {
+        Some(total) => total,
+        None => return Ok(None),
+    };
+    let attempts = match opts.self_heal {
+        Some(attempts) if opts.metadata_only == MetadataOnly::Never => attempts,
+        _ => return Ok(Some(total)),
+    };
+    // Recreated device nodes have no contents of their own to verify.
+    if !to.symlink_metadata()?.is_file() {
+        return Ok(Some(total));
+    }
+
+    let mut quiet = BatchUpdater {
+        sender: Box::new(NopUpdater {}),
+        stat: StatusUpdate::Copied(0),
+        batch_size: updates.batch_size,
+    };
+    let mut recopies = 0;
+    while !verify(from, to)? {
+        if recopies == attempts {
+            return Err(XcpError::VerifyFailed { path: from.to_path_buf(),
+                                                attempts: attempts + 1 }.into());
+        }
+        recopies += 1;
+        warn!("Copy of {:?} failed verification, re-copying ({} of {})",
+              from, recopies, attempts);
+        remove_file(to)?;
+        copy_file_with_updates(from, to, opts, throttle, stats, &mut quiet)?;
+    }
+    if recopies > 0 {
+        info!("Healed {:?} after {} re-copies", from, recopies);
+        stats.healed += 1;
+    }
+    Ok(Some(total))
+}
+
+/// Clean up after a failed copy into `to`, removing the destination
+/// if we created it; a pre-existing destination is left in place.
+/// Running out of space is translated into `XcpError::OutOfSpace`.
+fn abort_copy(to: &Path, outfd: &File, created: bool, err: Error) -> Error {
+    // The cursors are rewound after a failed chunk, so if the copy
+    // loop didn't record its progress use the allocated size.
+    let written = copied_before(&err)
+        .unwrap_or_else(|| outfd.metadata().map(|m| m.blocks() * 512).unwrap_or(0));
+    if created {
+        debug!("Removing partial destination {:?}", to);
+        if let Err(e) = remove_file(to) {
+            warn!("Failed to remove partial destination {:?}: {}", to, e);
+        }
+    }
+
+    if errno(&err) == Some(libc::ENOSPC) {
+        XcpError::OutOfSpace { path: to.to_path_buf(), written }.into()
+    } else {
+        err
+    }
+}
+
+/// Copy from the current cursor of `infd` to `outfd` until EOF,
+/// where either may be a pipe or standard stream rather than a
+/// regular file. The chained copy methods are only used if both ends
+/// are regular files; otherwise we stream through a userspace buffer
+/// and the total length isn't known in advance.
+pub fn copy_fd(infd: &File, outfd: &File, opts: &CopyOptions,
+               throttle: &mut Option<Throttle>, stats: &mut CopyStats,
+               updates: &mut BatchUpdater) -> Result<u64>
+{
+    if is_regular(infd)? && is_regular(outfd)? {
+        let len = infd.metadata()?.len().saturating_sub(cursor(infd)?);
+        return copy_range(infd, outfd, len, opts, throttle, stats, updates).map(|(n, _)| n);
+    }
+
+    debug!("Non-regular file descriptor; streaming until EOF");
+    let mut written = 0u64;
+    let mut off = 0u64;
+    let mut copy = |i: &File, o: &File, b: u64| {
+        let n = match opts.inspect {
+            Some(ref inspect) => copy_bytes_inspected(i, o, b, off, stats, &*inspect.0)?,
+            None => copy_bytes_uspace(i, o, b, stats)?,
+        };
+        off += n;
+        Ok(n)
+    };
+    loop {
+        let n = match throttle {
+            Some(throttle) => copy_bytes_throttled(infd, outfd, updates.batch_size,
+                                                   throttle, &mut copy),
+            None => copy(infd, outfd, updates.batch_size),
+        }.map_err(|e| partial_copy(e, written))?;
+        if n == 0 {
+            break;
+        }
+        written += n;
+        updates.update(Ok(n))?;
+    }
+
+    Ok(written)
+}
+
+/// Copy a single file according to the options, returning the
+/// number of bytes the destination represents and how it was copied.
+/// If `to` is a directory the file is copied into it; see
+/// `target_path()`. If the copy was skipped because the destination
+/// exists this is 0 and `CopyKind::Skipped`.
+#[allow(dead_code)]
+pub fn copy_file(from: &Path, to: &Path, opts: &CopyOptions) -> Result<(u64, CopyKind)> {
+    let to = &target_path(from, to)?;
+    let mut throttle = opts.bwlimit.map(Throttle::new);
+    copy_file_healing(from, to, opts, &mut throttle, &mut CopyStats::default(),
+                      &mut untracked_updates(), contents_match)
+        .map(|copied| copied.unwrap_or((0, CopyKind::Skipped)))
+}
+
+/// Copy the `len` bytes of `from` starting at `src_offset` to `to`
+/// at `dst_offset`, e.g. to extract a region of a disk image. `to` is
+/// created if it doesn't exist; otherwise only the window is
+/// overwritten, and the file is extended if the window ends past it.
+/// A window that isn't within the source fails before anything is
+/// written. Metadata isn't copied.
+#[allow(dead_code)]
+pub fn copy_byte_range(from: &Path, to: &Path, src_offset: u64, len: u64, dst_offset: u64,
+                       opts: &CopyOptions) -> Result<u64> {
+    let infd = open_source(from)?;
+    let size = fstat(&infd)?.st_size as u64;
+    if src_offset.checked_add(len).filter(|end| *end <= size).is_none() {
+        return Err(XcpError::InvalidSource { msg: "Range extends past the end of the source" }.into());
+    }
+    if dst_offset.checked_add(len).filter(|end| *end <= i64::MAX as u64).is_none() {
+        return Err(XcpError::InvalidDestination { msg: "Range is past the maximum file size" }.into());
+    }
+
+    let outfd = open_retrying(to, OpenOptions::new().read(true).write(true).create(true))?;
+    if let Err(e) = allocate_range(&outfd, dst_offset, len) {
+        debug!("Failed to preallocate {:?}: {}", to, e);
+    }
+    (&infd).seek(SeekFrom::Start(src_offset))?;
+    (&outfd).seek(SeekFrom::Start(dst_offset))?;
+
+    // Skipping zeros or holes would leave the destination's old
+    // bytes in place, so the window is always written densely.
+    let opts = &opts.clone().skip_zeros(false).sparse(Sparse::Never);
+    let mut throttle = opts.bwlimit.map(Throttle::new);
+    copy_range(&infd, &outfd, len, opts, &mut throttle, &mut CopyStats::default(),
+               &mut untracked_updates())
+        .map(|(n, _)| n)
+}
+
+/// Copy each (source, destination) pair as `copy_file()` does,
+/// carrying on past any that fail, and return each source with the
+/// number of bytes copied or its error, in the same order as the
+/// pairs. Any `opts.bwlimit` applies to the batch as a whole.
+#[allow(dead_code)]
+pub fn copy_many(pairs: &[(PathBuf, PathBuf)], opts: &CopyOptions) -> Vec<(PathBuf, Result<u64>)> {
+    copy_many_observed(pairs, opts, &NopObserver {})
+}
+
+/// As `copy_many()`, reporting each file's start, progress and
+/// outcome to `observer` as it goes.
+#[allow(dead_code)]
+pub fn copy_many_observed(pairs: &[(PathBuf, PathBuf)], opts: &CopyOptions,
+                          observer: &dyn CopyObserver) -> Vec<(PathBuf, Result<u64>)> {
+    let mut throttle = opts.bwlimit.map(Throttle::new);
+    let mut stats = CopyStats::default();
+
+    pairs.iter()
+        .map(|(from, to)| {
+            observer.on_file_start(from, from.metadata().map_or(0, |m| m.len()));
+            let mut updates = BatchUpdater {
+                sender: Box::new(ObserverUpdater { observer, path: from.clone() }),
+                stat: StatusUpdate::Copied(0),
+                batch_size: BATCH_DEFAULT,
+            };
+            let copied = copy_file_healing(from, to, opts, &mut throttle, &mut stats,
+                                           &mut updates, contents_match)
+                .and_then(|copied| updates.flush().map(|_| copied));
+            match copied {
+                Ok(ref copied) => {
+                    observer.on_file_done(from, copied.map_or(CopyKind::Skipped, |(_, kind)| kind));
+                }
+                Err(ref e) => observer.on_file_error(from, e),
+            }
+            (from.clone(), copied.map(|copied| copied.map_or(0, |(n, _)| n)))
+        })
+        .collect()
+}
+
+/// Updates for a copy with no progress reporting.
+fn untracked_updates() -> BatchUpdater<'static> {
+    BatchUpdater {
+        sender: Box::new(NopUpdater {}),
+        stat: StatusUpdate::Copied(0),
+        batch_size: u64::MAX,
+    }
+}
+
+
+/// Whether an error is because the source was removed after the tree
+/// was scanned, as opposed to e.g. a missing destination directory.
+fn source_vanished(err: &Error, from: &Path) -> bool {
+    errno(err) == Some(libc::ENOENT)
+        && from.symlink_metadata().map_err(|e| e.kind()).err() == Some(IOKind::NotFound)
+}
+
+/// Record a source that vanished after it was scanned, removing its
+/// size from the progress total.
+fn skip_vanished(from: &Path, size: u64, stats: &mut CopyStats,
+                 updates: &mut BatchUpdater) -> Result<()> {
+    warn!("File has vanished: {:?}", from);
+    stats.vanished += 1;
+    updates.sender.update(Ok(StatusUpdate::Vanished(size)))
+}
+
+fn copy_worker(work: mpsc::Receiver<Operation>,
+               opts: CopyOptions,
+               mut manifest: Option<Manifest>,
+               mut updates: BatchUpdater) -> Result<CopyStats>
+{
+    let mut throttle = opts.bwlimit.map(Throttle::new);
+    let mut stats = CopyStats::default();
+    // (st_dev, st_ino) of multiply-linked sources -> their first
+    // copy. This is shared by every source argument, so links between
+    // separate sources are preserved too.
+    let mut links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    // Created directories, as (source, copy), awaiting their metadata.
+    let mut dirs = Vec::new();
+    // Copied files awaiting a batched fsync.
+    let mut unsynced = Vec::new();
+    debug!("Starting copy worker {:?}", thread::current().id());
+    for op in work {
+        debug!("Received operation {:?}", op);
+
+        // FIXME: If we implement parallel copies (which may improve
+        // performance on some SSD configurations) we should also
+        // created the parent directory, and the dir-create operation
+        // could be out of order.
+        match op {
+            Operation::Copy(from, to, size) => {
+                let meta = match from.metadata() {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        let e = e.into();
+                        if source_vanished(&e, &from) {
+                            skip_vanished(&from, size, &mut stats, &mut updates)?;
+                        } else {
+                            updates.update(Err(e))?;
+                        }
+                        continue;
+                    }
+                };
+
+                // Files with other links may have been copied
+                // already under another path.
+                let inode = (meta.dev(), meta.ino());
+                let track_links = opts.preserve_links && meta.nlink() > 1
+                    && opts.metadata_only == MetadataOnly::Never;
+                if track_links {
+                    if let Some(first) = links.get(&inode) {
+                        if opts.clobber == Clobber::NoClobber && to.symlink_metadata().is_ok() {
+                            skip_existing(&to, progress_size(&meta, opts.sparse),
+                                          &mut stats, &mut updates)?;
+                            continue;
+                        }
+                        info!("Worker: Hardlink {:?} -> {:?}", to, first);
+                        let r = replace_hardlink(first, &to)
+                            .map(|_| progress_size(&meta, opts.sparse));
+                        updates.update(r)?;
+                        continue;
+                    }
+                }
+
+                if let Some(ref manifest) = manifest {
+                    if to.exists() && manifest.is_current(&from, &meta) {
+                        info!("Worker: Skipping {:?}, already copied", from);
+                        updates.update(Ok(progress_size(&meta, opts.sparse)))?;
+                        if track_links {
+                            links.insert(inode, to);
+                        }
+                        continue;
+                    }
+                }
+
+                info!("Worker: Copy {:?} -> {:?}", from, to);
+                // copy_file sends back its own updates, but we should
+                // send back any errors as they may have occured
+                // before the copy started..
+                let r = copy_file_healing(&from, &to, &opts, &mut throttle,
+                                          &mut stats, &mut updates, contents_match);
+                match r {
+                    Ok(Some(_)) => {}
+                    Ok(None) => continue,
+                    Err(ref e) if source_vanished(e, &from) => {
+                        skip_vanished(&from, size, &mut stats, &mut updates)?;
+                        continue;
+                    }
+                    Err(e) => {
+                        updates.update(Err(e))?;
+                        continue;
+                    }
+                }
+                // Stamped as the source was before the copy, so changes
+                // made during it are picked up by the next run.
+                if let Some(ref mut manifest) = manifest {
+                    if let Err(e) = manifest.record(&from, &meta) {
+                        updates.update(Err(e))?;
+                    }
+                }
+                if opts.fsync == Fsync::Batch {
+                    unsynced.push(to.clone());
+                    if unsynced.len() >= FSYNC_BATCH {
+                        if let Err(e) = sync_batch(&mut unsynced, &mut stats) {
+                            updates.update(Err(e))?;
+                        }
+                    }
+                }
+                if track_links {
+                    links.insert(inode, to);
+                }
+            }
+
+            Operation::Link(from, to) => {
+                if opts.metadata_only != MetadataOnly::Never {
+                    debug!("Worker: Not recreating symlink {:?} in metadata-only mode", to);
+                    continue;
+                }
+                if opts.clobber == Clobber::NoClobber && to.symlink_metadata().is_ok() {
+                    info!("Skipping existing destination {:?}", to);
+                    stats.skipped += 1;
+                    continue;
+                }
+                info!("Worker: Symlink {:?} -> {:?}", from, to);
+                let r = replace_symlink(&from, &to).and_then(|_| match opts.mtime {
+                    Some(mtime) => set_link_times(&to, mtime),
+                    None => Ok(()),
+                });
+                if let Err(e) = r {
+                    updates.update(Err(e))?;
+                }
+            }
+
+            Operation::CreateDir(from, dir) => {
+                info!("Worker: Creating directory: {:?}", dir);
+                create_dir_all(&dir)?;
+                updates.update(Ok(dir.metadata()?.len()))?;
+                // Before the contents are copied, so they inherit the
+                // default ACL as they would have in the source.
+                if opts.preserve_acls {
+                    if let Err(e) = preserve_acls(&File::open(&from)?, &File::open(&dir)?, &dir) {
+                        updates.update(Err(e))?;
+                    }
+                }
+                dirs.push((from, dir));
+            }
+
+            Operation::End => {
+                info!("Worker received shutdown command.");
+                break;
+            }
+        }
+    }
+
+    if let Err(e) = sync_batch(&mut unsynced, &mut stats) {
+        updates.update(Err(e))?;
+    }
+    // Directory metadata is applied last, in reverse so each
+    // directory's contents are finished before it is.
+    for (from, dir) in dirs.iter().rev() {
+        if let Err(e) = finish_dir(from, dir, &opts) {
+            updates.update(Err(e))?;
+        }
+    }
+    debug!("Copy worker {:?} shutting down", thread::current().id());
+    Ok(stats)
+}
+
+
+/// Tracks the paths written to a case-insensitive destination, so
+/// that sources differing only by case don't silently overwrite each
+/// other.
+struct CaseCollisions {
+    mode: CaseCollision,
+    /// Case-folded target path -> the target that claimed it.
+    seen: HashMap<String, PathBuf>,
+    /// Directories that were renamed; their contents follow them.
+    renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+fn fold_case(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+impl CaseCollisions {
+    fn new(mode: CaseCollision) -> CaseCollisions {
+        CaseCollisions {
+            mode,
+            seen: HashMap::new(),
+            renamed: Vec::new(),
+        }
+    }
+
+    /// Return the path to copy `target` to, or an error if it
+    /// collides with an earlier target and we're not renaming.
+    fn check(&mut self, target: PathBuf, is_dir: bool) -> Result<PathBuf> {
+        let mut target = target;
+        for (from, to) in &self.renamed {
+            if let Ok(rest) = target.strip_prefix(from) {
+                target = to.join(rest);
+                break;
+            }
+        }
+
+        let existing = match self.seen.get(&fold_case(&target)) {
+            None => {
+                self.seen.insert(fold_case(&target), target.clone());
+                return Ok(target);
+            }
+            Some(existing) => existing.clone(),
+        };
+
+        if self.mode == CaseCollision::Error {
+            return Err(XcpError::CaseCollision { path: target, existing }.into());
+        }
+
+        let stem = target.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let ext = target.extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+        let renamed = (1..)
+            .map(|n| target.with_file_name(format!("{}~{}{}", stem, n, ext)))
+            .find(|p| !self.seen.contains_key(&fold_case(p)))
+            .unwrap();
+        warn!("{:?} collides with {:?} on the destination; copying to {:?}",
+              target, existing, renamed);
+
+        self.seen.insert(fold_case(&renamed), renamed.clone());
+        if is_dir {
+            self.renamed.push((target, renamed.clone()));
+        }
+        Ok(renamed)
+    }
+}
+
+/// Create a symlink at `to` pointing to `target`, replacing any
+/// existing link. The target need not exist.
+fn replace_symlink(target: &Path, to: &Path) -> Result<()> {
+    if let Ok(meta) = to.symlink_metadata() {
+        if meta.file_type().is_symlink() {
+            remove_existing(to)?;
+        }
+    }
+    symlinkat(target, to)
+}
+
+/// Hardlink `to` to the already-copied `existing`, replacing any
+/// file at `to`.
+fn replace_hardlink(existing: &Path, to: &Path) -> Result<()> {
+    if to.symlink_metadata().is_ok() {
+        remove_existing(to)?;
+    }
+    linkat(existing, to)
+}
+
+fn ignore_filter(entry: &DirEntry, ignore: &Option<Gitignore>) -> bool {
+    match ignore {
+        None => true,
+        Some(gi) => {
+            let path = entry.path();
+            let m = gi.matched(path, path.is_dir());
+            !m.is_ignore()
+        }
+    }
+}
+
+fn empty(path: &Path) -> bool {
+    *path == PathBuf::new()
+}
+
+/// The path a source is recreated at under the destination with
+/// `--parents`; this is the source path without any leading `/`.
+fn parents_path(source: &Path) -> Result<PathBuf> {
+    let path = normalize(source);
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(XcpError::InvalidSource {
+            msg: "--parents can't be used with sources outside the current directory",
+        }.into());
+    }
+    Ok(path.components().filter(|c| matches!(c, Component::Normal(_))).collect())
+}
+
+/// The target to give the copy at `to` of the symlink `from`. With
+/// `--parents` the copy may be at a different depth, so a relative
+/// target that would no longer resolve to the copy of the original
+/// target is rewritten to point at the original instead.
+fn link_target(from: &Path, to: &Path, opts: &Opts) -> Result<PathBuf> {
+    let lfile = readlink(from)?;
+    if !opts.parents || lfile.is_absolute() {
+        return Ok(lfile);
+    }
+
+    let (from_dir, to_dir) = match (from.parent(), to.parent()) {
+        (Some(f), Some(t)) => (f, t),
+        _ => return Ok(lfile),
+    };
+    // Where the copy of the original target would be, if it's under
+    // the current directory.
+    let original = normalize(&from_dir.join(&lfile));
+    let dest_root = to.ancestors()
+        .nth(parents_path(from)?.components().count())
+        .unwrap_or_else(|| Path::new(""));
+    let copied = parents_path(&original).ok().map(|p| normalize(&dest_root.join(p)));
+    if copied == Some(normalize(&to_dir.join(&lfile))) {
+        return Ok(lfile);
+    }
+
+    let rewritten = relative_path(&absolute(to_dir)?, &absolute(&original)?);
+    warn!("Relative symlink {:?} -> {:?} would break under --parents; rewriting it to {:?}",
+          from, lfile, rewritten);
+    Ok(rewritten)
+}
+
+fn copy_source(
+    source: &Path,
+    dest: &Path,
+    opts: &Opts,
+    collisions: &mut Option<CaseCollisions>,
+    work_tx: &mpsc::Sender<Operation>,
+    updates: &mut BatchUpdater,
+) -> Result<()> {
+
+    let sourcedir = source.components().next_back().ok_or(XcpError::InvalidSource {
+        msg: "Failed to find source directory name.",
+    })?;
+
+    let target_base = if opts.parents {
+        let target = dest.join(parents_path(source)?);
+        if let Some(parent) = target.parent().filter(|_| !opts.dry_run) {
+            debug!("Creating parent directories {:?}", parent);
+            create_dir_all(parent)?;
+        }
+        target
+    } else if dest.exists() && !opts.no_target_directory {
+        dest.join(sourcedir)
+    } else {
+        dest.to_path_buf()
+    };
+    debug!("Target base is {:?}", target_base);
+
+    let gitignore = if opts.gitignore {
+        let mut builder = GitignoreBuilder::new(source);
+        builder.add(source.join(".gitignore"));
+        let ignore = builder.build()?;
+        Some(ignore)
+    } else {
+        None
+    };
+
+    let mode = opts.symlink_mode();
+    let follow = mode == SymlinkMode::Follow;
+
+    // WalkDir always follows a root symlink (and fails if it's
+    // broken), so handle that case here.
+    if !follow && source.symlink_metadata()?.file_type().is_symlink() {
+        if mode == SymlinkMode::Skip {
+            debug!("Skipping symlink {:?}", source);
+        } else {
+            let lfile = link_target(source, &target_base, opts)?;
+            debug!("Send symlink operation {:?} to {:?}", lfile, target_base);
+            work_tx.send(Operation::Link(lfile, target_base))?;
+        }
+        return Ok(());
+    }
+
+    let mut walker = WalkDir::new(source).follow_links(follow);
+    if opts.reproducible {
+        walker = walker.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    }
+    for entry in walker
+        .into_iter()
+        .filter_entry(|e| ignore_filter(e, &gitignore))
+    {
+        debug!("Got tree entry {:?}", entry);
+        let e = entry?;
+        let from = e.into_path();
+        let meta = if follow {
+            from.metadata()?
+        } else {
+            from.symlink_metadata()?
+        };
+        let path = from.strip_prefix(source)?;
+        let target = if !empty(path) {
+            target_base.join(path)
+        } else {
+            target_base.clone()
+        };
+        let target = match collisions {
+            Some(collisions) => match collisions.check(target, meta.is_dir()) {
+                Ok(target) => target,
+                Err(err) => {
+                    work_tx.send(Operation::End)?;
+                    updates.update(Err(err))?;
+                    return Err(XcpError::EarlyShutdown {
+                        msg: "Case collision on destination.",
+                    }.into());
+                }
+            },
+            None => target,
+        };
+
+        match meta.file_type().to_enum() {
+            FileType::File | FileType::Device | FileType::Fifo | FileType::Socket => {
+                debug!("Send copy operation {:?} to {:?}", from, target);
+                let size = progress_size(&meta, opts.sparse);
+                updates.update(Ok(size))?;
+                work_tx.send(Operation::Copy(from, target, size))?;
+            }
+
+            FileType::Symlink if mode == SymlinkMode::Skip => {
+                debug!("Skipping symlink {:?}", from);
+            }
+
+            FileType::Symlink => {
+                let lfile = link_target(&from, &target, opts)?;
+                debug!("Send symlink operation {:?} to {:?}", lfile, target);
+                work_tx.send(Operation::Link(lfile, target))?;
+            }
+
+            FileType::Dir => {
+                debug!("Send create-dir operation {:?} to {:?}", from, target);
+                work_tx.send(Operation::CreateDir(from.to_path_buf(), target))?;
+                updates.update(Ok(from.metadata()?.len()))?;
+            }
+
+            FileType::Unknown => {
+                error!("Unknown filetype found; this should never happen!");
+                work_tx.send(Operation::End)?;
+                updates.update(Err(XcpError::UnknownFiletype { path: target }.into()))?;
+            }
+        };
+    }
+
+    Ok(())
+}
+
+
+fn tree_walker(
+    sources: Vec<PathBuf>,
+    dest: PathBuf,
+    opts: Opts,
+    work_tx: mpsc::Sender<Operation>,
+    mut updates: BatchUpdater,
+) -> Result<()> {
+    debug!("Starting walk worker {:?}", thread::current().id());
+
+    // The destination may not exist yet, in which case probe the
+    // nearest directory that does.
+    let probe_dir = dest.ancestors()
+        .find(|p| p.is_dir())
+        .unwrap_or_else(|| Path::new("."));
+    // The probe writes a file, so a dry run skips it.
+    let insensitive = if opts.dry_run { Ok(false) } else { is_case_insensitive(probe_dir) };
+    let mut collisions = match insensitive {
+        Ok(true) => {
+            info!("Destination {:?} is case-insensitive", dest);
+            Some(CaseCollisions::new(opts.on_case_collision))
+        }
+        Ok(false) => None,
+        Err(e) => {
+            debug!("Failed to probe {:?} for case-sensitivity: {}", probe_dir, e);
+            None
+        }
+    };
+
+    for source in sources {
+        copy_source(&source, &dest, &opts, &mut collisions, &work_tx, &mut updates)?;
+    }
+    work_tx.send(Operation::End)?;
+    debug!("Walk-worker finished: {:?}", thread::current().id());
+    Ok(())
+}
+
+
+/// Run a copy with the operations produced by `walker`, which is run
+/// in its own thread and sends the expected size of each one through
+/// its updater, displaying the progress.
+fn run_copy<W>(opts: &Opts, walker: W) -> Result<()>
+    where W: FnOnce(mpsc::Sender<Operation>, BatchUpdater) -> Result<()> + Send + 'static
+{
+    let (work_tx, work_rx) = mpsc::channel();
+    let (stat_tx, stat_rx) = mpsc::channel();
+
+    let (pb, batch_size) = if opts.noprogress {
+        (ProgressBar::Nop, u64::MAX)
+    } else {
+        (iprogress_bar(0), BATCH_DEFAULT)
+    };
+
+    let manifest = match opts.manifest {
+        Some(ref path) => Some(Manifest::open(path)?),
+        None => None,
+    };
+
+    let copy_worker = {
+        let copy_stat = BatchUpdater {
+            sender: Box::new(stat_tx.clone()),
+            stat: StatusUpdate::Copied(0),
+            batch_size,
+        };
+        let copy_opts = CopyOptions::from_opts(opts);
+        thread::spawn(move || {
+            #[cfg(all(feature = "numa", target_os = "linux"))]
+            {
+                if let Err(e) = crate::numa::pin_worker(0) {
+                    warn!("Failed to pin the copy worker: {}", e);
+                }
+            }
+            copy_worker(work_rx, copy_opts, manifest, copy_stat)
+        })
+    };
+    let _walk_worker = {
+        let size_stat = BatchUpdater {
+            sender: Box::new(stat_tx),
+            stat: StatusUpdate::Size(0),
+            batch_size,
+        };
+        thread::spawn(move || walker(work_tx, size_stat))
+    };
+
+    let mut copied = 0;
+    let mut total = 0;
+    // Kept separately as the scanned sizes are batched, so may not
+    // have been added to the total yet.
+    let mut vanished = 0;
+
+    for stat in stat_rx {
+        match stat? {
+            StatusUpdate::Size(s) => {
+                total += s;
+                pb.set_size(total.saturating_sub(vanished));
+            }
+            StatusUpdate::Copied(s) => {
+                copied += s;
+                pb.set_position(copied);
+            }
+            StatusUpdate::Vanished(s) => {
+                vanished += s;
+                pb.set_size(total.saturating_sub(vanished));
+            }
+        }
+    }
+    // FIXME: We should probably join the threads and consume any errors.
+
+    pb.end();
+    if let Ok(Ok(stats)) = copy_worker.join() {
+        stats.log_summary();
+    }
+    debug!("Copy complete");
+
+    Ok(())
+}
+
+/// Work out what copying `from` to `to` would do, without writing
+/// anything.
+pub fn plan_file(from: &Path, to: &Path, opts: &CopyOptions) -> Result<PlannedCopy> {
+    let meta = from.metadata()?;
+    let decision = if is_node(&meta, opts) {
+        Decision::CreateNode
+    } else if opts.metadata_only != MetadataOnly::Never {
+        if same_contents(from, &File::open(from)?, to, opts.metadata_only)? {
+            Decision::SyncMetadata
+        } else {
+            Decision::Skip
+        }
+    } else if (opts.clobber == Clobber::NoClobber && to.symlink_metadata().is_ok())
+        || (opts.clobber == Clobber::Update && !source_is_newer(&File::open(from)?, to)?)
+    {
+        Decision::Skip
+    } else {
+        Decision::Copy(predict_method(&meta, to, opts))
+    };
+    let size = match decision {
+        Decision::Copy(_) => progress_size(&meta, opts.sparse),
+        _ => 0,
+    };
+
+    Ok(PlannedCopy { from: from.to_path_buf(), to: to.to_path_buf(), size, decision })
+}
+
+/// The dry-run equivalent of `run_copy`; the operations produced by
+/// `walker` are planned rather than performed.
+fn plan_copy<W>(opts: &Opts, walker: W) -> Result<CopyPlan>
+    where W: FnOnce(mpsc::Sender<Operation>, BatchUpdater) -> Result<()> + Send + 'static
+{
+    let (work_tx, work_rx) = mpsc::channel();
+    let (stat_tx, stat_rx) = mpsc::channel();
+    let copy_opts = CopyOptions::from_opts(opts);
+
+    let walk_worker = {
+        // Only errors are sent.
+        let size_stat = BatchUpdater {
+            sender: Box::new(stat_tx),
+            stat: StatusUpdate::Size(0),
+            batch_size: u64::MAX,
+        };
+        thread::spawn(move || walker(work_tx, size_stat))
+    };
+
+    let mut plan = CopyPlan::default();
+    for op in work_rx {
+        let entry = match op {
+            Operation::Copy(from, to, _) => match plan_file(&from, &to, &copy_opts) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    plan.errors.push(format!("{:?}: {}", from, e));
+                    continue;
+                }
+            },
+            Operation::Link(target, to) =>
+                PlannedCopy { from: target, to, size: 0, decision: Decision::Symlink },
+            Operation::CreateDir(from, to) =>
+                PlannedCopy { from, to, size: 0, decision: Decision::CreateDir },
+            Operation::End => break,
+        };
+        plan.entries.push(entry);
+    }
+
+    walk_worker.join()
+        .map_err(|_| XcpError::EarlyShutdown { msg: "Walk worker panicked." })??;
+    plan.errors.extend(stat_rx.try_iter().filter_map(|s| s.err()).map(|e| e.to_string()));
+    Ok(plan)
+}
+
+pub fn copy_all(sources: Vec<PathBuf>, dest: &Path, opts: &Opts) -> Result<()> {
+    let tdest = dest.to_path_buf();
+    let topts = opts.clone();
+    if opts.dry_run {
+        return plan_copy(opts, move |work_tx, updates| {
+            tree_walker(sources, tdest, topts, work_tx, updates)
+        })?.print();
+    }
+    run_copy(opts, move |work_tx, updates| tree_walker(sources, tdest, topts, work_tx, updates))?;
+
+    if opts.fsync == Some(Fsync::Syncfs) && dest.exists() {
+        debug!("Syncing filesystem of {:?}", dest);
+        syncfs(&File::open(dest)?)?;
+    }
+    Ok(())
+}
+
+
+/// Send a copy operation for each (source, destination) pair of a
+/// map file, creating the destinations' parent directories.
+fn map_walker(mapping: Vec<(PathBuf, PathBuf)>, opts: Opts,
+              work_tx: mpsc::Sender<Operation>, mut updates: BatchUpdater) -> Result<()> {
+    debug!("Starting map worker {:?}", thread::current().id());
+    for (from, to) in mapping {
+        let meta = match from.metadata() {
+            Ok(meta) if meta.is_dir() => {
+                updates.update(Err(XcpError::InvalidSource {
+                    msg: "Map file sources must be files, not directories.",
+                }.into()))?;
+                continue;
+            }
+            Ok(meta) => meta,
+            Err(e) => {
+                updates.update(Err(e.into()))?;
+                continue;
+            }
+        };
+        if let Some(parent) = to.parent().filter(|p| !empty(p) && !opts.dry_run) {
+            create_dir_all(parent)?;
+        }
+
+        let size = progress_size(&meta, opts.sparse);
+        debug!("Send copy operation {:?} to {:?}", from, to);
+        updates.update(Ok(size))?;
+        work_tx.send(Operation::Copy(from, to, size))?;
+    }
+    work_tx.send(Operation::End)?;
+    debug!("Map worker finished: {:?}", thread::current().id());
+    Ok(())
+}
+
+/// Copy each source in the map file to its own destination.
+pub fn copy_mapped(map_file: &Path, opts: &Opts) -> Result<()> {
+    let mapping = read_map_file(map_file)?;
+    let dests = mapping.iter().map(|(_, to)| to.clone()).collect::<Vec<PathBuf>>();
+    let mopts = opts.clone();
+    if opts.dry_run {
+        return plan_copy(opts, move |work_tx, updates| {
+            map_walker(mapping, mopts, work_tx, updates)
+        })?.print();
+    }
+    run_copy(opts, move |work_tx, updates| map_walker(mapping, mopts, work_tx, updates))?;
+
+    if opts.fsync == Some(Fsync::Syncfs) {
+        let mut synced = Vec::new();
+        for dest in dests.iter().filter(|d| d.exists()) {
+            let fd = File::open(dest)?;
+            let dev = fd.metadata()?.dev();
+            if !synced.contains(&dev) {
+                debug!("Syncing filesystem of {:?}", dest);
+                syncfs(&fd)?;
+                synced.push(dev);
+            }
+        }
+    }
+    Ok(())
+}
+
+
+pub fn copy_single_file(source: &Path, dest: &Path, opts: &Opts) -> Result<()> {
+    let dest = target_path(source, dest)?;
+
+    if opts.dry_run {
+        let entry = match opts.symlink_mode() {
+            SymlinkMode::Follow => None,
+            _ if !source.symlink_metadata()?.file_type().is_symlink() => None,
+            SymlinkMode::Copy if opts.noclobber && dest.symlink_metadata().is_ok() =>
+                Some(PlannedCopy { from: readlink(source)?, to: dest.clone(), size: 0,
+                                   decision: Decision::Skip }),
+            SymlinkMode::Copy =>
+                Some(PlannedCopy { from: readlink(source)?, to: dest.clone(), size: 0,
+                                   decision: Decision::Symlink }),
+            SymlinkMode::Skip => Some(PlannedCopy { from: source.to_path_buf(), to: dest.clone(),
+                                                    size: 0, decision: Decision::Skip }),
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => plan_file(source, &dest, &CopyOptions::from_opts(opts))?,
+        };
+        return CopyPlan { entries: vec![entry], errors: Vec::new() }.print();
+    }
+
+    if source.symlink_metadata()?.file_type().is_symlink() {
+        match opts.symlink_mode() {
+            SymlinkMode::Follow => {}
+            SymlinkMode::Copy if opts.noclobber && dest.symlink_metadata().is_ok() => {
+                info!("Skipping existing destination {:?}", dest);
+                return Ok(());
+            }
+            SymlinkMode::Copy => {
+                let target = readlink(source)?;
+                debug!("Copying symlink {:?} -> {:?} to {:?}", source, target, dest);
+                if dest.symlink_metadata().is_ok() {
+                    remove_existing(&dest)?;
+                }
+                return symlinkat(&target, &dest);
+            }
+            SymlinkMode::Skip => {
+                info!("Skipping symlink {:?}", source);
+                return Ok(());
+            }
+        }
+    }
+
+    let mut copy_stat = if opts.noprogress {
+        BatchUpdater {
+            sender: Box::new(NopUpdater {}),
+            stat: StatusUpdate::Copied(0),
+            batch_size: u64::MAX,
+        }
+    } else {
+        let size = progress_size(&source.metadata()?, opts.sparse);
+        BatchUpdater {
+            sender: Box::new(ProgressUpdater {
+                pb: iprogress_bar(size),
+                written: 0,
+            }),
+            stat: StatusUpdate::Copied(0),
+            batch_size: BATCH_DEFAULT,
+        }
+    };
+
+    let copy_opts = CopyOptions::from_opts(opts);
+    let mut throttle = copy_opts.bwlimit.map(Throttle::new);
+    let mut stats = CopyStats::default();
+    let copied = copy_file_healing(source, &dest, &copy_opts, &mut throttle, &mut stats,
+                                   &mut copy_stat, contents_match)?;
+    if copied.is_some() {
+        match copy_opts.fsync {
+            Fsync::Batch => sync_batch(&mut vec![dest.clone()], &mut stats)?,
+            Fsync::Syncfs => syncfs(&File::open(&dest)?)?,
+            Fsync::Never | Fsync::File => {}
+        }
+    }
+    stats.log_summary();
+
+    Ok(())
+}
+
+
+/// A `File` handle on one of the standard streams, which must not be
+/// closed when dropped.
+fn std_stream(fd: RawFd) -> ManuallyDrop<File> {
+    ManuallyDrop::new(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Copy where the source or destination is `-`, i.e. stdin or
+/// stdout. Only the data is copied; there is no file to take
+/// permissions from, and the length of a pipe isn't known in advance.
+/// With `--wire` the stream is encoded or decoded with `wire`.
+pub fn copy_stdio(source: &str, dest: &str, opts: &Opts) -> Result<()> {
+    let (stdin, infile);
+    let infd: &File = if source == "-" {
+        stdin = std_stream(libc::STDIN_FILENO);
+        &stdin
+    } else {
+        infile = File::open(source)?;
+        &infile
+    };
+
+    let (stdout, outfile);
+    let outfd: &File = if dest == "-" {
+        stdout = std_stream(libc::STDOUT_FILENO);
+        &stdout
+    } else {
+        if let Ok(meta) = Path::new(dest).metadata() {
+            refuse_self(Path::new(dest), &meta)?;
+        }
+        let mut outopts = OpenOptions::new();
+        outopts.write(true);
+        if opts.noclobber {
+            outopts.create_new(true);
+        } else {
+            outopts.create(true).truncate(true);
+        }
+        outfile = match outopts.open(dest) {
+            Ok(fd) => fd,
+            Err(ref e) if e.kind() == IOKind::AlreadyExists => {
+                info!("Skipping existing destination {:?}", dest);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        &outfile
+    };
+
+    let size = if is_regular(infd)? {
+        Some(infd.metadata()?.len().saturating_sub(cursor(infd)?))
+    } else {
+        None
+    };
+    let mut copy_stat = match size {
+        Some(size) if !opts.noprogress => BatchUpdater {
+            sender: Box::new(ProgressUpdater {
+                pb: iprogress_bar(size),
+                written: 0,
+            }),
+            stat: StatusUpdate::Copied(0),
+            batch_size: BATCH_DEFAULT,
+        },
+        _ => BatchUpdater {
+            sender: Box::new(NopUpdater {}),
+            stat: StatusUpdate::Copied(0),
+            batch_size: BATCH_DEFAULT,
+        },
+    };
+
+    let copy_opts = CopyOptions::from_opts(opts);
+    let mut throttle = copy_opts.bwlimit.map(Throttle::new);
+    let mut stats = CopyStats::default();
+    if opts.wire && dest == "-" {
+        wire::encode(infd, &mut BufWriter::new(outfd), copy_opts.sparse, &mut stats, &mut copy_stat)?;
+    } else if opts.wire {
+        wire::decode(&mut BufReader::new(infd), outfd, &mut stats, &mut copy_stat)?;
+    } else {
+        copy_fd(infd, outfd, &copy_opts, &mut throttle, &mut stats, &mut copy_stat)?;
+    }
+    match copy_opts.fsync {
+        Fsync::File | Fsync::Batch => outfd.sync_all()?,
+        Fsync::Syncfs => syncfs(outfd)?,
+        Fsync::Never => {}
+    }
+    stats.log_summary();
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::{copy_stream_with, probably_sparse, SMALL_FILE_BLOCKS};
+    use std::fs::{read, set_permissions, write, Permissions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    /// Pass a test that can't run here, saying so rather than passing
+    /// silently.
+    fn skip(test: &str, why: &str) -> Result<()> {
+        eprintln!("Skipping {}; {}", test, why);
+        Ok(())
+    }
+
+    fn create_sparse(path: &Path) -> Result<()> {
+        let mut fd = File::create(path)?;
+        write!(fd, "head")?;
+        fd.seek(SeekFrom::Start(1024 * 1024))?;
+        write!(fd, "tail")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_many() -> Result<()> {
+        let dir = tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        write(path("a.txt"), "first")?;
+        write(path("c.txt"), "third file")?;
+
+        let pairs = vec![
+            (path("a.txt"), path("a-copy.txt")),
+            (path("missing.txt"), path("b-copy.txt")),
+            (path("c.txt"), path("c-copy.txt")),
+        ];
+        let results = copy_many(&pairs, &CopyOptions::new());
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results.iter().map(|r| &r.0).collect::<Vec<_>>(),
+                   pairs.iter().map(|p| &p.0).collect::<Vec<_>>());
+        assert_eq!(results[0].1.as_ref().ok(), Some(&5));
+        assert_eq!(errno(results[1].1.as_ref().unwrap_err()), Some(libc::ENOENT));
+        assert_eq!(results[2].1.as_ref().ok(), Some(&10));
+        assert_eq!(read(path("a-copy.txt"))?, b"first");
+        assert!(!path("b-copy.txt").exists());
+        assert_eq!(read(path("c-copy.txt"))?, b"third file");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_into_dir() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let dest = dir.path().join("dest");
+        write(&from, "test data")?;
+        std::fs::create_dir(&dest)?;
+
+        copy_file(&from, &dest, &CopyOptions::new())?;
+        assert_eq!(read(dest.join("from.txt"))?, b"test data");
+
+        // An explicit file path is used as is.
+        copy_file(&from, &dest.join("to.txt"), &CopyOptions::new())?;
+        assert_eq!(read(dest.join("to.txt"))?, b"test data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_byte_range() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..256 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        write(&from, &data)?;
+
+        let (off, len) = (100 * 1024 + 7, 64 * 1024);
+        assert_eq!(copy_byte_range(&from, &to, off, len, 0, &CopyOptions::new())?, len);
+        assert_eq!(read(&to)?, &data[off as usize..(off + len) as usize]);
+
+        // Into the middle of an existing file.
+        write(&to, vec![0xff; 1024])?;
+        copy_byte_range(&from, &to, 10, 100, 500, &CopyOptions::new())?;
+        let copy = read(&to)?;
+        assert_eq!(copy.len(), 1024);
+        assert_eq!(&copy[500..600], &data[10..110]);
+        assert!(copy[..500].iter().chain(&copy[600..]).all(|b| *b == 0xff));
+
+        // Zeros and holes in the source still overwrite the window.
+        let holey = dir.path().join("holey.bin");
+        {
+            let fd = File::create(&holey)?;
+            fd.write_all_at(&data[..64 * 1024], 0)?;
+            fd.write_all_at(&vec![0; 64 * 1024], 64 * 1024)?;
+            fd.write_all_at(&data[..64 * 1024], 192 * 1024)?;
+        }
+        write(&to, vec![0xff; 256 * 1024])?;
+        let opts = CopyOptions::new().skip_zeros(true).sparse(Sparse::Always);
+        copy_byte_range(&holey, &to, 0, 256 * 1024, 0, &opts)?;
+        assert_eq!(read(&to)?, read(&holey)?);
+
+        // Out of range, so nothing is written.
+        let to = dir.path().join("none.bin");
+        assert!(copy_byte_range(&from, &to, data.len() as u64 - 10, 11, 0,
+                                &CopyOptions::new()).is_err());
+        assert!(copy_byte_range(&from, &to, u64::MAX, 1, 0, &CopyOptions::new()).is_err());
+        assert!(!to.exists());
+
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Start(PathBuf, u64),
+        Progress(PathBuf, u64),
+        Done(PathBuf),
+        Error(PathBuf),
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver(std::sync::Mutex<Vec<Event>>);
+
+    impl CopyObserver for RecordingObserver {
+        fn on_file_start(&self, path: &Path, size: u64) {
+            self.0.lock().unwrap().push(Event::Start(path.to_path_buf(), size));
+        }
+        fn on_progress(&self, path: &Path, bytes: u64) {
+            self.0.lock().unwrap().push(Event::Progress(path.to_path_buf(), bytes));
+        }
+        fn on_file_done(&self, path: &Path, kind: CopyKind) {
+            assert_ne!(kind, CopyKind::Skipped);
+            self.0.lock().unwrap().push(Event::Done(path.to_path_buf()));
+        }
+        fn on_file_error(&self, path: &Path, _err: &Error) {
+            self.0.lock().unwrap().push(Event::Error(path.to_path_buf()));
+        }
+    }
+
+    #[test]
+    fn test_copy_many_observed() -> Result<()> {
+        let dir = tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        write(path("a.txt"), "first")?;
+        write(path("c.txt"), "third file")?;
+
+        let pairs = vec![
+            (path("a.txt"), path("a-copy.txt")),
+            (path("missing.txt"), path("b-copy.txt")),
+            (path("c.txt"), path("c-copy.txt")),
+        ];
+        let observer = RecordingObserver::default();
+        let results = copy_many_observed(&pairs, &CopyOptions::new(), &observer);
+        assert!(results[0].1.is_ok() && results[1].1.is_err() && results[2].1.is_ok());
+
+        assert_eq!(observer.0.into_inner().unwrap(), vec![
+            Event::Start(path("a.txt"), 5),
+            Event::Progress(path("a.txt"), 5),
+            Event::Done(path("a.txt")),
+            Event::Start(path("missing.txt"), 0),
+            Event::Error(path("missing.txt")),
+            Event::Start(path("c.txt"), 10),
+            Event::Progress(path("c.txt"), 10),
+            Event::Done(path("c.txt")),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_bytes_retrying() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        write(&from, &data)?;
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+
+        // Fails with EAGAIN twice, after copying part of the chunk.
+        let mut calls = 0;
+        let mut stats = CopyStats::default();
+        let flaky = |i: &File, o: &File, b: u64, stats: &mut CopyStats| {
+            calls += 1;
+            if calls <= 2 {
+                copy_bytes_uspace(i, o, 1000, stats)?;
+                return Err(io::Error::from_raw_os_error(libc::EAGAIN).into());
+            }
+            copy_bytes_uspace(i, o, b, stats)
+        };
+        assert_eq!(copy_bytes_retrying(&infd, &outfd, data.len() as u64, 3, &mut stats, flaky)?,
+                   data.len() as u64);
+        assert_eq!(calls, 3);
+        assert_eq!(read(&to)?, data);
+        assert_eq!((stats.read_bytes, stats.write_bytes), (data.len() as u64, data.len() as u64));
+
+        // Permanent errors, and transient ones past the limit, aren't retried.
+        let mut calls = 0;
+        let err = copy_bytes_retrying(&infd, &outfd, 1, 3, &mut stats, |_, _, _, _| -> Result<u64> {
+            calls += 1;
+            Err(io::Error::from_raw_os_error(libc::EACCES).into())
+        }).unwrap_err();
+        assert_eq!((errno(&err), calls), (Some(libc::EACCES), 1));
+        let mut calls = 0;
+        let err = copy_bytes_retrying(&infd, &outfd, 1, 1, &mut stats, |_, _, _, _| -> Result<u64> {
+            calls += 1;
+            Err(io::Error::from_raw_os_error(libc::ESTALE).into())
+        }).unwrap_err();
+        assert_eq!((errno(&err), calls), (Some(libc::ESTALE), 2));
+
+        Ok(())
+    }
+
+    /// A writer that fails with EAGAIN once, after `fail_after` bytes.
+    struct FlakyWriter<'a> {
+        fd: &'a File,
+        fail_after: Option<usize>,
+    }
+
+    impl Write for FlakyWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.fail_after {
+                Some(0) => {
+                    self.fail_after = None;
+                    Err(io::Error::from_raw_os_error(libc::EAGAIN))
+                }
+                Some(n) => {
+                    let n = self.fd.write(&buf[..cmp::min(n, buf.len())])?;
+                    self.fail_after = Some(self.fail_after.unwrap() - n);
+                    Ok(n)
+                }
+                None => self.fd.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_retry_inspects_once() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        write(&from, &data)?;
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let s = seen.clone();
+        let once = inspect_once(Arc::new(move |buf: &[u8], off| {
+            s.lock().unwrap().push((off, buf.to_vec()))
+        }));
+
+        // The first attempt fails partway through the chunk.
+        let mut writer = FlakyWriter { fd: &outfd, fail_after: Some(10000) };
+        let mut stats = CopyStats::default();
+        let n = copy_bytes_retrying(&infd, &outfd, data.len() as u64, 1, &mut stats,
+                                    |mut i, _, b, stats| {
+            let mut off = cursor(i)?;
+            copy_stream_with(&mut i, &mut writer, b, 4096, stats, &mut |buf| {
+                once(buf, off);
+                off += buf.len() as u64;
+            })
+        })?;
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(read(&to)?, data);
+        assert_eq!((stats.read_bytes, stats.write_bytes), (n, n));
+
+        let seen = seen.lock().unwrap();
+        let mut next = 0;
+        for (off, buf) in seen.iter() {
+            assert_eq!(*off, next);
+            assert_eq!(&buf[..], &data[next as usize..next as usize + buf.len()]);
+            next += buf.len() as u64;
+        }
+        assert_eq!(next, n);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwrite_with_sparse() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("dense.bin");
+        let len = 4 * 1024 * 1024;
+        {
+            let fd = File::create(&from)?;
+            fd.set_len(len)?;
+            fd.write_all_at(b"head", 0)?;
+            fd.write_all_at(b"middle", len / 2)?;
+        }
+        assert!(probably_sparse(&File::open(&from)?)?);
+
+        // Longer than the source, so the tail must go too.
+        write(&to, vec![0xaa; len as usize * 2])?;
+        for opts in [CopyOptions::new(), CopyOptions::new().sparse(Sparse::Always)] {
+            copy_file(&from, &to, &opts)?;
+            assert_eq!(read(&to)?, read(&from)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_defaults() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        create_sparse(&from)?;
+        set_permissions(&from, Permissions::from_mode(0o751))?;
+
+        let (n, _) = copy_file(&from, &to, &CopyOptions::new())?;
+
+        assert_eq!(n, from.metadata()?.len());
+        assert_eq!(read(&from)?, read(&to)?);
+        assert_eq!(to.metadata()?.permissions().mode() & 0o7777, 0o751);
+        assert!(probably_sparse(&File::open(&to)?)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_setuid_with_owner() -> Result<()> {
+        // Changing the owner needs privilege.
+        if unsafe { libc::geteuid() } != 0 {
+            return skip("test_preserve_setuid_with_owner", "not running as root");
+        }
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        write(&from, "test data")?;
+        std::os::unix::fs::chown(&from, Some(1), Some(1))?;
+        set_permissions(&from, Permissions::from_mode(0o4755))?;
+
+        copy_file(&from, &to, &CopyOptions::new().preserve_owner(true))?;
+
+        let meta = to.metadata()?;
+        assert_eq!((meta.uid(), meta.gid()), (1, 1));
+        assert_eq!(meta.mode() & 0o7777, 0o4755);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuse_overwriting_self() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let link = dir.path().join("link");
+        write(&from, "test data")?;
+        let exe = env::current_exe()?.canonicalize()?;
+        std::os::unix::fs::symlink(&exe, &link)?;
+        let len = exe.metadata()?.len();
+
+        for to in &[&exe, &link] {
+            match copy_file(&from, to, &CopyOptions::new()).unwrap_err().downcast::<XcpError>()? {
+                XcpError::SelfOverwrite { path } => assert_eq!(&path, *to),
+                e => panic!("Expected SelfOverwrite, got {}", e),
+            }
+        }
+        assert_eq!(exe.metadata()?.len(), len);
+
+        Ok(())
+    }
+
+    /// Needs an fscrypt-encrypted file whose key isn't loaded, named
+    /// by `XCP_TEST_LOCKED_FILE`, as setting one up needs privilege
+    /// and filesystem support.
+    #[test]
+    fn test_locked_encrypted_source() -> Result<()> {
+        let from = match env::var_os("XCP_TEST_LOCKED_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => return skip("test_locked_encrypted_source", "XCP_TEST_LOCKED_FILE isn't set"),
+        };
+        let dir = tempdir()?;
+        let to = dir.path().join("to.bin");
+
+        match copy_file(&from, &to, &CopyOptions::new()).unwrap_err().downcast::<XcpError>()? {
+            XcpError::EncryptedSource { path } => assert_eq!(path, from),
+            e => panic!("Expected EncryptedSource, got {}", e),
+        }
+        assert!(!to.exists());
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_copy_acls() -> Result<()> {
+        use crate::os::{get_xattr, set_xattr};
+        use std::ffi::CStr;
+
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        write(&from, "test data")?;
+
+        // user::rw-, user:1:r--, group::r--, mask::r--, other::---
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for &(tag, perm, id) in &[(0x01u16, 6u16, u32::MAX), (0x02, 4, 1), (0x04, 4, u32::MAX),
+                                  (0x10, 4, u32::MAX), (0x20, 0, u32::MAX)] {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&perm.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        let name = CStr::from_bytes_with_nul(b"system.posix_acl_access\0").unwrap();
+        if set_xattr(&File::open(&from)?, name, &acl).is_err() {
+            return skip("test_copy_acls", "no ACL support in the test filesystem");
+        }
+
+        copy_file(&from, &to, &CopyOptions::new().preserve_acls(true))?;
+
+        assert_eq!(get_xattr(&File::open(&to)?, name)?, acl);
+        assert_eq!(to.metadata()?.mode() & 0o777, 0o640);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_mmap() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..4096).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        write(&from, &data)?;
+
+        let opts = CopyOptions::new().methods("mmap,userspace".parse().unwrap());
+        assert_eq!(copy_file(&from, &to, &opts)?.0, 4096);
+        assert_eq!(read(&to)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_inspected() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        let mut text = String::new();
+        for i in 0..100_000 {
+            text.push_str(&format!("line {}\n", i));
+        }
+        write(&from, &text)?;
+
+        let newlines = Arc::new(Mutex::new(Vec::new()));
+        let index = newlines.clone();
+        let opts = CopyOptions::new().inspect(move |buf, off| {
+            let mut index = index.lock().unwrap();
+            index.extend(buf.iter().enumerate()
+                         .filter(|(_, b)| **b == b'\n')
+                         .map(|(i, _)| off + i as u64));
+        });
+        copy_file(&from, &to, &opts)?;
+
+        assert_eq!(read(&to)?, text.as_bytes());
+        let expected = text.bytes().enumerate()
+            .filter(|(_, b)| *b == b'\n')
+            .map(|(i, _)| i as u64)
+            .collect::<Vec<u64>>();
+        assert_eq!(expected.len(), 100_000);
+        assert_eq!(*newlines.lock().unwrap(), expected);
+
+        // Setting the methods afterwards doesn't bypass the inspector.
+        newlines.lock().unwrap().clear();
+        let index = newlines.clone();
+        let opts = CopyOptions::new()
+            .inspect(move |buf, off| {
+                let mut index = index.lock().unwrap();
+                index.extend(buf.iter().enumerate()
+                             .filter(|(_, b)| **b == b'\n')
+                             .map(|(i, _)| off + i as u64));
+            })
+            .methods("reflink,copy_file_range".parse().unwrap());
+        remove_file(&to)?;
+        copy_file(&from, &to, &opts)?;
+        assert_eq!(read(&to)?, text.as_bytes());
+        assert_eq!(*newlines.lock().unwrap(), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_dense_no_mode() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        create_sparse(&from)?;
+        set_permissions(&from, Permissions::from_mode(0o751))?;
+
+        let opts = CopyOptions::new()
+            .reflink(false)
+            .preserve_mode(false)
+            .sparse(Sparse::Never)
+            .fsync(Fsync::File);
+        copy_file(&from, &to, &opts)?;
+
+        assert_eq!(read(&from)?, read(&to)?);
+        assert_ne!(to.metadata()?.permissions().mode() & 0o7777, 0o751);
+        assert!(!probably_sparse(&File::open(&to)?)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_userspace_sparse() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        create_sparse(&from)?;
+
+        let opts = CopyOptions::new()
+            .methods("userspace".parse().unwrap())
+            .sparse(Sparse::Always);
+        copy_file(&from, &to, &opts)?;
+
+        assert_eq!(read(&from)?, read(&to)?);
+        assert!(probably_sparse(&File::open(&to)?)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_skip_zeros() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let mut data = vec![0x5a; 64 * 1024];
+        data.extend(vec![0; 1024 * 1024]);
+        data.extend(vec![0xa5; 64 * 1024]);
+        data.extend(vec![0; 256 * 1024]);
+        File::create(&from)?.write_all(&data)?;
+        assert!(!probably_sparse(&File::open(&from)?)?);
+
+        let opts = CopyOptions::new()
+            .sparse(Sparse::Never)
+            .skip_zeros(true);
+        copy_file(&from, &to, &opts)?;
+
+        assert_eq!(read(&to)?, data);
+        let segments = data_segments(&File::open(&to)?, data.len() as u64)?;
+        let allocated: u64 = segments.iter().map(|(start, end)| end - start).sum();
+        assert!(allocated <= 128 * 1024);
+        assert!(segments.iter().all(|(start, end)| {
+            *end <= 64 * 1024 || (*start >= 1088 * 1024 && *end <= 1152 * 1024)
+        }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_skip_zeros_inspected() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let mut data = vec![0x5a; 64 * 1024];
+        data.extend(vec![0; 1024 * 1024]);
+        data.extend(vec![0xa5; 64 * 1024]);
+        File::create(&from)?.write_all(&data)?;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let inspected = seen.clone();
+        let opts = CopyOptions::new()
+            .sparse(Sparse::Never)
+            .inspect(move |buf, off| {
+                let mut seen = inspected.lock().unwrap();
+                assert_eq!(off, seen.len() as u64);
+                seen.extend_from_slice(buf);
+            })
+            .skip_zeros(true);
+        copy_file(&from, &to, &opts)?;
+
+        assert_eq!(read(&to)?, data);
+        // The zero blocks are passed on too.
+        assert_eq!(*seen.lock().unwrap(), data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_decision() -> Result<()> {
+        let dir = tempdir()?;
+        let sparse = dir.path().join("sparse.bin");
+        let dense = dir.path().join("dense.bin");
+        create_sparse(&sparse)?;
+        File::create(&dense)?.write_all(&vec![1; 8 * 1024 * 1024])?;
+
+        let meta = sparse.metadata()?;
+        assert!(is_sparse_copy(&meta, Sparse::Auto));
+        assert!(!is_sparse_copy(&meta, Sparse::Never));
+        // A large dense file isn't forced through the sparse copier.
+        assert!(!is_sparse_copy(&dense.metadata()?, Sparse::Auto));
+        assert!(is_sparse_copy(&dense.metadata()?, Sparse::Always));
+
+        for (mode, expect_sparse) in &[(Sparse::Auto, true), (Sparse::Never, false)] {
+            let to = dir.path().join(format!("{:?}.bin", mode));
+            copy_file(&sparse, &to, &CopyOptions::new().sparse(*mode))?;
+            assert_eq!(read(&sparse)?, read(&to)?);
+            assert_eq!(probably_sparse(&File::open(&to)?)?, *expect_sparse);
+        }
+
+        Ok(())
+    }
+
+    fn pipe() -> Result<(File, File)> {
+        let mut fds = [0; 2];
+        let r = unsafe { libc::pipe(fds.as_mut_ptr()) };
+        if r != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+    }
+
+    fn nop_updater() -> BatchUpdater<'static> {
+        BatchUpdater {
+            sender: Box::new(NopUpdater {}),
+            stat: StatusUpdate::Copied(0),
+            batch_size: 64 * 1024,
+        }
+    }
+
+    #[test]
+    fn test_copy_fd_pipes() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        std::fs::write(&from, &data)?;
+        let opts = CopyOptions::new();
+
+        // File -> pipe
+        let (rx, tx) = pipe()?;
+        let reader = thread::spawn(move || -> std::io::Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            (&rx).read_to_end(&mut buf)?;
+            Ok(buf)
+        });
+        let n = copy_fd(&File::open(&from)?, &tx, &opts, &mut None,
+                        &mut CopyStats::default(), &mut nop_updater())?;
+        drop(tx);
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(reader.join().unwrap()?, data);
+
+        // Pipe -> file
+        let (rx, mut tx) = pipe()?;
+        let writer = {
+            let data = data.clone();
+            thread::spawn(move || tx.write_all(&data))
+        };
+        let mut stats = CopyStats::default();
+        let n = copy_fd(&rx, &File::create(&to)?, &opts, &mut None,
+                        &mut stats, &mut nop_updater())?;
+        writer.join().unwrap()?;
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(stats.read_bytes, n);
+        assert_eq!(read(&to)?, data);
+
+        Ok(())
+    }
+
+    /// Sums the progress reported through it.
+    struct ProgressSum(Arc<Mutex<u64>>);
+
+    impl Updater<Result<StatusUpdate>> for ProgressSum {
+        fn update(&mut self, update: Result<StatusUpdate>) -> Result<()> {
+            if let Ok(StatusUpdate::Copied(bytes)) = update {
+                *self.0.lock().unwrap() += bytes;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sparse_progress_is_physical() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        {
+            let mut fd = File::create(&from)?;
+            fd.seek(SeekFrom::Start(1024 * 1024))?;
+            fd.write_all(&[1; 4096])?;
+            fd.set_len(16 * 1024 * 1024)?;
+        }
+        let meta = from.metadata()?;
+        assert_eq!(progress_size(&meta, Sparse::Auto), 4096);
+        assert_eq!(progress_size(&meta, Sparse::Never), meta.len());
+
+        let sum = Arc::new(Mutex::new(0));
+        let mut updates = BatchUpdater {
+            sender: Box::new(ProgressSum(sum.clone())),
+            stat: StatusUpdate::Copied(0),
+            batch_size: 1,
+        };
+        copy_file_with_updates(&from, &to, &CopyOptions::new(), &mut None,
+                               &mut CopyStats::default(), &mut updates)?;
+
+        // Only the data region counts as progress.
+        assert_eq!(*sum.lock().unwrap(), 4096);
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
+
+    /// Truncates a file to `len` on the first progress update.
+    struct TruncateOnUpdate(PathBuf, u64);
+
+    impl Updater<Result<StatusUpdate>> for TruncateOnUpdate {
+        fn update(&mut self, _update: Result<StatusUpdate>) -> Result<()> {
+            if self.0.metadata()?.len() > self.1 {
+                OpenOptions::new().write(true).open(&self.0)?.set_len(self.1)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_truncated_source_reports_copied() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        File::create(&from)?.write_all(&vec![0x5a; 1024 * 1024])?;
+
+        let truncated = 200 * 1024;
+        let mut updates = BatchUpdater {
+            sender: Box::new(TruncateOnUpdate(from.clone(), truncated)),
+            stat: StatusUpdate::Copied(0),
+            batch_size: 64 * 1024,
+        };
+        let opts = CopyOptions::new().sparse(Sparse::Never);
+        let err = copy_file_with_updates(&from, &to, &opts, &mut None,
+                                         &mut CopyStats::default(), &mut updates)
+            .unwrap_err();
+
+        assert_eq!(copied_before(&err), Some(truncated));
+        match err.downcast_ref::<XcpError>() {
+            Some(XcpError::PartialCopy { source, .. }) =>
+                assert_eq!(source.kind(), IOKind::UnexpectedEof),
+            _ => panic!("Expected PartialCopy, got {}", err),
+        }
+        assert!(!to.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_copy_long_name() -> Result<()> {
+        let dir = tempdir()?;
+        let name = "x".repeat(NAME_MAX - 3);
+        let from = dir.path().join(&name);
+        let to = dir.path().join("dest");
+        std::fs::create_dir(&to)?;
+        write(&from, "test data")?;
+
+        let tmp = temp_path(&to.join(&name), &temp_suffix());
+        assert!(tmp.file_name().unwrap().len() <= NAME_MAX);
+        assert_eq!(tmp.parent(), Some(to.as_path()));
+
+        copy_file(&from, &to.join(&name), &CopyOptions::new().atomic(Atomic::Always))?;
+        assert_eq!(read(to.join(&name))?, b"test data");
+        assert_eq!(std::fs::read_dir(&to)?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_temp_is_exclusive() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        write(&from, "test data")?;
+
+        let (a, _) = create_temp(&to)?.unwrap();
+        let (b, _) = create_temp(&to)?.unwrap();
+        assert_ne!(a, b);
+        assert!(a.exists() && b.exists());
+
+        // Another copy's temporary file is left alone.
+        write(&a, "in progress")?;
+        copy_file(&from, &to, &CopyOptions::new().atomic(Atomic::Always))?;
+        assert_eq!(read(&to)?, b"test data");
+        assert_eq!(read(&a)?, b"in progress");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_copy_failure_keeps_destination() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let existing = dir.path().join("existing.bin");
+        let new = dir.path().join("new.bin");
+        File::create(&from)?.write_all(&vec![0x5a; 1024 * 1024])?;
+        write(&existing, "old contents")?;
+
+        let opts = CopyOptions::new().sparse(Sparse::Never).atomic(Atomic::Always);
+        for to in &[&existing, &new] {
+            let mut updates = BatchUpdater {
+                sender: Box::new(TruncateOnUpdate(from.clone(), 200 * 1024)),
+                stat: StatusUpdate::Copied(0),
+                batch_size: 64 * 1024,
+            };
+            copy_file_with_updates(&from, to, &opts, &mut None,
+                                   &mut CopyStats::default(), &mut updates)
+                .unwrap_err();
+            File::create(&from)?.write_all(&vec![0x5a; 1024 * 1024])?;
+        }
+
+        assert_eq!(read(&existing)?, b"old contents");
+        assert!(!new.exists());
+        // The temporary files are cleaned up.
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 2);
+
+        // A successful copy replaces the destination.
+        copy_file(&from, &existing, &opts)?;
+        assert_eq!(read(&existing)?, read(&from)?);
+        write(&new, "new contents")?;
+        copy_file(&from, &new, &opts.clobber(Clobber::NoClobber))?;
+        assert_eq!(read(&new)?, b"new contents");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fallback_only_when_unsupported() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        write(&from, "test data")?;
+        let methods = [CopyMethod::CopyFileRange, CopyMethod::Userspace];
+
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+        let (n, method) = copy_with_fallback(&infd, &outfd, methods.iter().cloned(), |m| {
+            match m {
+                CopyMethod::CopyFileRange => Err(std::io::Error::from_raw_os_error(libc::EXDEV).into()),
+                _ => copy_bytes_uspace(&infd, &outfd, 9, &mut CopyStats::default()).map(Some),
+            }
+        })?;
+        assert_eq!((n, method), (9, CopyMethod::Userspace));
+        assert_eq!(read(&to)?, b"test data");
+
+        // EINVAL only means unsupported for reflinks.
+        for errno in &[libc::EIO, libc::ENOSPC, libc::EINVAL] {
+            let mut tried = Vec::new();
+            let err = copy_with_fallback(&infd, &outfd, methods.iter().cloned(), |m| {
+                tried.push(m);
+                Err(std::io::Error::from_raw_os_error(*errno).into())
+            }).unwrap_err();
+            assert_eq!(crate::os::errno(&err), Some(*errno));
+            assert_eq!(tried, vec![CopyMethod::CopyFileRange]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fallback_across_mounts() -> Result<()> {
+        let dir = tempdir()?;
+        let shm = match tempfile::tempdir_in("/dev/shm") {
+            Ok(shm) => shm,
+            Err(_) => return skip("test_fallback_across_mounts", "no /dev/shm"),
+        };
+        if shm.path().metadata()?.dev() == dir.path().metadata()?.dev() {
+            return skip("test_fallback_across_mounts", "/dev/shm is on the same filesystem");
+        }
+        let from = shm.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        write(&from, vec![0x5a; 64 * 1024])?;
+
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+        let opts = CopyOptions::new().methods("copy_file_range,userspace".parse().unwrap());
+        let (n, method) = copy_chunk(&infd, &outfd, 64 * 1024, false, &opts,
+                                     &mut CopyStats::default(), None)?;
+        assert_eq!(n, 64 * 1024);
+        assert_eq!(read(&to)?, read(&from)?);
+        if !crate::os::supports_cfr(&infd, &outfd) {
+            assert_eq!(method, CopyMethod::Userspace);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_kind_reflinked() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        write(&from, vec![0x5a; 64 * 1024])?;
+
+        // Skip on filesystems without reflink support.
+        let probe = dir.path().join("probe.bin");
+        if let Err(e) = reflink(&File::open(&from)?, &File::create(&probe)?) {
+            if crate::os::is_reflink_unsupported(&e) {
+                return skip("test_copy_kind_reflinked", "no reflink support");
+            }
+            return Err(e);
+        }
+
+        let (n, kind) = copy_file(&from, &to, &CopyOptions::new())?;
+        assert_eq!(n, 64 * 1024);
+        assert_eq!(kind, CopyKind::Reflinked);
+        assert_eq!(read(&to)?, read(&from)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflink_not_preallocated() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        write(&from, vec![0x5a; 256 * 1024])?;
+
+        let opts = CopyOptions::new().preallocate(true);
+        let (_, kind) = copy_file(&from, &to, &opts)?;
+        if kind != CopyKind::Reflinked {
+            return skip("test_reflink_not_preallocated", "no reflink support");
+        }
+
+        assert_eq!(read(&to)?, read(&from)?);
+        let extents = fiemap(&File::open(&to)?)?;
+        assert!(!extents.is_empty());
+        assert!(extents.iter().all(|e| e.is_shared() && !e.is_unwritten()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_small_files_skip_cfr() -> Result<()> {
+        let dir = tempdir()?;
+        let small = dir.path().join("small.bin");
+        let large = dir.path().join("large.bin");
+        write(&small, vec![0x5a; 100])?;
+        let blksize = small.metadata()?.blksize();
+        write(&large, vec![0xa5; (SMALL_FILE_BLOCKS * blksize * 4) as usize])?;
+        assert!(is_small_file(&small.metadata()?));
+        assert!(!is_small_file(&large.metadata()?));
+
+        let opts = |methods: &str| CopyOptions::new()
+            .methods(methods.parse().unwrap())
+            .sparse(Sparse::Never);
+        let cfr = crate::os::supports_cfr(&File::open(&small)?,
+                                          &File::create(dir.path().join("probe"))?);
+
+        let to = dir.path().join("small-copy.bin");
+        assert_eq!(copy_file(&small, &to, &opts("copy_file_range,userspace"))?.1,
+                   CopyKind::ReadWrite);
+        assert_eq!(read(&to)?, read(&small)?);
+
+        if cfr {
+            let to = dir.path().join("large-copy.bin");
+            assert_eq!(copy_file(&large, &to, &opts("copy_file_range,userspace"))?.1,
+                       CopyKind::CopyFileRange);
+            assert_eq!(read(&to)?, read(&large)?);
+
+            // Without a userspace fallback in the chain, it's used anyway.
+            let to = dir.path().join("small-cfr.bin");
+            assert_eq!(copy_file(&small, &to, &opts("copy_file_range"))?.1,
+                       CopyKind::CopyFileRange);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_kind_across_mounts() -> Result<()> {
+        let dir = tempdir()?;
+        let shm = match tempfile::tempdir_in("/dev/shm") {
+            Ok(shm) => shm,
+            Err(_) => return skip("test_copy_kind_across_mounts", "no /dev/shm"),
+        };
+        if shm.path().metadata()?.dev() == dir.path().metadata()?.dev() {
+            return skip("test_copy_kind_across_mounts", "/dev/shm is on the same filesystem");
+        }
+        let from = shm.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        write(&from, vec![0x5a; 64 * 1024])?;
+
+        let opts = CopyOptions::new()
+            .methods("copy_file_range,userspace".parse().unwrap())
+            .sparse(Sparse::Never)
+            .atomic(Atomic::Never);
+        let cfr = crate::os::supports_cfr(&File::open(&from)?, &File::create(&to)?);
+        let (n, kind) = copy_file(&from, &to, &opts)?;
+        assert_eq!(n, 64 * 1024);
+        assert_eq!(read(&to)?, read(&from)?);
+        if !cfr {
+            assert_eq!(kind, CopyKind::ReadWrite);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        let existing = dir.path().join("existing.txt");
+        write(&from, "test data")?;
+        write(&existing, "old")?;
+
+        let opts = CopyOptions::new().dry_run(true).methods("userspace".parse().unwrap());
+        assert_eq!(copy_file(&from, &to, &opts)?, (0, CopyKind::Skipped));
+        assert!(!to.exists());
+
+        let plan = plan_file(&from, &to, &opts)?;
+        assert_eq!(plan.decision, Decision::Copy(CopyMethod::Userspace));
+        assert_eq!(plan.size, 9);
+        let plan = plan_file(&from, &existing, &opts.clone().clobber(Clobber::NoClobber))?;
+        assert_eq!(plan.decision, Decision::Skip);
+        assert_eq!(plan.size, 0);
+
+        // Reflinks aren't predicted on filesystems that can't make them.
+        let plan = plan_file(&from, &to, &opts.clone().methods("reflink,userspace".parse().unwrap()))?;
+        let fs = crate::os::fs_type(&File::open(dir.path())?)?;
+        if matches!(fs, crate::os::FsType::Ext4 | crate::os::FsType::Tmpfs) {
+            assert_eq!(plan.decision, Decision::Copy(CopyMethod::Userspace));
+        }
+
+        assert!(!to.exists());
+        assert_eq!(read(&existing)?, b"old");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_copy_counts_accumulate() {
+        let err = io_err(IOKind::Other, "test");
+        let err = partial_copy(partial_copy(err, 100), 20);
+        assert_eq!(copied_before(&err), Some(120));
+
+        let err = partial_copy(XcpError::UnknownFilename.into(), 10);
+        assert_eq!(copied_before(&err), None);
+    }
+
+    #[test]
+    fn test_fsync_batch_syncs_all() -> Result<()> {
+        let dir = tempdir()?;
+        let count = FSYNC_BATCH + 3;
+        let (tx, rx) = mpsc::channel();
+        for i in 0..count {
+            let from = dir.path().join(format!("from{}.txt", i));
+            write(&from, format!("file {}", i))?;
+            tx.send(Operation::Copy(from, dir.path().join(format!("to{}.txt", i)), 6))?;
+        }
+        tx.send(Operation::End)?;
+
+        let opts = CopyOptions::new().fsync(Fsync::Batch);
+        let stats = copy_worker(rx, opts, None, nop_updater())?;
+
+        assert_eq!(stats.synced, count as u64);
+        for i in 0..count {
+            assert_eq!(read(dir.path().join(format!("to{}.txt", i)))?,
+                       format!("file {}", i).as_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_contents() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        write(&from, "test data")?;
+        write(&to, "test data")?;
+        set_times(&File::open(&to)?, libc::timespec { tv_sec: 0, tv_nsec: 0 },
+                  libc::timespec { tv_sec: 0, tv_nsec: 0 })?;
+        let infd = File::open(&from)?;
+
+        // Only the full comparison sees through differing mtimes.
+        assert!(!same_contents(&from, &infd, &to, MetadataOnly::Quick)?);
+        assert!(same_contents(&from, &infd, &to, MetadataOnly::Verify)?);
+        assert!(!same_contents(&from, &infd, &dir.path().join("missing"), MetadataOnly::Verify)?);
+
+        Ok(())
+    }
+
+    /// Records the updates sent through it.
+    struct UpdateLog(Arc<Mutex<Vec<StatusUpdate>>>);
+
+    impl Updater<Result<StatusUpdate>> for UpdateLog {
+        fn update(&mut self, update: Result<StatusUpdate>) -> Result<()> {
+            self.0.lock().unwrap().push(update?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_vanished_source_skipped() -> Result<()> {
+        let dir = tempdir()?;
+        let gone = dir.path().join("gone.txt");
+        let from = dir.path().join("from.txt");
+        write(&gone, "scanned")?;
+        write(&from, "test data")?;
+
+        let (tx, rx) = mpsc::channel();
+        tx.send(Operation::Copy(gone.clone(), dir.path().join("gone-copy.txt"), 7))?;
+        tx.send(Operation::Copy(from, dir.path().join("to.txt"), 9))?;
+        tx.send(Operation::End)?;
+        remove_file(&gone)?;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let updates = BatchUpdater {
+            sender: Box::new(UpdateLog(log.clone())),
+            stat: StatusUpdate::Copied(0),
+            batch_size: 1,
+        };
+        let stats = copy_worker(rx, CopyOptions::new(), None, updates)?;
+
+        assert_eq!(stats.vanished, 1);
+        assert!(!dir.path().join("gone-copy.txt").exists());
+        assert_eq!(read(dir.path().join("to.txt"))?, b"test data");
+        let log = log.lock().unwrap();
+        assert!(matches!(log[0], StatusUpdate::Vanished(7)));
+        assert!(log[1..].iter().all(|u| matches!(u, StatusUpdate::Copied(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_small_holes() {
+        let segments = vec![(4096, 8192), (12288, 16384), (1 << 20, (1 << 20) + 4096)];
+        let len = (1 << 20) + 8192;
+
+        assert_eq!(merge_small_holes(segments.clone(), len, 0), segments);
+        assert_eq!(merge_small_holes(segments, len, 64 * 1024),
+                   vec![(0, 16384), (1 << 20, len)]);
+        assert_eq!(merge_small_holes(vec![], 1024, 4096), vec![(0, 1024)]);
+    }
+
+    #[test]
+    fn test_min_hole_size() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let large_hole = 4096 * 3 + 1024 * 1024;
+        {
+            // Data, an 8K hole, data, a 1M hole, data.
+            let mut fd = File::create(&from)?;
+            fd.write_all(&[1; 4096])?;
+            fd.seek(SeekFrom::Start(4096 * 3))?;
+            fd.write_all(&[2; 4096])?;
+            fd.seek(SeekFrom::Start(large_hole))?;
+            fd.write_all(&[3; 4096])?;
+        }
+
+        let opts = CopyOptions::new().sparse(Sparse::Always).min_hole_size(64 * 1024);
+        copy_file(&from, &to, &opts)?;
+        assert_eq!(read(&from)?, read(&to)?);
+
+        let fd = File::open(&to)?;
+        // The small hole was filled...
+        assert_eq!(lseek(&fd, 4096, Wence::Data)?, SeekOff::Offset(4096));
+        // ...but the large one preserved.
+        assert_eq!(lseek(&fd, 4096 * 4, Wence::Data)?, SeekOff::Offset(large_hole));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_sparse_unwritten_extents() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let mb = 1024 * 1024;
+        {
+            let mut fd = File::create(&from)?;
+            fd.write_all(&[1; 4096])?;
+            if allocate_range(&fd, mb, mb).is_err() {
+                return skip("test_copy_sparse_unwritten_extents", "no fallocate support");
+            }
+            fd.seek(SeekFrom::Start(2 * mb))?;
+            fd.write_all(&[2; 4096])?;
+        }
+        let unwritten = |path: &Path| -> Result<Vec<(u64, u64)>> {
+            Ok(fiemap(&File::open(path)?)?.iter()
+               .filter(|e| e.is_unwritten())
+               .map(|e| (e.logical, e.end()))
+               .collect())
+        };
+        if unwritten(&from)?.is_empty() {
+            return skip("test_copy_sparse_unwritten_extents", "no unwritten extents reported");
+        }
+
+        let opts = CopyOptions::new().sparse(Sparse::Always);
+        copy_file(&from, &to, &opts)?;
+
+        assert_eq!(read(&from)?, read(&to)?);
+        let extents = unwritten(&to)?;
+        assert_eq!(extents.first().map(|e| e.0), Some(mb));
+        assert_eq!(extents.last().map(|e| e.1), Some(2 * mb));
+        assert!(to.metadata()?.blocks() * 512 >= mb);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lseek_segments_trailing_hole() -> Result<()> {
+        let dir = tempdir()?;
+        let holes = dir.path().join("holes.bin");
+        let tail = dir.path().join("tail.bin");
+        let len = 1024 * 1024;
+        File::create(&holes)?.set_len(len)?;
+        {
+            let fd = File::create(&tail)?;
+            (&fd).write_all(&[1; 4096])?;
+            fd.sync_all()?;
+            fd.set_len(len)?;
+        }
+
+        // The whole file is a single hole.
+        assert_eq!(lseek_segments(&File::open(&holes)?, len)?, vec![]);
+        // One data segment, then a hole to EOF.
+        assert_eq!(lseek_segments(&File::open(&tail)?, len)?, vec![(0, 4096)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_sparse_trailing_hole() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        {
+            let fd = File::create(&from)?;
+            (&fd).write_all(&[1; 4096])?;
+            fd.set_len(1024 * 1024 + 17)?;
+        }
+
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+        let opts = CopyOptions::new().sparse(Sparse::Always);
+        let n = copy_sparse(&infd, &outfd, &opts, &mut None, &mut CopyStats::default(),
+                            &mut nop_updater())?;
+
+        assert_eq!(n, 1024 * 1024 + 17);
+        assert_eq!(to.metadata()?.len(), from.metadata()?.len());
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_noclobber_skips_existing() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        std::fs::write(&from, "new data")?;
+        std::fs::write(&to, "old")?;
+        let opts = CopyOptions::new().clobber(Clobber::NoClobber);
+
+        let mut stats = CopyStats::default();
+        let r = copy_file_with_updates(&from, &to, &opts, &mut None, &mut stats,
+                                       &mut nop_updater())?;
+
+        assert_eq!(r, None);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(read(&to)?, b"old");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_noclobber_creates_missing() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        std::fs::write(&from, "new data")?;
+        let opts = CopyOptions::new().clobber(Clobber::NoClobber);
+
+        let mut stats = CopyStats::default();
+        let r = copy_file_with_updates(&from, &to, &opts, &mut None, &mut stats,
+                                       &mut nop_updater())?;
+
+        assert_eq!(r.map(|(n, _)| n), Some(8));
+        assert_eq!(stats.skipped, 0);
+        assert_eq!(read(&to)?, b"new data");
+
+        Ok(())
+    }
+
+    /// Copy `from` over `to` in update mode, with the given mtimes.
+    fn update_copy(from: &Path, to: &Path, src_mtime: libc::timespec,
+                   dest_mtime: Option<libc::timespec>) -> Result<Option<u64>> {
+        std::fs::write(from, "new data")?;
+        set_times(&File::open(from)?, src_mtime, src_mtime)?;
+        if let Some(mtime) = dest_mtime {
+            std::fs::write(to, "old")?;
+            set_times(&File::open(to)?, mtime, mtime)?;
+        }
+
+        let opts = CopyOptions::new().clobber(Clobber::Update);
+        copy_file_with_updates(from, to, &opts, &mut None, &mut CopyStats::default(),
+                               &mut nop_updater())
+            .map(|r| r.map(|(n, _)| n))
+    }
+
+    #[test]
+    fn test_update_modes() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        let time = |nsec| libc::timespec { tv_sec: 1_000_000, tv_nsec: nsec };
+
+        // Newer source copies, even by a nanosecond.
+        assert_eq!(update_copy(&from, &to, time(2), Some(time(1)))?, Some(8));
+        assert_eq!(read(&to)?, b"new data");
+
+        // Older source skips.
+        assert_eq!(update_copy(&from, &to, time(1), Some(time(2)))?, None);
+        assert_eq!(read(&to)?, b"old");
+
+        // Equal mtimes mean don't copy.
+        assert_eq!(update_copy(&from, &to, time(1), Some(time(1)))?, None);
+        assert_eq!(read(&to)?, b"old");
+
+        // Missing destination always copies.
+        remove_file(&to)?;
+        assert_eq!(update_copy(&from, &to, time(1), None)?, Some(8));
+        assert_eq!(read(&to)?, b"new data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_heal_recopies() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        std::fs::write(&from, "test data")?;
+        let opts = CopyOptions::new().self_heal(Some(2));
+
+        // Fail the first verification only.
+        let mut checks = 0;
+        let verify = |f: &Path, t: &Path| {
+            checks += 1;
+            if checks == 1 {
+                std::fs::write(t, "corrupted")?;
+                return Ok(false);
+            }
+            contents_match(f, t)
+        };
+        let mut stats = CopyStats::default();
+        let n = copy_file_healing(&from, &to, &opts, &mut None, &mut stats,
+                                  &mut nop_updater(), verify)?;
+
+        assert_eq!(n.map(|(n, _)| n), Some(9));
+        assert_eq!(checks, 2);
+        assert_eq!(stats.healed, 1);
+        assert_eq!(read(&to)?, b"test data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_heal_gives_up() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        std::fs::write(&from, "test data")?;
+        let opts = CopyOptions::new().self_heal(Some(2));
+
+        let mut checks = 0;
+        let mut stats = CopyStats::default();
+        let r = copy_file_healing(&from, &to, &opts, &mut None, &mut stats, &mut nop_updater(),
+                                  |_: &Path, _: &Path| { checks += 1; Ok(false) });
+
+        match r.unwrap_err().downcast::<XcpError>()? {
+            XcpError::VerifyFailed { attempts, .. } => assert_eq!(attempts, 3),
+            e => panic!("Unexpected error {}", e),
+        }
+        assert_eq!(checks, 3);
+        assert_eq!(stats.healed, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_collision_error() {
+        // Simulates a case-insensitive destination.
+        let mut collisions = CaseCollisions::new(CaseCollision::Error);
+        let dest = PathBuf::from("/dest");
+
+        assert!(collisions.check(dest.join("File.txt"), false).is_ok());
+        assert!(collisions.check(dest.join("other.txt"), false).is_ok());
+
+        let err = collisions.check(dest.join("file.TXT"), false).unwrap_err();
+        match err.downcast_ref::<XcpError>() {
+            Some(XcpError::CaseCollision { path, existing }) => {
+                assert_eq!(*path, dest.join("file.TXT"));
+                assert_eq!(*existing, dest.join("File.txt"));
+            }
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_case_collision_rename() -> Result<()> {
+        let mut collisions = CaseCollisions::new(CaseCollision::Rename);
+        let dest = PathBuf::from("/dest");
+
+        assert_eq!(collisions.check(dest.join("File.txt"), false)?, dest.join("File.txt"));
+        assert_eq!(collisions.check(dest.join("file.txt"), false)?, dest.join("file~1.txt"));
+        assert_eq!(collisions.check(dest.join("FILE.txt"), false)?, dest.join("FILE~2.txt"));
+
+        // Contents of a renamed directory follow it.
+        assert_eq!(collisions.check(dest.join("Dir"), true)?, dest.join("Dir"));
+        assert_eq!(collisions.check(dest.join("dir"), true)?, dest.join("dir~1"));
+        assert_eq!(collisions.check(dest.join("dir/a"), false)?, dest.join("dir~1/a"));
+        assert_eq!(collisions.check(dest.join("Dir/a"), false)?, dest.join("Dir/a"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_sensitive_probe() -> Result<()> {
+        let dir = tempdir()?;
+        // Linux filesystems are case-sensitive unless casefolding is
+        // enabled, which tempdirs won't have.
+        assert!(!is_case_insensitive(dir.path())?);
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
 }
@@ -0,0 +1,883 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::env;
+use std::fmt;
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::os::{group_id, user_id};
+use crate::Opts;
+
+
+/// The ways we know how to copy file data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CopyMethod {
+    /// Share the source's extents via the FICLONE ioctl (CoW
+    /// filesystems only). Whole-file only.
+    Reflink,
+    /// Map both files and copy between the mappings, which saves
+    /// syscalls for small files. Whole-file only, and only used for
+    /// files smaller than `os::MMAP_THRESHOLD`.
+    Mmap,
+    /// copy_file_range(2).
+    CopyFileRange,
+    /// A read/write loop through a userspace buffer.
+    Userspace,
+}
+
+impl FromStr for CopyMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<CopyMethod, String> {
+        match s.trim() {
+            "reflink" => Ok(CopyMethod::Reflink),
+            "mmap" => Ok(CopyMethod::Mmap),
+            "cfr" | "copy_file_range" => Ok(CopyMethod::CopyFileRange),
+            "userspace" => Ok(CopyMethod::Userspace),
+            _ => Err(format!("Unknown copy method: {}", s)),
+        }
+    }
+}
+
+/// The signature of an `Inspector`.
+pub type InspectFn = dyn Fn(&[u8], u64) + Send + Sync;
+
+/// A callback over the data of copied files, with the offset of each
+/// slice in its file; see `CopyOptions::inspect()`.
+#[derive(Clone)]
+pub struct Inspector(pub Arc<InspectFn>);
+
+impl fmt::Debug for Inspector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Inspector")
+    }
+}
+
+/// An ordered list of copy methods; each is tried in turn until one
+/// succeeds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CopyMethodChain(Vec<CopyMethod>);
+
+impl CopyMethodChain {
+    pub fn contains(&self, method: CopyMethod) -> bool {
+        self.0.contains(&method)
+    }
+
+    /// The methods that can copy an arbitrary byte range, in order.
+    pub fn byte_methods(&self) -> impl Iterator<Item = CopyMethod> + '_ {
+        self.0.iter()
+            .cloned()
+            .filter(|m| *m != CopyMethod::Reflink && *m != CopyMethod::Mmap)
+    }
+}
+
+impl Default for CopyMethodChain {
+    fn default() -> CopyMethodChain {
+        CopyMethodChain(vec![CopyMethod::Reflink,
+                             CopyMethod::CopyFileRange,
+                             CopyMethod::Userspace])
+    }
+}
+
+impl FromStr for CopyMethodChain {
+    type Err = String;
+
+    /// Parse a comma-separated list, e.g. `reflink,cfr,userspace`.
+    fn from_str(s: &str) -> result::Result<CopyMethodChain, String> {
+        let methods = s.split(',')
+            .map(CopyMethod::from_str)
+            .collect::<result::Result<Vec<CopyMethod>, String>>()?;
+        Ok(CopyMethodChain(methods))
+    }
+}
+
+
+/// File attributes to preserve, parsed from a comma-separated list
+/// as with `cp --preserve`.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Preserve {
+    /// Give copies the source's permission bits, rather than the
+    /// defaults for new files.
+    pub mode: bool,
+    /// Give copies the source's owner and group, where permitted.
+    pub ownership: bool,
+    /// Copy POSIX ACLs, including directories' default ACLs.
+    pub acl: bool,
+    /// Copy the source's access and modification times.
+    pub timestamps: bool,
+    /// Recreate hardlinks between copied files.
+    pub links: bool,
+    /// Copy extended attributes.
+    pub xattr: bool,
+    /// Apply the SELinux context the policy assigns to the
+    /// destination path, as `restorecon` would.
+    pub context: bool,
+    /// Copy inode flags such as immutable and append-only (see
+    /// chattr(1)).
+    pub flags: bool,
+}
+
+impl FromStr for Preserve {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Preserve, String> {
+        let mut preserve = Preserve::default();
+        for attr in s.split(',') {
+            match attr.trim() {
+                "mode" => preserve.mode = true,
+                "ownership" => preserve.ownership = true,
+                "acl" => preserve.acl = true,
+                "timestamps" => preserve.timestamps = true,
+                "links" => preserve.links = true,
+                "xattr" => preserve.xattr = true,
+                "context" => preserve.context = true,
+                "flags" => preserve.flags = true,
+                _ => return Err(format!("Unknown attribute: {}", attr)),
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+/// How to treat source paths that are symlinks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkMode {
+    /// Dereference the link and copy the target's contents.
+    Follow,
+    /// Recreate the link at the destination, even if it is broken.
+    Copy,
+    /// Don't copy the link at all.
+    Skip,
+}
+
+impl FromStr for SymlinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<SymlinkMode, String> {
+        match s {
+            "follow" => Ok(SymlinkMode::Follow),
+            "copy" => Ok(SymlinkMode::Copy),
+            "skip" => Ok(SymlinkMode::Skip),
+            _ => Err(format!("Unknown symlink mode: {}", s)),
+        }
+    }
+}
+
+/// What to do when two sources map to the same path on a
+/// case-insensitive destination, e.g. `File.txt` and `file.txt`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaseCollision {
+    /// Abort the copy.
+    Error,
+    /// Copy the later file under a new name, e.g. `file~1.txt`.
+    Rename,
+}
+
+impl FromStr for CaseCollision {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<CaseCollision, String> {
+        match s {
+            "error" => Ok(CaseCollision::Error),
+            "rename" => Ok(CaseCollision::Rename),
+            _ => Err(format!("Unknown case-collision mode: {}", s)),
+        }
+    }
+}
+
+/// A comma-separated list of names.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameList(pub Vec<String>);
+
+impl FromStr for NameList {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<NameList, String> {
+        Ok(NameList(s.split(',')
+                    .map(|n| n.trim().to_string())
+                    .filter(|n| !n.is_empty())
+                    .collect()))
+    }
+}
+
+/// A file owner, as `USER`, `USER:GROUP` or `:GROUP`. Either part may
+/// be a name or a numeric ID; a missing part is left unchanged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FromStr for Owner {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Owner, String> {
+        fn id(name: &str, lookup: fn(&str) -> Option<u32>) -> result::Result<Option<u32>, String> {
+            if name.is_empty() {
+                return Ok(None);
+            }
+            name.parse()
+                .ok()
+                .or_else(|| lookup(name))
+                .map(Some)
+                .ok_or_else(|| format!("Unknown user or group: {}", name))
+        }
+
+        let (user, group) = match s.find(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        let owner = Owner {
+            uid: id(user, user_id)?,
+            gid: id(group, group_id)?,
+        };
+        if owner.uid.is_none() && owner.gid.is_none() {
+            return Err(format!("Invalid owner: {}", s));
+        }
+        Ok(owner)
+    }
+}
+
+/// Restricts which extended attributes are copied.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct XattrFilter {
+    /// If set, only these attributes are copied.
+    pub names: Option<Vec<String>>,
+    /// Attributes that are never copied.
+    pub exclude: Vec<String>,
+}
+
+impl XattrFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        let included = match self.names {
+            Some(ref names) => names.iter().any(|n| n == name),
+            None => true,
+        };
+        included && !self.exclude.iter().any(|n| n == name)
+    }
+}
+
+/// How to treat holes in the source file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sparse {
+    /// Preserve holes if the source looks sparse (see
+    /// `os::probably_sparse()`).
+    Auto,
+    /// Always use the hole-preserving copier.
+    Always,
+    /// Always copy densely, filling any holes.
+    Never,
+}
+
+impl FromStr for Sparse {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Sparse, String> {
+        match s {
+            "auto" => Ok(Sparse::Auto),
+            "always" => Ok(Sparse::Always),
+            "never" => Ok(Sparse::Never),
+            _ => Err(format!("Unknown sparse mode: {}", s)),
+        }
+    }
+}
+
+/// When to flush copied files to disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fsync {
+    Never,
+    /// fsync each file as soon as it is copied.
+    File,
+    /// fsync files in batches once a number of them have been
+    /// copied, so the writeback of each batch can overlap.
+    Batch,
+    /// Sync the whole destination filesystem once, at the end.
+    Syncfs,
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Fsync, String> {
+        match s {
+            "never" => Ok(Fsync::Never),
+            "file" => Ok(Fsync::File),
+            "batch" => Ok(Fsync::Batch),
+            "syncfs" => Ok(Fsync::Syncfs),
+            _ => Err(format!("Unknown fsync mode: {}", s)),
+        }
+    }
+}
+
+/// Whether to continue an interrupted copy into an existing, shorter
+/// destination.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resume {
+    Never,
+    /// Resume if the source hasn't been modified since the
+    /// destination was written.
+    Unverified,
+    /// As `Unverified`, but also check the existing destination
+    /// matches the start of the source.
+    Verify,
+}
+
+/// When to copy files to a temporary name and rename them into place
+/// once complete, rather than writing the destination directly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Atomic {
+    Never,
+    /// Only where the destination doesn't exist yet. Replacing an
+    /// existing file gives it a new inode, which breaks hardlinks to
+    /// it and drops any owner, ACLs or xattrs that aren't preserved.
+    NewFiles,
+    Always,
+}
+
+/// Whether to only apply the source's metadata to existing
+/// destinations with the same contents, rather than copying data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataOnly {
+    Never,
+    /// Treat files with the same size and mtime as identical.
+    Quick,
+    /// Compare the contents of the files.
+    Verify,
+}
+
+/// What to do when a destination file already exists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum Clobber {
+    /// Replace the destination.
+    Overwrite,
+    /// Leave the destination alone and skip the copy.
+    NoClobber,
+    /// Replace the destination only if the source is newer.
+    Update,
+}
+
+/// Behaviour flags for a file copy. Construct with `new()` and
+/// override the defaults with the builder methods, e.g:
+///
+/// ```text
+/// CopyOptions::new().reflink(false).sparse(Sparse::Never).fsync(Fsync::File)
+/// ```
+#[derive(Clone, Debug)]
+pub struct CopyOptions {
+    pub methods: CopyMethodChain,
+    pub clobber: Clobber,
+    pub preserve_mode: bool,
+    pub normalize_mode: bool,
+    pub preserve_owner: bool,
+    pub preserve_acls: bool,
+    pub uniform_mode: Option<u32>,
+    pub uniform_dir_mode: Option<u32>,
+    pub uniform_owner: Option<Owner>,
+    pub preserve_context: bool,
+    pub preserve_timestamps: bool,
+    pub preserve_links: bool,
+    pub preserve_xattrs: bool,
+    pub preserve_flags: bool,
+    pub xattrs: XattrFilter,
+    pub mtime: Option<i64>,
+    pub sparse: Sparse,
+    pub min_hole_size: u64,
+    pub fsync: Fsync,
+    pub resume: Resume,
+    pub atomic: Atomic,
+    pub dry_run: bool,
+    pub metadata_only: MetadataOnly,
+    pub self_heal: Option<u32>,
+    pub retries: Option<u32>,
+    pub bwlimit: Option<u64>,
+    pub preallocate: bool,
+    pub drop_cache: bool,
+    pub skip_zeros: bool,
+    pub device_contents: bool,
+    pub fifo_contents: bool,
+    pub report_fragmentation: Option<u64>,
+    pub inspect: Option<Inspector>,
+}
+
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions {
+            methods: CopyMethodChain::default(),
+            clobber: Clobber::Overwrite,
+            preserve_mode: true,
+            normalize_mode: false,
+            preserve_owner: false,
+            preserve_acls: false,
+            uniform_mode: None,
+            uniform_dir_mode: None,
+            uniform_owner: None,
+            preserve_context: false,
+            preserve_timestamps: false,
+            preserve_links: false,
+            preserve_xattrs: false,
+            preserve_flags: false,
+            xattrs: XattrFilter::default(),
+            mtime: None,
+            sparse: Sparse::Auto,
+            min_hole_size: 0,
+            fsync: Fsync::Never,
+            resume: Resume::Never,
+            atomic: Atomic::Never,
+            dry_run: false,
+            metadata_only: MetadataOnly::Never,
+            self_heal: None,
+            retries: None,
+            bwlimit: None,
+            preallocate: false,
+            drop_cache: false,
+            skip_zeros: false,
+            device_contents: false,
+            fifo_contents: false,
+            report_fragmentation: None,
+            inspect: None,
+        }
+    }
+}
+
+impl CopyOptions {
+    pub fn new() -> CopyOptions {
+        CopyOptions::default()
+    }
+
+    pub fn from_opts(opts: &Opts) -> CopyOptions {
+        let copts = CopyOptions::base_opts(opts);
+        if opts.reproducible {
+            copts.reproducible()
+        } else {
+            copts
+        }
+    }
+
+    fn base_opts(opts: &Opts) -> CopyOptions {
+        let resume = if opts.verify_resume {
+            Resume::Verify
+        } else if opts.resume {
+            Resume::Unverified
+        } else {
+            Resume::Never
+        };
+
+        let metadata_only = if opts.verify_metadata_only {
+            MetadataOnly::Verify
+        } else if opts.metadata_only {
+            MetadataOnly::Quick
+        } else {
+            MetadataOnly::Never
+        };
+
+        let clobber = if opts.noclobber {
+            Clobber::NoClobber
+        } else if opts.update {
+            Clobber::Update
+        } else {
+            Clobber::Overwrite
+        };
+
+        let atomic = if opts.atomic {
+            Atomic::Always
+        } else if opts.recursive && !opts.no_atomic {
+            Atomic::NewFiles
+        } else {
+            Atomic::Never
+        };
+
+        CopyOptions::new()
+            .methods(opts.methods.clone())
+            .clobber(clobber)
+            .uniform_mode(opts.uniform_mode)
+            .uniform_dir_mode(opts.uniform_dir_mode)
+            .uniform_owner(opts.uniform_owner)
+            .preserve_mode(opts.preserve.mode)
+            .preserve_owner(opts.preserve.ownership)
+            .preserve_acls(opts.preserve.acl)
+            .preserve_context(opts.preserve.context)
+            .preserve_timestamps(opts.preserve.timestamps)
+            .preserve_links(opts.preserve.links)
+            // Naming attributes implies copying them.
+            .preserve_xattrs(opts.preserve.xattr || opts.xattr_name.is_some())
+            .preserve_flags(opts.preserve.flags)
+            .xattrs(XattrFilter {
+                names: opts.xattr_name.clone().map(|n| n.0),
+                exclude: opts.xattr_exclude.clone().map(|n| n.0).unwrap_or_default(),
+            })
+            .mtime(opts.mtime)
+            .sparse(opts.sparse)
+            .min_hole_size(opts.min_hole_size.unwrap_or(0))
+            .fsync(opts.fsync.unwrap_or(Fsync::Never))
+            .resume(resume)
+            .atomic(atomic)
+            .dry_run(opts.dry_run)
+            .metadata_only(metadata_only)
+            .self_heal(opts.self_heal)
+            .retries(opts.retries)
+            .bwlimit(opts.bwlimit)
+            .preallocate(opts.preallocate)
+            .drop_cache(opts.drop_cache)
+            .skip_zeros(opts.skip_zeros)
+            .device_contents(opts.device_contents)
+            .fifo_contents(opts.fifo_contents)
+            .report_fragmentation(opts.report_fragmentation)
+    }
+
+    pub fn methods(mut self, methods: CopyMethodChain) -> CopyOptions {
+        self.methods = methods;
+        self
+    }
+
+    pub fn clobber(mut self, clobber: Clobber) -> CopyOptions {
+        self.clobber = clobber;
+        self
+    }
+
+    /// Enable or disable attempting a reflink before copying data.
+    #[allow(dead_code)]
+    pub fn reflink(mut self, enable: bool) -> CopyOptions {
+        self.methods.0.retain(|m| *m != CopyMethod::Reflink);
+        if enable {
+            self.methods.0.insert(0, CopyMethod::Reflink);
+        }
+        self
+    }
+
+    pub fn preserve_mode(mut self, preserve: bool) -> CopyOptions {
+        self.preserve_mode = preserve;
+        self
+    }
+
+    /// Give copied files mode 0755 if any of the source's execute bits
+    /// are set and 0644 otherwise, as git does. `uniform_mode` takes
+    /// precedence.
+    pub fn normalize_mode(mut self, normalize: bool) -> CopyOptions {
+        self.normalize_mode = normalize;
+        self
+    }
+
+    /// Compose the options so that copying the same source twice
+    /// gives identical trees: modes are normalised, and files and directories are given a
+    /// fixed mtime and owner. Any mtime, owner or directory mode
+    /// already set is kept; otherwise the mtime is taken from
+    /// `SOURCE_DATE_EPOCH` if set, or is the epoch, and the owner is
+    /// root. Only root can give files away, so for other users copies
+    /// are left owned by them.
+    pub fn reproducible(self) -> CopyOptions {
+        let mtime = self.mtime
+            .or_else(|| env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.parse().ok()))
+            .unwrap_or(0);
+        let owner = self.uniform_owner.or_else(|| {
+            if unsafe { libc::geteuid() } == 0 {
+                Some(Owner { uid: Some(0), gid: Some(0) })
+            } else {
+                None
+            }
+        });
+        let dir_mode = self.uniform_dir_mode.unwrap_or(0o755);
+
+        self.normalize_mode(true)
+            .preserve_owner(false)
+            .preserve_acls(false)
+            .mtime(Some(mtime))
+            .uniform_owner(owner)
+            .uniform_dir_mode(Some(dir_mode))
+    }
+
+    /// Give copied files the source's owner and group. Failure to
+    /// change them for lack of privilege is ignored, as with `cp -p`.
+    pub fn preserve_owner(mut self, preserve: bool) -> CopyOptions {
+        self.preserve_owner = preserve;
+        self
+    }
+
+    /// Copy the POSIX ACLs of files and directories. A destination
+    /// without ACL support is warned about rather than failing the
+    /// copy.
+    pub fn preserve_acls(mut self, preserve: bool) -> CopyOptions {
+        self.preserve_acls = preserve;
+        self
+    }
+
+    /// Give every copied file this mode, overriding `preserve_mode`.
+    pub fn uniform_mode(mut self, mode: Option<u32>) -> CopyOptions {
+        self.uniform_mode = mode;
+        self
+    }
+
+    /// Give every copied directory this mode.
+    pub fn uniform_dir_mode(mut self, mode: Option<u32>) -> CopyOptions {
+        self.uniform_dir_mode = mode;
+        self
+    }
+
+    /// Give every copied file and directory this owner, overriding
+    /// `preserve_owner`.
+    pub fn uniform_owner(mut self, owner: Option<Owner>) -> CopyOptions {
+        self.uniform_owner = owner;
+        self
+    }
+
+    /// Relabel the destination with the SELinux context its path
+    /// maps to. Requires the `selinux` feature.
+    pub fn preserve_context(mut self, preserve: bool) -> CopyOptions {
+        self.preserve_context = preserve;
+        self
+    }
+
+    pub fn preserve_timestamps(mut self, preserve: bool) -> CopyOptions {
+        self.preserve_timestamps = preserve;
+        self
+    }
+
+    /// When copying multiple files, recreate sources that are
+    /// hardlinked to each other as hardlinks rather than copying the
+    /// data again.
+    pub fn preserve_links(mut self, preserve: bool) -> CopyOptions {
+        self.preserve_links = preserve;
+        self
+    }
+
+    pub fn preserve_xattrs(mut self, preserve: bool) -> CopyOptions {
+        self.preserve_xattrs = preserve;
+        self
+    }
+
+    /// Copy inode flags such as immutable and append-only. Only
+    /// supported on Linux, and setting immutable or append-only needs
+    /// CAP_LINUX_IMMUTABLE; where the flags can't be copied a warning
+    /// is logged rather than failing the copy.
+    pub fn preserve_flags(mut self, preserve: bool) -> CopyOptions {
+        self.preserve_flags = preserve;
+        self
+    }
+
+    /// Restrict which extended attributes are copied when
+    /// `preserve_xattrs` is set.
+    pub fn xattrs(mut self, filter: XattrFilter) -> CopyOptions {
+        self.xattrs = filter;
+        self
+    }
+
+    /// Set the destination's access and modification times to this
+    /// many seconds since the epoch. Overrides `preserve_timestamps`.
+    pub fn mtime(mut self, mtime: Option<i64>) -> CopyOptions {
+        self.mtime = mtime;
+        self
+    }
+
+    pub fn sparse(mut self, sparse: Sparse) -> CopyOptions {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Holes smaller than this are copied densely by the sparse
+    /// copier.
+    pub fn min_hole_size(mut self, size: u64) -> CopyOptions {
+        self.min_hole_size = size;
+        self
+    }
+
+    pub fn fsync(mut self, fsync: Fsync) -> CopyOptions {
+        self.fsync = fsync;
+        self
+    }
+
+    pub fn resume(mut self, resume: Resume) -> CopyOptions {
+        self.resume = resume;
+        self
+    }
+
+    /// When to copy files to a temporary name and rename them into
+    /// place once complete. Ignored when resuming, which has to write
+    /// in place.
+    pub fn atomic(mut self, atomic: Atomic) -> CopyOptions {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Plan the copy without writing anything; see `plan_file()` and
+    /// `plan_copy()`.
+    pub fn dry_run(mut self, dry_run: bool) -> CopyOptions {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn metadata_only(mut self, metadata_only: MetadataOnly) -> CopyOptions {
+        self.metadata_only = metadata_only;
+        self
+    }
+
+    /// Verify each copy against its source, re-copying up to this
+    /// many times on a mismatch.
+    pub fn self_heal(mut self, attempts: Option<u32>) -> CopyOptions {
+        self.self_heal = attempts;
+        self
+    }
+
+    /// Retry each chunk of data up to this many times after transient
+    /// errors; see `is_transient()`.
+    pub fn retries(mut self, attempts: Option<u32>) -> CopyOptions {
+        self.retries = attempts;
+        self
+    }
+
+    pub fn bwlimit(mut self, bwlimit: Option<u64>) -> CopyOptions {
+        self.bwlimit = bwlimit;
+        self
+    }
+
+    /// Allocate the destination's blocks before copying data into
+    /// it. Has no effect on sparse or reflinked copies.
+    pub fn preallocate(mut self, preallocate: bool) -> CopyOptions {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// Drop the destination's pages from the page cache once it's
+    /// complete (and synced, if enabled), so large copies don't evict
+    /// more useful data. This is best-effort; failures are ignored.
+    pub fn drop_cache(mut self, drop: bool) -> CopyOptions {
+        self.drop_cache = drop;
+        self
+    }
+
+    /// Scan copied data for blocks of zeros and leave holes in the
+    /// destination rather than writing them, so dense sources with
+    /// runs of zeros are copied sparsely. This forces a userspace
+    /// copy of the data, so costs CPU.
+    pub fn skip_zeros(mut self, skip: bool) -> CopyOptions {
+        self.skip_zeros = skip;
+        self
+    }
+
+    /// Copy the contents of device sources rather than recreating
+    /// the device node.
+    pub fn device_contents(mut self, enable: bool) -> CopyOptions {
+        self.device_contents = enable;
+        self
+    }
+
+    /// Copy what is written to FIFO sources, blocking until a writer
+    /// closes them, rather than recreating the FIFO. Sockets are
+    /// always recreated.
+    pub fn fifo_contents(mut self, enable: bool) -> CopyOptions {
+        self.fifo_contents = enable;
+        self
+    }
+
+    /// Log the number of extents in each destination file, warning
+    /// if it exceeds the given threshold.
+    pub fn report_fragmentation(mut self, threshold: Option<u64>) -> CopyOptions {
+        self.report_fragmentation = threshold;
+        self
+    }
+
+    /// Pass the data of each copied file to `inspect` as it's copied,
+    /// with its offset in the file, e.g. to index records without
+    /// reading the file again. The data only passes through xcp with
+    /// a userspace copy, so that's used whatever the copy methods.
+    /// Holes skipped by sparse copies aren't passed to it.
+    #[allow(dead_code)]
+    pub fn inspect<F>(mut self, inspect: F) -> CopyOptions
+        where F: Fn(&[u8], u64) + Send + Sync + 'static
+    {
+        self.inspect = Some(Inspector(Arc::new(inspect)));
+        self
+    }
+
+    /// Whether file data has to be copied through userspace, whatever
+    /// the copy methods, because it's looked at on the way.
+    pub fn needs_userspace(&self) -> bool {
+        self.skip_zeros || self.inspect.is_some()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_method_chain() {
+        let chain = "userspace".parse::<CopyMethodChain>().unwrap();
+        assert_eq!(chain, CopyMethodChain(vec![CopyMethod::Userspace]));
+        assert!(!chain.contains(CopyMethod::Reflink));
+
+        let chain = "reflink,mmap,cfr".parse::<CopyMethodChain>().unwrap();
+        assert!(chain.contains(CopyMethod::Reflink));
+        assert!(chain.contains(CopyMethod::Mmap));
+        assert_eq!(chain.byte_methods().collect::<Vec<_>>(), vec![CopyMethod::CopyFileRange]);
+
+        assert!("reflink,sendmail".parse::<CopyMethodChain>().is_err());
+    }
+
+    #[test]
+    fn test_parse_fsync() {
+        assert_eq!("batch".parse::<Fsync>().unwrap(), Fsync::Batch);
+        assert_eq!("syncfs".parse::<Fsync>().unwrap(), Fsync::Syncfs);
+        assert!("sometimes".parse::<Fsync>().is_err());
+    }
+
+    #[test]
+    fn test_parse_preserve() {
+        let preserve = "mode,context".parse::<Preserve>().unwrap();
+        assert!(preserve.mode && preserve.context && !preserve.timestamps);
+        assert!("timestamps".parse::<Preserve>().unwrap().timestamps);
+        assert!("mode,ownership".parse::<Preserve>().unwrap().ownership);
+        assert!("acl".parse::<Preserve>().unwrap().acl);
+        assert!(!"mode".parse::<Preserve>().unwrap().context);
+        assert!("mode,flags".parse::<Preserve>().unwrap().flags);
+        assert!("mode,colour".parse::<Preserve>().is_err());
+    }
+
+    #[test]
+    fn test_parse_owner() {
+        assert_eq!("1000:100".parse::<Owner>().unwrap(), Owner { uid: Some(1000), gid: Some(100) });
+        assert_eq!("root".parse::<Owner>().unwrap(), Owner { uid: Some(0), gid: None });
+        assert_eq!(":0".parse::<Owner>().unwrap(), Owner { uid: None, gid: Some(0) });
+        assert!("no-such-user-xcp".parse::<Owner>().is_err());
+        assert!(":".parse::<Owner>().is_err());
+    }
+
+    #[test]
+    fn test_xattr_filter() {
+        assert!(XattrFilter::default().matches("user.foo"));
+
+        let filter = XattrFilter {
+            names: Some("user.foo, user.bar".parse::<NameList>().unwrap().0),
+            exclude: "user.bar".parse::<NameList>().unwrap().0,
+        };
+        assert!(filter.matches("user.foo"));
+        assert!(!filter.matches("user.bar"));
+        assert!(!filter.matches("user.baz"));
+    }
+
+    #[test]
+    fn test_reflink_option() {
+        let opts = CopyOptions::new().reflink(false);
+        assert!(!opts.methods.contains(CopyMethod::Reflink));
+
+        let opts = CopyOptions::new()
+            .methods("userspace".parse().unwrap())
+            .reflink(true);
+        assert_eq!(opts.methods, CopyMethodChain(vec![CopyMethod::Reflink, CopyMethod::Userspace]));
+    }
+}
@@ -13,54 +13,60 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-
-use libc;
-use std::fs::File;
+//! Low-level file operations. The platform-specific primitives are in
+//! the `linux`, `macos` and `freebsd` submodules, which export the
+//! same signatures; the remainder are common to all of them.
+
+use std::cell::Cell;
+use std::cmp;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString, OsString};
+use std::fs::{File, Metadata};
 use std::mem;
+use log::{debug, warn};
 use std::io;
+use std::io::{ErrorKind as IOKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
-
-use crate::errors::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::errors::{io_err, Error, Result, XcpError};
+use crate::options::InspectFn;
+use crate::progress::CopyStats;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+use self::linux as sys;
+
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "macos")]
+use self::macos as sys;
+
+#[cfg(target_os = "freebsd")]
+mod freebsd;
+#[cfg(target_os = "freebsd")]
+use self::freebsd as sys;
+
+pub use self::sys::{
+    allocate_range, copy_file_bytes, copy_inode_flags, drop_cache, extent_count, fiemap, fs_type,
+    get_xattr, is_encrypted, list_xattrs, mapped_files, missing_key, preallocate, reflink,
+    set_xattr, syncfs,
+};
+#[cfg(test)]
+use self::sys::birth_time;
+#[cfg(target_os = "macos")]
+pub use self::sys::clone_file;
+use self::sys::{ftruncate64, futimens, lseek64, lutimes, off64_t, SEEK_DATA, SEEK_HOLE};
 
 /* **** Low level operations **** */
 
-mod ffi {
-    // Assumes Linux kernel >= 4.5.
-    #[cfg(feature = "kernel_copy_file_range")]
-    pub unsafe fn copy_file_range(
-        fd_in: libc::c_int,
-        off_in: *mut libc::loff_t,
-        fd_out: libc::c_int,
-        off_out: *mut libc::loff_t,
-        len: libc::size_t,
-        flags: libc::c_uint,
-    ) -> libc::ssize_t {
-        libc::syscall(
-            libc::SYS_copy_file_range,
-            fd_in,
-            off_in,
-            fd_out,
-            off_out,
-            len,
-            flags,
-        ) as libc::ssize_t
-    }
-
-    // Requires GlibC >= 2.27
-    #[cfg(not(feature = "kernel_copy_file_range"))]
-    extern "C" {
-        pub fn copy_file_range(
-            fd_in: libc::c_int,
-            off_in: libc::loff_t,
-            fd_out: libc::c_int,
-            off_out: libc::loff_t,
-            len: libc::size_t,
-            flags: libc::c_uint,
-        ) -> libc::ssize_t;
-    }
-}
-
 fn result_or_errno<T>(result: i64, retval: T) -> Result<T> {
     match result {
         -1 => Err(io::Error::last_os_error().into()),
@@ -68,65 +74,760 @@ fn result_or_errno<T>(result: i64, retval: T) -> Result<T> {
     }
 }
 
-/// Full mapping of copy_file_range(2). Not used directly, as we
-/// always want to copy the same range to the same offset. See
-/// wrappers below.
-pub fn copy_file_range(infd: &File, mut in_off: i64,
-                       outfd: &File, mut out_off: i64,
-                       bytes: u64) -> Result<u64>
+/// The most a single copy_file_range call is asked to copy. This is
+/// the ceiling read(2) and write(2) apply internally; larger lengths
+/// can fail with EOVERFLOW or be clamped by some kernels, and would
+/// overflow `size_t` on 32-bit targets, so this is the one place copy
+/// requests are capped. (macOS copies with fcopyfile(3), which takes
+/// no length.)
+#[cfg(not(target_os = "macos"))]
+const MAX_CFR_LEN: u64 = 0x7fff_f000;
+
+/// Make a copy of `bytes` with `copy` in calls of at most `max` bytes,
+/// stopping early at the end of the source. An error after some data
+/// has been copied is returned as a short copy, leaving the caller's
+/// next call to report it.
+#[cfg(not(target_os = "macos"))]
+fn copy_clamped<F>(bytes: u64, max: u64, mut copy: F) -> Result<u64>
+    where F: FnMut(u64) -> Result<u64>
 {
-    let r = unsafe {
-        ffi::copy_file_range(
-            infd.as_raw_fd(),
-            &mut in_off as *mut i64,
-            outfd.as_raw_fd(),
-            &mut out_off as *mut i64,
-            bytes as usize,
-            0,
-        ) as i64
-    };
-    result_or_errno(r, r as u64)
+    // A zero-length call still checks the descriptors; see
+    // supports_cfr().
+    if bytes == 0 {
+        return copy(0);
+    }
+    let mut copied = 0u64;
+    while copied < bytes {
+        match copy(cmp::min(bytes - copied, max)) {
+            Ok(0) => break,
+            Ok(n) => copied += n,
+            Err(_) if copied > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(copied)
 }
 
-/// Version of copy_file_range(2) that copies the give range to the
-/// same place in the target file.
-#[allow(dead_code)]
-pub fn copy_file_chunk(infd: &File, outfd: &File,
-                       off: i64, bytes: u64) -> Result<u64>
+/// Buffer size used by the userspace copy fallback. For files this is
+/// rounded up to a whole number of the filesystem's preferred IO size.
+const USPACE_BUFFER: usize = 1024 * 1024;
+
+/// Upper bound on the userspace buffer, as some filesystems (e.g. FUSE
+/// and network mounts) report very large preferred IO sizes.
+const MAX_USPACE_BUFFER: usize = 8 * 1024 * 1024;
+
+thread_local! {
+    /// The userspace copy buffer. This is reused by every copy on the
+    /// thread, so copying a tree of small files doesn't allocate a
+    /// buffer per file.
+    static USPACE_BUF: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+/// The userspace buffer size for a file with the given preferred IO
+/// size (`st_blksize`).
+fn uspace_buffer_size(blksize: u64) -> usize {
+    let blksize = cmp::max(cmp::min(blksize, MAX_USPACE_BUFFER as u64) as usize, 1);
+    cmp::min(USPACE_BUFFER.div_ceil(blksize) * blksize, MAX_USPACE_BUFFER)
+}
+
+fn file_buffer_size(fd: &File) -> Result<usize> {
+    Ok(uspace_buffer_size(fd.metadata()?.blksize()))
+}
+
+/// Run `f` with the thread's userspace buffer, grown to at least
+/// `size` bytes if necessary.
+fn with_uspace_buffer<T, F>(size: usize, f: F) -> T
+    where F: FnOnce(&mut [u8]) -> T
 {
-    copy_file_range(infd, off, outfd, off, bytes)
+    USPACE_BUF.with(|cell| {
+        // Taken rather than borrowed, so a nested copy just allocates
+        // a buffer of its own.
+        let mut buf = cell.take();
+        if buf.len() < size {
+            buf.resize(size, 0);
+        }
+        let r = f(&mut buf[..size]);
+        cell.set(buf);
+        r
+    })
 }
 
-/// Version of copy_file_range that defers offset-management to the
-/// syscall. see copy_file_range(2) for details.
-pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
-    let r = unsafe {
-        ffi::copy_file_range(
-            infd.as_raw_fd(),
-            null_mut(),
-            outfd.as_raw_fd(),
-            null_mut(),
-            bytes as usize,
-            0,
-        ) as i64
-    };
-    result_or_errno(r, r as u64)
+/// Copy up to `bytes` from the reader to the writer via a userspace
+/// buffer, timing the read and write halves of each chunk into
+/// `stats`. Returns the number of bytes copied, which may be short if
+/// EOF is reached.
+pub fn copy_stream<R, W>(reader: &mut R, writer: &mut W, bytes: u64,
+                         stats: &mut CopyStats) -> Result<u64>
+    where R: Read, W: Write
+{
+    copy_stream_with(reader, writer, bytes, USPACE_BUFFER, stats, &mut |_| ())
+}
+
+/// As `copy_stream`, using a buffer of `bufsize` bytes and passing
+/// each chunk read to `inspect` before it's written.
+pub fn copy_stream_with<R, W>(reader: &mut R, writer: &mut W, bytes: u64, bufsize: usize,
+                              stats: &mut CopyStats, inspect: &mut dyn FnMut(&[u8]))
+                              -> Result<u64>
+    where R: Read, W: Write
+{
+    with_uspace_buffer(bufsize, |buf| {
+        let mut written = 0u64;
+
+        while written < bytes {
+            let len = cmp::min(bytes - written, buf.len() as u64) as usize;
+            let start = Instant::now();
+            let r = reader.read(&mut buf[..len])?;
+            stats.read_time += start.elapsed();
+            if r == 0 {
+                break;
+            }
+            stats.read_bytes += r as u64;
+            inspect(&buf[..r]);
+
+            let start = Instant::now();
+            writer.write_all(&buf[..r])?;
+            stats.write_time += start.elapsed();
+            stats.write_bytes += r as u64;
+            written += r as u64;
+        }
+
+        Ok(written)
+    })
+}
+
+/// Userspace equivalent of `copy_file_bytes`; copies up to `bytes`
+/// from the current input cursor to the current output cursor via a
+/// read/write loop.
+pub fn copy_bytes_uspace(mut infd: &File, mut outfd: &File, bytes: u64,
+                         stats: &mut CopyStats) -> Result<u64> {
+    let bufsize = file_buffer_size(infd)?;
+    copy_stream_with(&mut infd, &mut outfd, bytes, bufsize, stats, &mut |_| ())
+}
+
+/// As `copy_bytes_uspace`, passing the data to `inspect` as it's
+/// copied along with its offset in the source, where the copy starts
+/// at `off`.
+pub fn copy_bytes_inspected(mut infd: &File, mut outfd: &File, bytes: u64, mut off: u64,
+                            stats: &mut CopyStats, inspect: &dyn Fn(&[u8], u64)) -> Result<u64> {
+    let bufsize = file_buffer_size(infd)?;
+    copy_stream_with(&mut infd, &mut outfd, bytes, bufsize, stats, &mut |buf| {
+        inspect(buf, off);
+        off += buf.len() as u64;
+    })
+}
+
+/// Granularity at which `copy_bytes_skip_zeros` looks for zeros.
+const ZERO_BLOCK: usize = 4096;
+
+/// Whether the buffer is entirely zero. This checks a word at a time,
+/// with the unaligned ends checked bytewise.
+pub fn is_zero(buf: &[u8]) -> bool {
+    let (head, words, tail) = unsafe { buf.align_to::<u64>() };
+    head.iter().all(|b| *b == 0) && words.iter().all(|w| *w == 0) && tail.iter().all(|b| *b == 0)
+}
+
+/// As `copy_bytes_uspace`, but blocks that are entirely zero are
+/// skipped over rather than written, leaving holes in the destination.
+/// If the copy ends on a hole the destination is extended to cover it.
+/// All of the data, zeros included, is passed to `inspect` as with
+/// `copy_bytes_inspected()`.
+pub fn copy_bytes_skip_zeros(mut infd: &File, mut outfd: &File, bytes: u64, mut off: u64,
+                             stats: &mut CopyStats,
+                             inspect: Option<&InspectFn>) -> Result<u64> {
+    let bufsize = file_buffer_size(infd)?;
+    let copied = with_uspace_buffer(bufsize, |buf| -> Result<u64> {
+        let mut copied = 0u64;
+
+        while copied < bytes {
+            let len = cmp::min(bytes - copied, buf.len() as u64) as usize;
+            let start = Instant::now();
+            let r = infd.read(&mut buf[..len])?;
+            stats.read_time += start.elapsed();
+            if r == 0 {
+                break;
+            }
+            stats.read_bytes += r as u64;
+            if let Some(inspect) = inspect {
+                inspect(&buf[..r], off);
+                off += r as u64;
+            }
+
+            let start = Instant::now();
+            let mut pos = 0;
+            while pos < r {
+                let zero = is_zero(&buf[pos..cmp::min(pos + ZERO_BLOCK, r)]);
+                let mut end = cmp::min(pos + ZERO_BLOCK, r);
+                while end < r && is_zero(&buf[end..cmp::min(end + ZERO_BLOCK, r)]) == zero {
+                    end = cmp::min(end + ZERO_BLOCK, r);
+                }
+                if zero {
+                    outfd.seek(SeekFrom::Current((end - pos) as i64))?;
+                } else {
+                    outfd.write_all(&buf[pos..end])?;
+                    stats.write_bytes += (end - pos) as u64;
+                }
+                pos = end;
+            }
+            stats.write_time += start.elapsed();
+            copied += r as u64;
+        }
+        Ok(copied)
+    })?;
+
+    let end = cursor(outfd)?;
+    if end > outfd.metadata()?.len() {
+        outfd.set_len(end)?;
+    }
+    Ok(copied)
+}
+
+/// Extract the OS errno from an error, if it has one. This looks
+/// through `XcpError::PartialCopy` to the underlying error.
+pub fn errno(err: &Error) -> Option<i32> {
+    match err.downcast_ref::<XcpError>() {
+        Some(XcpError::PartialCopy { source, .. }) => source.raw_os_error(),
+        _ => err.downcast_ref::<io::Error>().and_then(|e| e.raw_os_error()),
+    }
+}
+
+/// Whether an error means a copy method isn't supported for these
+/// files (e.g. ENOSYS from an old kernel, or EXDEV across
+/// filesystems), so the next method should be tried, rather than
+/// being a real failure such as EIO or ENOSPC.
+pub fn is_unsupported(err: &Error) -> bool {
+    match errno(err) {
+        Some(e) => e == libc::ENOSYS || e == libc::EXDEV || e == libc::EOPNOTSUPP
+            || e == libc::ENOTSUP,
+        None => false,
+    }
+}
+
+/// As `is_unsupported()`, for errors from a reflink. These also fail
+/// with EINVAL where the filesystem can't share the files' extents
+/// (see ioctl_ficlone(2)), which elsewhere would be a real failure.
+pub fn is_reflink_unsupported(err: &Error) -> bool {
+    is_unsupported(err) || errno(err) == Some(libc::EINVAL)
+}
+
+/// Whether an error may go away if the operation is retried, such as
+/// ESTALE from an NFS server that has failed over. Permanent errors
+/// such as EACCES, ENOSPC and EIO aren't.
+pub fn is_transient(err: &Error) -> bool {
+    match errno(err) {
+        Some(e) => e == libc::EAGAIN || e == libc::ESTALE || e == libc::EBUSY,
+        None => false,
+    }
+}
+
+/// Set the first time copy_file_range returns ENOSYS (i.e. the kernel
+/// is < 4.5), after which all copies use the userspace fallback.
+static CFR_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+fn cfr_checked_with<F>(cfr: F, unavailable: &AtomicBool,
+                       infd: &File, outfd: &File, bytes: u64) -> Result<u64>
+    where F: Fn(&File, &File, u64) -> Result<u64>
+{
+    if unavailable.load(Ordering::Relaxed) {
+        return Err(io::Error::from_raw_os_error(libc::ENOSYS).into());
+    }
+    let r = cfr(infd, outfd, bytes);
+    if let Err(ref e) = r {
+        if errno(e) == Some(libc::ENOSYS) && !unavailable.swap(true, Ordering::Relaxed) {
+            warn!("copy_file_range() is not supported by this kernel; \
+                   falling back to userspace copy");
+        }
+    }
+    r
+}
+
+fn copy_bytes_with<F>(cfr: F, unavailable: &AtomicBool,
+                      infd: &File, outfd: &File, bytes: u64) -> Result<u64>
+    where F: Fn(&File, &File, u64) -> Result<u64>
+{
+    match cfr_checked_with(cfr, unavailable, infd, outfd, bytes) {
+        // ENOTSUP is also returned by the macOS implementation for
+        // anything other than a whole-file copy.
+        Err(ref e) if errno(e) == Some(libc::ENOSYS) || errno(e) == Some(libc::ENOTSUP) => {
+            copy_bytes_uspace(infd, outfd, bytes, &mut CopyStats::default())
+        }
+        r => r,
+    }
+}
+
+/// Version of `copy_file_bytes` that fails immediately with ENOSYS
+/// once the kernel is known not to support copy_file_range, rather
+/// than re-probing the syscall on every call.
+pub fn copy_file_bytes_checked(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
+    cfr_checked_with(copy_file_bytes, &CFR_UNAVAILABLE, infd, outfd, bytes)
+}
+
+/// Probe whether copy_file_range can copy between the descriptors by
+/// attempting a zero-length copy, which leaves the cursors and the
+/// files untouched. ENOSYS, EXDEV, EOPNOTSUPP and EINVAL mean the
+/// kernel or filesystems don't support it; any other error is taken
+/// to be specific to these files, and is left for the copy itself to
+/// report.
+pub fn supports_cfr(infd: &File, outfd: &File) -> bool {
+    // fcopyfile() works between any pair of files, but is emulated
+    // for whole-file copies only; see macos::copy_file_bytes().
+    if cfg!(target_os = "macos") {
+        return true;
+    }
+    match cfr_checked_with(copy_file_bytes, &CFR_UNAVAILABLE, infd, outfd, 0) {
+        Err(ref e) if is_unsupported(e) || errno(e) == Some(libc::EINVAL) => {
+            debug!("copy_file_range() not supported between these files: {}", e);
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Results of `supports_cfr()` by source and destination device, so
+/// a tree copy only probes each pair of filesystems once.
+static CFR_DEVICES: Mutex<Vec<((u64, u64), bool)>> = Mutex::new(Vec::new());
+
+fn cfr_supported_cached(cache: &Mutex<Vec<((u64, u64), bool)>>,
+                        infd: &File, outfd: &File) -> Result<bool> {
+    let devs = (infd.metadata()?.dev(), outfd.metadata()?.dev());
+    let mut cache = cache.lock()
+        .map_err(|_| io_err(IOKind::Other, "Poisoned copy_file_range cache."))?;
+    if let Some((_, supported)) = cache.iter().find(|(d, _)| *d == devs) {
+        return Ok(*supported);
+    }
+    let supported = supports_cfr(infd, outfd);
+    cache.push((devs, supported));
+    Ok(supported)
+}
+
+/// Whether copy_file_range can be used between the descriptors'
+/// filesystems, probing with `supports_cfr()` the first time each
+/// pair of devices is seen.
+pub fn cfr_supported(infd: &File, outfd: &File) -> Result<bool> {
+    cfr_supported_cached(&CFR_DEVICES, infd, outfd)
+}
+
+/// Copy bytes between the current cursors of the descriptors, using
+/// copy_file_range where the kernel supports it and falling back to
+/// a userspace copy otherwise.
+#[allow(dead_code)]
+pub fn copy_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
+    copy_bytes_with(copy_file_bytes, &CFR_UNAVAILABLE, infd, outfd, bytes)
+}
+
+fn copy_chunks(infd: &File, outfd: &File, total: u64, chunk: u64,
+               cancel: Option<&AtomicBool>, progress: &mut dyn FnMut(u64)) -> Result<u64> {
+    if chunk == 0 {
+        return Err(io_err(IOKind::InvalidInput, "Chunk size must be non-zero."));
+    }
+    let mut written = 0u64;
+    while written < total {
+        if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+            return Err(XcpError::Cancelled { copied: written }.into());
+        }
+        let n = copy_bytes(infd, outfd, cmp::min(total - written, chunk))?;
+        if n == 0 {
+            break;
+        }
+        written += n;
+        progress(written);
+    }
+    Ok(written)
+}
+
+/// Copy `total` bytes between the current cursors of the descriptors
+/// with `copy_bytes()`, in steps of at most `chunk` bytes. A single
+/// large copy_file_range can't be interrupted, so this keeps each
+/// call short. The copy stops early at the end of the source, and
+/// the number of bytes copied is returned.
+#[allow(dead_code)]
+pub fn copy_file_chunked(infd: &File, outfd: &File, total: u64, chunk: u64) -> Result<u64> {
+    copy_chunks(infd, outfd, total, chunk, None, &mut |_| ())
+}
+
+/// Version of `copy_file_chunked()` that checks `cancel` before each
+/// chunk, and if it is set stops with `XcpError::Cancelled`. The
+/// partial destination is left for the caller to clean up.
+#[allow(dead_code)]
+pub fn copy_file_chunked_cancellable(infd: &File, outfd: &File, total: u64, chunk: u64,
+                                     cancel: &AtomicBool) -> Result<u64> {
+    copy_chunks(infd, outfd, total, chunk, Some(cancel), &mut |_| ())
+}
+
+/// The chunk size used by `copy_file_bytes_with_progress()`; small
+/// enough for the callback to be called several times a second on
+/// slow storage, while keeping the syscall overhead negligible.
+pub const PROGRESS_CHUNK: u64 = 1024 * 1024;
+
+/// Version of `copy_file_chunked()` that copies in chunks of
+/// `PROGRESS_CHUNK` bytes, calling `progress` with the cumulative
+/// number of bytes copied after each one. The copy stops early at the
+/// end of the source. If a chunk fails the error is returned without
+/// calling `progress`, so the last value it was given is the amount
+/// successfully copied.
+#[allow(dead_code)]
+pub fn copy_file_bytes_with_progress(infd: &File, outfd: &File, bytes: u64,
+                                     progress: &mut dyn FnMut(u64)) -> Result<u64> {
+    copy_chunks(infd, outfd, bytes, PROGRESS_CHUNK, None, progress)
+}
+
+/// The current cursor position of the descriptor.
+pub fn cursor(fd: &File) -> Result<u64> {
+    match lseek(fd, 0, Wence::Cur)? {
+        SeekOff::Offset(off) => Ok(off),
+        SeekOff::NoData | SeekOff::EOF => {
+            Err(io_err(IOKind::UnexpectedEof, "Failed to find file cursor."))
+        }
+    }
 }
 
 pub fn fstat(fd: &File) -> Result<libc::stat> {
-    let mut stat: libc::stat = unsafe { mem::uninitialized() };
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
     let r = unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) };
 
     result_or_errno(r as i64, stat)
 }
 
-pub fn allocate_file(fd: &File, len: u64) -> Result<()> {
+fn fstatfs(fd: &File) -> Result<libc::statfs> {
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    let r = unsafe { libc::fstatfs(fd.as_raw_fd(), &mut stat) };
+
+    result_or_errno(r as i64, stat)
+}
+
+/// The filesystems whose behaviour affects how files are best copied,
+/// e.g. whether they support reflinks. See `fs_type()`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsType {
+    Btrfs,
+    Xfs,
+    /// Also ext2 and ext3, which share its magic number.
+    Ext4,
+    Zfs,
+    Tmpfs,
+    Nfs,
+    Overlay,
+    Apfs,
+    Hfs,
+    Ufs,
+    /// Any other filesystem, by the `f_type` reported by statfs(2).
+    Other(i64),
+}
+
+/// Map the filesystem type name BSD-derived systems report in
+/// `f_fstypename`.
+#[cfg(not(target_os = "linux"))]
+fn fs_type_from_name(name: &[u8], f_type: u32) -> FsType {
+    match name {
+        b"apfs" => FsType::Apfs,
+        b"hfs" => FsType::Hfs,
+        b"ufs" => FsType::Ufs,
+        b"zfs" => FsType::Zfs,
+        b"tmpfs" => FsType::Tmpfs,
+        b"nfs" => FsType::Nfs,
+        _ => FsType::Other(i64::from(f_type)),
+    }
+}
+
+/// Whether the descriptor refers to a regular file, as opposed to
+/// e.g. a pipe, socket or terminal, on which offset-based calls
+/// (lseek, copy_file_range, FIEMAP) can't be used.
+pub fn is_regular(fd: &File) -> Result<bool> {
+    Ok(fstat(fd)?.st_mode & libc::S_IFMT == libc::S_IFREG)
+}
+
+/// Read the target of a symlink with readlink(2). The target need
+/// not exist.
+pub fn readlink(path: &Path) -> Result<PathBuf> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let r = unsafe {
+        libc::readlink(cpath.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    let len = result_or_errno(r as i64, r as usize)?;
+    buf.truncate(len);
+    Ok(PathBuf::from(OsString::from_vec(buf)))
+}
+
+/// Create a symlink at `linkpath` pointing to `target` with
+/// symlinkat(2), relative to the current directory.
+pub fn symlinkat(target: &Path, linkpath: &Path) -> Result<()> {
+    let ctarget = CString::new(target.as_os_str().as_bytes())?;
+    let clink = CString::new(linkpath.as_os_str().as_bytes())?;
+    let r = unsafe { libc::symlinkat(ctarget.as_ptr(), libc::AT_FDCWD, clink.as_ptr()) };
+    result_or_errno(r as i64, ())
+}
+
+/// Whether the stat is of a block or character device.
+pub fn is_device(st: &libc::stat) -> bool {
+    let fmt = st.st_mode & libc::S_IFMT;
+    fmt == libc::S_IFBLK || fmt == libc::S_IFCHR
+}
+
+/// Whether the stat is of a named pipe.
+pub fn is_fifo(st: &libc::stat) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFIFO
+}
+
+/// Create a named pipe with mkfifo(3).
+#[allow(clippy::unnecessary_cast)]
+pub fn mkfifo(path: &Path, mode: u32) -> Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let r = unsafe { libc::mkfifo(cpath.as_ptr(), mode as libc::mode_t) };
+    result_or_errno(r as i64, ())
+}
+
+/// Create a filesystem node with mknod(2); used to recreate device
+/// nodes. `mode` includes the file type.
+#[allow(clippy::unnecessary_cast)]
+pub fn mknod(path: &Path, mode: u32, rdev: u64) -> Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    // mode_t and dev_t are narrower on some platforms.
+    let r = unsafe { libc::mknod(cpath.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) };
+    result_or_errno(r as i64, ())
+}
+
+/// Change the owner and/or group of a path without opening it; see
+/// `fchown()`.
+pub fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let uid = uid.unwrap_or(libc::uid_t::MAX);
+    let gid = gid.unwrap_or(libc::gid_t::MAX);
+    let r = unsafe { libc::chown(cpath.as_ptr(), uid, gid) };
+    result_or_errno(r as i64, ())
+}
+
+/// Create a hardlink at `newpath` to `oldpath` with linkat(2),
+/// relative to the current directory.
+pub fn linkat(oldpath: &Path, newpath: &Path) -> Result<()> {
+    let cold = CString::new(oldpath.as_os_str().as_bytes())?;
+    let cnew = CString::new(newpath.as_os_str().as_bytes())?;
     let r = unsafe {
-        libc::ftruncate(fd.as_raw_fd(), len as i64)
+        libc::linkat(libc::AT_FDCWD, cold.as_ptr(), libc::AT_FDCWD, cnew.as_ptr(), 0)
     };
     result_or_errno(r as i64, ())
 }
 
+/// Change the owner and/or group of an open file; `None` leaves that
+/// ID unchanged.
+pub fn fchown(fd: &File, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    // -1 means "don't change" to fchown(2).
+    let uid = uid.unwrap_or(libc::uid_t::MAX);
+    let gid = gid.unwrap_or(libc::gid_t::MAX);
+    let r = unsafe { libc::fchown(fd.as_raw_fd(), uid, gid) };
+    result_or_errno(r as i64, ())
+}
+
+/// Look up a user ID by name.
+pub fn user_id(name: &str) -> Option<u32> {
+    let cname = CString::new(name).ok()?;
+    let pw = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        None
+    } else {
+        Some(unsafe { (*pw).pw_uid })
+    }
+}
+
+/// Look up a group ID by name.
+pub fn group_id(name: &str) -> Option<u32> {
+    let cname = CString::new(name).ok()?;
+    let gr = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if gr.is_null() {
+        None
+    } else {
+        Some(unsafe { (*gr).gr_gid })
+    }
+}
+
+/// Call an xattr function that fills a buffer, growing the buffer
+/// until the result fits. `call` is passed the buffer and its size,
+/// which is 0 to query the required size.
+#[cfg_attr(target_os = "freebsd", allow(dead_code))]
+fn xattr_buffer<F>(call: F) -> Result<Vec<u8>>
+    where F: Fn(*mut libc::c_void, usize) -> libc::ssize_t
+{
+    loop {
+        let size = call(null_mut(), 0);
+        let size = result_or_errno(size as i64, size as usize)?;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut buf = vec![0u8; size];
+        let r = call(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        match result_or_errno(r as i64, r as usize) {
+            Ok(len) => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            // Grew between calls; try again.
+            Err(ref e) if errno(e) == Some(libc::ERANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copy the extended attributes accepted by `filter` from one file
+/// to another. A source filesystem without xattr support is treated
+/// as having none.
+pub fn copy_xattrs<F>(infd: &File, outfd: &File, filter: F) -> Result<()>
+    where F: Fn(&str) -> bool
+{
+    let names = match list_xattrs(infd) {
+        Err(ref e) if errno(e) == Some(libc::ENOTSUP) => return Ok(()),
+        r => r?,
+    };
+
+    for name in names {
+        let name_str = name.to_string_lossy();
+        if !filter(&name_str) {
+            debug!("Skipping xattr {}", name_str);
+            continue;
+        }
+        debug!("Copying xattr {}", name_str);
+        set_xattr(outfd, &name, &get_xattr(infd, &name)?)?;
+    }
+    Ok(())
+}
+
+/// The xattrs Linux stores POSIX ACLs in; the default ACL, inherited
+/// by new files, only exists on directories.
+const ACL_ACCESS: &[u8] = b"system.posix_acl_access\0";
+const ACL_DEFAULT: &[u8] = b"system.posix_acl_default\0";
+
+/// Copy the source's POSIX access ACL, and for a directory its default
+/// ACL, to the destination. A source without ACLs, or on a filesystem
+/// without xattr support, has none to copy. If the destination
+/// doesn't support ACLs this fails with `XcpError::AclsUnsupported`.
+pub fn copy_acls(infd: &File, outfd: &File) -> Result<()> {
+    let mut names = vec![ACL_ACCESS];
+    if infd.metadata()?.is_dir() {
+        names.push(ACL_DEFAULT);
+    }
+
+    for name in names {
+        let name = CStr::from_bytes_with_nul(name).unwrap();
+        let acl = match get_xattr(infd, name) {
+            Ok(acl) => acl,
+            Err(ref e) if errno(e) == Some(libc::ENOATTR) || errno(e) == Some(libc::ENOTSUP) => {
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("Copying ACL {:?}", name);
+        if let Err(e) = set_xattr(outfd, name, &acl) {
+            return Err(match errno(&e) {
+                Some(libc::ENOTSUP) => XcpError::AclsUnsupported.into(),
+                _ => e,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Files smaller than this may be copied with `copy_small_mmap()`;
+/// for larger files the mapping overhead outweighs the syscalls saved.
+pub const MMAP_THRESHOLD: u64 = 64 * 1024;
+
+/// Files of fewer than this many of their filesystem's preferred IO
+/// blocks (`st_blksize`) are copied with a single userspace read and
+/// write rather than copy_file_range, whose setup cost dominates for
+/// them.
+pub const SMALL_FILE_BLOCKS: u64 = 1;
+
+/// Whether copy_file_range isn't worth using for a file of this size;
+/// see `SMALL_FILE_BLOCKS`.
+pub fn is_small_file(meta: &Metadata) -> bool {
+    meta.len() < SMALL_FILE_BLOCKS * meta.blksize()
+}
+
+/// Copy the first `len` bytes of `infd` to `outfd`, which is resized
+/// to `len`, by mapping both and copying between the mappings. The
+/// destination's blocks are allocated first, as running out of space
+/// while writing to a mapping raises SIGBUS rather than an error. The
+/// source mustn't be truncated during the copy for the same reason,
+/// so this is only suitable for small files. The file cursors aren't
+/// moved, and the destination is left alone if there's nothing to copy
+/// or the source is too short.
+pub fn copy_small_mmap(infd: &File, outfd: &File, len: u64) -> Result<u64> {
+    if len == 0 {
+        return Ok(0);
+    }
+    if infd.metadata()?.len() < len {
+        return Err(io_err(IOKind::UnexpectedEof, "Source is shorter than the requested length."));
+    }
+    allocate_file(outfd, len)?;
+    preallocate(outfd, len)?;
+    let size = usize::try_from(len)
+        .map_err(|_| io_err(IOKind::InvalidInput, "Length exceeds the address space."))?;
+
+    unsafe {
+        let src = libc::mmap(null_mut(), size, libc::PROT_READ, libc::MAP_PRIVATE,
+                             infd.as_raw_fd(), 0);
+        if src == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let dst = libc::mmap(null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+                             libc::MAP_SHARED, outfd.as_raw_fd(), 0);
+        if dst == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            libc::munmap(src, size);
+            return Err(err.into());
+        }
+
+        std::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, size);
+
+        // Start writeback before the mapping goes; waiting for it is
+        // left to the fsync options.
+        let r = libc::msync(dst, size, libc::MS_ASYNC);
+        let err = io::Error::last_os_error();
+        libc::munmap(dst, size);
+        libc::munmap(src, size);
+        if r != 0 {
+            return Err(err.into());
+        }
+    }
+    Ok(len)
+}
+
+/// Convert an unsigned offset/length to `off64_t`, failing rather
+/// than wrapping for values >= 2^63.
+fn to_off64(val: u64) -> Result<off64_t> {
+    off64_t::try_from(val)
+        .map_err(|_| io_err(IOKind::InvalidInput, "Offset exceeds the range of off64_t."))
+}
+
+/// Convert a length to the `size_t` syscalls take; on 32-bit targets
+/// large lengths would otherwise be silently truncated.
+#[cfg(not(target_os = "macos"))]
+fn to_size(val: u64) -> Result<usize> {
+    usize::try_from(val)
+        .map_err(|_| io_err(IOKind::InvalidInput, "Length exceeds the range of size_t."))
+}
+
+/// Check that `bytes` from offset `off` are all valid file offsets,
+/// so a copy of the range can't wrap around partway through.
+#[cfg(target_os = "linux")]
+fn check_range(off: i64, bytes: u64) -> Result<()> {
+    let end = i64::try_from(bytes).ok()
+        .filter(|_| off >= 0)
+        .and_then(|bytes| off.checked_add(bytes));
+    match end {
+        Some(_) => Ok(()),
+        None => Err(io_err(IOKind::InvalidInput, "Range exceeds the range of off64_t.")),
+    }
+}
+
+/// Set the file length without allocating blocks. Uses ftruncate64
+/// so lengths over 2GB work on 32-bit targets.
+pub fn allocate_file(fd: &File, len: u64) -> Result<()> {
+    let len = to_off64(len)?;
+    let r = unsafe {
+        ftruncate64(fd.as_raw_fd(), len)
+    };
+    result_or_errno(r as i64, ())
+}
 
 /// Corresponds to lseek(2) `wence`
 #[allow(dead_code)]
@@ -134,19 +835,29 @@ pub enum Wence {
     Set = libc::SEEK_SET as isize,
     Cur = libc::SEEK_CUR as isize,
     End = libc::SEEK_END as isize,
-    Data = libc::SEEK_DATA as isize,
-    Hole = libc::SEEK_HOLE as isize,
+    Data = SEEK_DATA as isize,
+    Hole = SEEK_HOLE as isize,
 }
 
+/// The result of `lseek()`. Both `NoData` and `EOF` come from ENXIO,
+/// but mean different things: `SEEK_DATA` fails with it when there's
+/// no data between the offset and the end of the file, which may be
+/// before EOF if the file ends with a hole.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug)]
 pub enum SeekOff {
     Offset(u64),
+    /// No data after the offset (from `Wence::Data`); the rest of the
+    /// file is a hole.
+    NoData,
+    /// The offset is at or past the end of the file.
     EOF
 }
 
 pub fn lseek(fd: &File, off: i64, wence: Wence) -> Result<SeekOff> {
+    let seek_data = matches!(wence, Wence::Data);
     let r = unsafe {
-        libc::lseek64(
+        lseek64(
             fd.as_raw_fd(),
             off,
             wence as libc::c_int
@@ -156,6 +867,9 @@ pub fn lseek(fd: &File, off: i64, wence: Wence) -> Result<SeekOff> {
     if r == -1 {
         let err = io::Error::last_os_error();
         match err.raw_os_error() {
+            Some(errno) if errno == libc::ENXIO && seek_data => {
+                Ok(SeekOff::NoData)
+            }
             Some(errno) if errno == libc::ENXIO => {
                 Ok(SeekOff::EOF)
             }
@@ -168,24 +882,255 @@ pub fn lseek(fd: &File, off: i64, wence: Wence) -> Result<SeekOff> {
 
 }
 
+// Extent flags; see linux/fiemap.h.
+const FIEMAP_EXTENT_LAST: u32 = 0x0000_0001;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x0000_0800;
+const FIEMAP_EXTENT_SHARED: u32 = 0x0000_2000;
+
+/// A single extent as reported by the FS_IOC_FIEMAP ioctl. Only
+/// ranges backed by storage are reported; holes are the gaps between
+/// them.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+    pub flags: u32,
+}
+
+impl Extent {
+    pub fn end(&self) -> u64 {
+        self.logical + self.length
+    }
+
+    /// Allocated but never written, i.e. reads as zeros
+    /// (e.g. created by fallocate(2)).
+    pub fn is_unwritten(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_UNWRITTEN != 0
+    }
+
+    /// Shared with another file, e.g. by a reflink.
+    #[allow(dead_code)]
+    pub fn is_shared(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_SHARED != 0
+    }
+
+    #[allow(dead_code)]
+    pub fn is_last(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_LAST != 0
+    }
+}
+
+/// Convert a timestamp from the kernel to a `SystemTime`.
+fn system_time(sec: i64, nsec: u32) -> SystemTime {
+    if sec >= 0 {
+        UNIX_EPOCH + Duration::new(sec as u64, nsec)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(sec.unsigned_abs()) + Duration::new(0, nsec)
+    }
+}
+
+/// Set the access and modification times of the file with
+/// futimens(2).
+pub fn set_times(fd: &File, atime: libc::timespec, mtime: libc::timespec) -> Result<()> {
+    let times = [atime, mtime];
+    let r = unsafe { futimens(fd.as_raw_fd(), times.as_ptr()) };
+    result_or_errno(r as i64, ())
+}
+
+/// Set the access and modification times of a symlink itself, rather
+/// than its target, to `time` in seconds since the epoch.
+pub fn set_link_times(path: &Path, time: i64) -> Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let tv = libc::timeval { tv_sec: time as libc::time_t, tv_usec: 0 };
+    let times = [tv, tv];
+    let r = unsafe { lutimes(cpath.as_ptr(), times.as_ptr()) };
+    result_or_errno(r as i64, ())
+}
+
+/// The ratio of the file's allocated size to its length. Well below
+/// 1.0 means the file has holes; above 1.0 means it has blocks
+/// preallocated past its data. Empty files are treated as dense, and
+/// return 1.0.
+pub fn sparseness(fd: &File) -> Result<f64> {
+    Ok(meta_sparseness(&fd.metadata()?))
+}
+
+/// As `sparseness()`, from already-fetched metadata.
+pub fn meta_sparseness(meta: &Metadata) -> f64 {
+    match meta.len() {
+        0 => 1.0,
+        // st_blocks is always in 512-byte units, whatever st_blksize is.
+        len => (meta.blocks() * 512) as f64 / len as f64,
+    }
+}
 
 // Guestimate if file is sparse; if it has less blocks that would be
 // expected for its stated size. This is the same test used by
 // coreutils `cp`.
 pub fn probably_sparse(fd: &File) -> Result<bool> {
-    let st = fstat(fd)?;
-    Ok(st.st_blocks < st.st_size / st.st_blksize)
+    Ok(sparseness(fd)? < 1.0)
 }
 
+/// As `probably_sparse()`, from already-fetched metadata.
+pub fn meta_probably_sparse(meta: &Metadata) -> bool {
+    meta_sparseness(meta) < 1.0
+}
+
+/// Whether the file has a hole before EOF, according to SEEK_HOLE.
+/// Unlike `probably_sparse()` this isn't fooled by compressed or
+/// inlined files, or by blocks preallocated past the data, but costs
+/// a syscall. Filesystems without SEEK_HOLE support report no holes,
+/// and some report unwritten preallocated ranges as holes.
+#[allow(dead_code)]
+pub fn is_definitely_sparse(fd: &File) -> Result<bool> {
+    let len = fd.metadata()?.len();
+    match lseek(fd, 0, Wence::Hole)? {
+        SeekOff::Offset(hole) => Ok(hole < len),
+        SeekOff::NoData | SeekOff::EOF => Ok(false),
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use tempfile::tempdir;
-    use std::path::{PathBuf};
     use std::fs::{read, OpenOptions};
-    use std::process::Command;
     use std::io::{Seek, SeekFrom, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Reader that sleeps before each read, simulating a slow disk.
+    struct SlowReader<'a>(io::Cursor<&'a [u8]>);
+
+    impl Read for SlowReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(10));
+            self.0.read(buf)
+        }
+    }
+
+    /// Writer that sleeps before each write, simulating a slower disk.
+    struct SlowWriter(Vec<u8>);
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(20));
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy_stream_split_timing() -> Result<()> {
+        let data = vec![0x5a; 4 * USPACE_BUFFER];
+        let mut reader = SlowReader(io::Cursor::new(&data));
+        let mut writer = SlowWriter(Vec::new());
+        let mut stats = CopyStats::default();
+
+        let start = Instant::now();
+        let n = copy_stream(&mut reader, &mut writer, data.len() as u64, &mut stats)?;
+        let elapsed = start.elapsed();
+
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(writer.0, data);
+        assert_eq!(stats.read_bytes, n);
+        assert_eq!(stats.write_bytes, n);
+        // Sleeps are never short, and the two are timed separately, so
+        // these hold however loaded the machine is.
+        assert!(stats.read_time >= Duration::from_millis(40));
+        assert!(stats.write_time >= Duration::from_millis(80));
+        assert!(stats.read_time + stats.write_time <= elapsed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uspace_buffer_size() {
+        assert_eq!(uspace_buffer_size(4096), USPACE_BUFFER);
+        assert_eq!(uspace_buffer_size(0), USPACE_BUFFER);
+        assert_eq!(uspace_buffer_size(1000 * 1000), 2 * 1000 * 1000);
+        assert_eq!(uspace_buffer_size(3 * 1024 * 1024), 3 * 1024 * 1024);
+        assert_eq!(uspace_buffer_size(1 << 40), MAX_USPACE_BUFFER);
+    }
+
+    #[test]
+    fn test_uspace_buffer_reused() -> Result<()> {
+        let dir = tempdir()?;
+        let buf_ptr = || USPACE_BUF.with(|cell| {
+            let buf = cell.take();
+            let ptr = buf.as_ptr();
+            cell.set(buf);
+            ptr
+        });
+
+        let mut first = None;
+        for (i, len) in [3 * USPACE_BUFFER + 5, 17, USPACE_BUFFER, 0, 4099].iter().enumerate() {
+            let from = dir.path().join(format!("from{}.bin", i));
+            let to = dir.path().join(format!("to{}.bin", i));
+            let data = (0..*len).map(|b| (b * 7 + i) as u8).collect::<Vec<u8>>();
+            std::fs::write(&from, &data)?;
+
+            let n = copy_bytes_uspace(&File::open(&from)?, &File::create(&to)?, *len as u64,
+                                      &mut CopyStats::default())?;
+            assert_eq!(n, *len as u64);
+            assert_eq!(read(&to)?, data);
+
+            // The buffer is allocated by the first copy, and the rest
+            // use it.
+            let ptr = buf_ptr();
+            assert_eq!(*first.get_or_insert(ptr), ptr);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_enosys_fallback() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        let data = "test data";
+        {
+            let mut fd = File::create(&from)?;
+            write!(fd, "{}", data)?;
+        }
+
+        let calls = std::cell::Cell::new(0);
+        let enosys = |_: &File, _: &File, _: u64| -> Result<u64> {
+            calls.set(calls.get() + 1);
+            Err(io::Error::from_raw_os_error(libc::ENOSYS).into())
+        };
+        let unavailable = AtomicBool::new(false);
+
+        for _ in 0..2 {
+            let infd = File::open(&from)?;
+            let outfd = File::create(&to)?;
+            let n = copy_bytes_with(enosys, &unavailable, &infd, &outfd, data.len() as u64)?;
+            assert_eq!(n, data.len() as u64);
+            assert_eq!(read(&to)?, data.as_bytes());
+        }
+
+        assert!(unavailable.load(Ordering::Relaxed));
+        assert_eq!(calls.get(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let link = dir.path().join("link");
+
+        // Broken links are fine.
+        symlinkat(Path::new("no/such/file"), &link)?;
+        assert_eq!(readlink(&link)?, PathBuf::from("no/such/file"));
+        assert!(symlinkat(Path::new("other"), &link).is_err());
+
+        Ok(())
+    }
 
     #[test]
     fn test_stat() -> Result<()> {
@@ -203,11 +1148,7 @@ mod tests {
 
         let dir = tempdir()?;
         let file = dir.path().join("sparse.bin");
-        let out = Command::new("/usr/bin/truncate")
-            .args(&["-s", "1M", file.to_str().unwrap()])
-            .output()
-            ?;
-        assert!(out.status.success());
+        allocate_file(&File::create(&file)?, 1024 * 1024)?;
 
         {
             let fd = File::open(&file)?;
@@ -218,7 +1159,7 @@ mod tests {
                 .write(true)
                 .append(false)
                 .open(&file)?;
-            write!(fd, "{}", "test")?;
+            write!(fd, "test")?;
             assert!(probably_sparse(&fd)?);
         }
 
@@ -226,111 +1167,100 @@ mod tests {
     }
 
     #[test]
-    fn test_copy_range_sparse() -> Result<()> {
+    fn test_fs_type() -> Result<()> {
         let dir = tempdir()?;
-        let file = dir.path().join("sparse.bin");
-        let from = dir.path().join("from.txt");
-        let data = "test data";
+        let file = dir.path().join("file.txt");
+        let fs = fs_type(&File::create(&file)?)?;
+        match fs {
+            FsType::Btrfs | FsType::Xfs | FsType::Ext4 | FsType::Zfs | FsType::Tmpfs
+                | FsType::Nfs | FsType::Overlay | FsType::Apfs | FsType::Hfs | FsType::Ufs => {}
+            FsType::Other(f_type) => assert_ne!(f_type, 0),
+        }
+        // Files on the same filesystem agree.
+        assert_eq!(fs_type(&File::open(dir.path())?)?, fs);
 
+        #[cfg(target_os = "linux")]
         {
-            let mut fd = File::create(&from)?;
-            write!(fd, "{}", data)?;
+            if let Ok(shm) = File::open("/dev/shm") {
+                assert_eq!(fs_type(&shm)?, FsType::Tmpfs);
+            }
         }
 
-        let out = Command::new("/usr/bin/truncate")
-            .args(&["-s", "1M", file.to_str().unwrap()])
-            .output()
-            ?;
-        assert!(out.status.success());
+        Ok(())
+    }
 
-        {
-            let infd = File::open(&from)?;
-            let outfd: File = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .open(&file)?;
-            copy_file_bytes(&infd, &outfd, data.len() as u64)?;
+    #[test]
+    fn test_birth_time() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file.txt");
+        let before = SystemTime::now() - Duration::from_secs(5);
+        let fd = File::create(&file)?;
+
+        // Not all filesystems record it.
+        if let Some(btime) = birth_time(&fd)? {
+            assert!(btime >= before);
+            assert!(btime <= SystemTime::now());
         }
 
-        assert!(probably_sparse(&File::open(file)?)?);
-
         Ok(())
     }
 
     #[test]
-    fn test_sparse_copy_middle() -> Result<()> {
+    fn test_system_time() {
+        assert_eq!(system_time(0, 0), UNIX_EPOCH);
+        assert_eq!(system_time(1, 500), UNIX_EPOCH + Duration::new(1, 500));
+        assert_eq!(system_time(-1, 500), UNIX_EPOCH - Duration::new(0, 999_999_500));
+    }
+
+    #[test]
+    fn test_sparseness() -> Result<()> {
         let dir = tempdir()?;
-        let file = dir.path().join("sparse.bin");
-        let from = dir.path().join("from.txt");
-        let data = "test data";
 
-        {
-            let mut fd = File::create(&from)?;
-            write!(fd, "{}", data)?;
-        }
+        let empty = dir.path().join("empty.bin");
+        File::create(&empty)?;
+        assert_eq!(sparseness(&File::open(&empty)?)?, 1.0);
 
-        let out = Command::new("/usr/bin/truncate")
-            .args(&["-s", "1M", file.to_str().unwrap()])
-            .output()?;
-        assert!(out.status.success());
+        let dense = dir.path().join("dense.bin");
+        std::fs::write(&dense, vec![0x5a; 1024 * 1024])?;
+        let ratio = sparseness(&File::open(&dense)?)?;
+        assert!((1.0..1.1).contains(&ratio), "dense ratio {}", ratio);
 
-        let offset: usize = 512*1024;
+        let sparse = dir.path().join("sparse.bin");
         {
-            let infd = File::open(&from)?;
-            let outfd: File = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .open(&file)?;
-            copy_file_range(&infd, 0,
-                            &outfd, offset as i64,
-                            data.len() as u64)?;
+            let fd = File::create(&sparse)?;
+            (&fd).write_all(&[0x5a; 4096])?;
+            fd.set_len(64 * 1024 * 1024)?;
         }
-
-        assert!(probably_sparse(&File::open(&file)?)?);
-
-        let bytes = read(&file)?;
-        assert!(bytes.len() == 1024*1024);
-        assert!(bytes[offset] == b't');
-        assert!(bytes[offset+1] == b'e');
-        assert!(bytes[offset+2] == b's');
-        assert!(bytes[offset+3] == b't');
-        assert!(bytes[offset+data.len()] == 0);
+        let ratio = sparseness(&File::open(&sparse)?)?;
+        assert!(ratio < 0.01, "sparse ratio {}", ratio);
+        assert!(probably_sparse(&File::open(&sparse)?)?);
 
         Ok(())
     }
 
     #[test]
-    fn test_lseek_data() -> Result<()> {
+    fn test_is_definitely_sparse() -> Result<()> {
         let dir = tempdir()?;
-        let file = dir.path().join("sparse.bin");
-        let from = dir.path().join("from.txt");
-        let data = "test data";
-        let offset = 512*1024;
 
-        {
-            let mut fd = File::create(&from)?;
-            write!(fd, "{}", data)?;
-        }
+        let empty = dir.path().join("empty.bin");
+        File::create(&empty)?;
+        assert!(!is_definitely_sparse(&File::open(&empty)?)?);
+
+        let dense = dir.path().join("dense.bin");
+        std::fs::write(&dense, vec![0x5a; 1024 * 1024])?;
+        let fd = File::open(&dense)?;
+        assert!(!is_definitely_sparse(&fd)?);
+        assert_eq!(is_definitely_sparse(&fd)?, probably_sparse(&fd)?);
 
-        let out = Command::new("/usr/bin/truncate")
-            .args(&["-s", "1M", file.to_str().unwrap()])
-            .output()?;
-        assert!(out.status.success());
+        let sparse = dir.path().join("sparse.bin");
         {
-            let infd = File::open(&from)?;
-            let outfd: File = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .open(&file)?;
-            copy_file_range(&infd, 0,
-                            &outfd, offset as i64,
-                            data.len() as u64)?;
+            let fd = File::create(&sparse)?;
+            (&fd).write_all(&[0x5a; 4096])?;
+            fd.set_len(64 * 1024 * 1024)?;
         }
-
-        assert!(probably_sparse(&File::open(&file)?)?);
-
-        let off = lseek(&File::open(&file)?, 0, Wence::Data)?;
-        assert_eq!(off, SeekOff::Offset(offset));
+        let fd = File::open(&sparse)?;
+        assert!(is_definitely_sparse(&fd)?);
+        assert_eq!(is_definitely_sparse(&fd)?, probably_sparse(&fd)?);
 
         Ok(())
     }
@@ -375,15 +1305,13 @@ mod tests {
         let dir = tempdir()?;
         let file = dir.path().join("sparse.bin");
 
-        let out = Command::new("/usr/bin/truncate")
-            .args(&["-s", "1M", file.to_str().unwrap()])
-            .output()?;
-        assert!(out.status.success());
+        allocate_file(&File::create(&file)?, 1024 * 1024)?;
         assert!(probably_sparse(&File::open(&file)?)?);
 
         let fd = File::open(&file)?;
         let off = lseek(&fd, 0, Wence::Data)?;
-        assert!(off == SeekOff::EOF);
+        assert!(off == SeekOff::NoData);
+        assert_eq!(lseek(&fd, 1024 * 1024, Wence::Hole)?, SeekOff::EOF);
 
         Ok(())
     }
@@ -404,4 +1332,246 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_allocate_file_large() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+        let fd = File::create(&file)?;
+
+        // Only run where we know truncation won't allocate blocks.
+        allocate_file(&fd, 1024 * 1024)?;
+        if !probably_sparse(&fd)? {
+            eprintln!("Skipping test_allocate_file_large; truncation allocates blocks here");
+            return Ok(());
+        }
+
+        let len = 5 * 1024 * 1024 * 1024;
+        allocate_file(&fd, len)?;
+        assert_eq!(len, file.metadata()?.len());
+        assert_eq!(len as i64, fstat(&fd)?.st_size);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate_file_overflow() -> Result<()> {
+        let dir = tempdir()?;
+        let fd = File::create(dir.path().join("file.bin"))?;
+
+        assert!(allocate_file(&fd, 1 << 63).is_err());
+        assert_eq!(0, fd.metadata()?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_syncfs() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file.txt");
+        File::create(&file)?.write_all(b"test data")?;
+
+        syncfs(&File::open(&file)?)?;
+        syncfs(&File::open(dir.path())?)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_with_progress() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let len = 3 * PROGRESS_CHUNK + 4096;
+        File::create(&from)?.write_all(&vec![0x5a; len as usize])?;
+
+        let mut progress = Vec::new();
+        let n = copy_file_bytes_with_progress(&File::open(&from)?, &File::create(&to)?,
+                                              len, &mut |copied| progress.push(copied))?;
+
+        assert_eq!(n, len);
+        assert_eq!(progress, vec![PROGRESS_CHUNK, 2 * PROGRESS_CHUNK, 3 * PROGRESS_CHUNK, len]);
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_with_progress_error() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        File::create(&from)?.write_all(b"test data")?;
+        File::create(&to)?;
+
+        let mut progress = Vec::new();
+        let r = copy_file_bytes_with_progress(&File::open(&from)?, &File::open(&to)?,
+                                              1024, &mut |copied| progress.push(copied));
+
+        assert!(r.is_err());
+        assert!(progress.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_chunked() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..100_003u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        File::create(&from)?.write_all(&data)?;
+
+        let n = copy_file_chunked(&File::open(&from)?, &File::create(&to)?,
+                                  data.len() as u64, 4096)?;
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(read(&to)?, data);
+
+        // Asking for more than the source has stops at EOF.
+        let n = copy_file_chunked(&File::open(&from)?, &File::create(&to)?,
+                                  data.len() as u64 * 2, 7000)?;
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(read(&to)?, data);
+
+        assert!(copy_file_chunked(&File::open(&from)?, &File::create(&to)?, 1, 0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_small_mmap() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..4096).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        std::fs::write(&from, &data)?;
+        std::fs::write(&to, vec![0xff; 8192])?;
+
+        let outfd = OpenOptions::new().read(true).write(true).open(&to)?;
+        assert_eq!(copy_small_mmap(&File::open(&from)?, &outfd, 4096)?, 4096);
+        drop(outfd);
+        assert_eq!(read(&to)?, data);
+
+        let empty = dir.path().join("empty");
+        File::create(&empty)?;
+        let outfd = OpenOptions::new().read(true).write(true).open(&to)?;
+        assert_eq!(copy_small_mmap(&File::open(&empty)?, &outfd, 0)?, 0);
+        assert_eq!(outfd.metadata()?.len(), 4096);
+
+        // A short source is caught before the destination is resized.
+        let err = copy_small_mmap(&File::open(&empty)?, &outfd, 8192).unwrap_err();
+        assert!(matches!(err.downcast_ref::<io::Error>(),
+                         Some(e) if e.kind() == IOKind::UnexpectedEof));
+        assert_eq!(read(&to)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_zero() {
+        let mut buf = vec![0u8; 4099];
+        assert!(is_zero(&buf));
+        assert!(is_zero(&buf[1..]));
+        assert!(is_zero(&[]));
+        for i in &[0, 1, 2048, 4098] {
+            buf[*i] = 1;
+            assert!(!is_zero(&buf));
+            assert!(!is_zero(&buf[1..]) || *i == 0);
+            buf[*i] = 0;
+        }
+    }
+
+    #[test]
+    fn test_drop_cache() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file.bin");
+        let mut fd = File::create(&file)?;
+        fd.write_all(&vec![0x5a; 64 * 1024])?;
+        fd.sync_all()?;
+
+        drop_cache(&fd)?;
+        assert_eq!(read(&file)?, vec![0x5a; 64 * 1024]);
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn test_clamped_requests_fit_32bit() -> Result<()> {
+        let total = 3 * 1024 * 1024 * 1024;
+        let mut calls = Vec::new();
+        let n = copy_clamped(total, MAX_CFR_LEN, |len| {
+            calls.push(len);
+            Ok(len)
+        })?;
+        assert_eq!(n, total);
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|len| *len <= i32::MAX as u64));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_supports_cfr_same_fs() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        std::fs::write(&from, "test data")?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        assert!(supports_cfr(&infd, &outfd));
+        // The probe doesn't copy anything or move the cursors.
+        assert_eq!(cursor(&infd)?, 0);
+        assert_eq!(outfd.metadata()?.len(), 0);
+
+        let cache = Mutex::new(Vec::new());
+        assert!(cfr_supported_cached(&cache, &infd, &outfd)?);
+        assert!(cfr_supported_cached(&cache, &infd, &outfd)?);
+        assert_eq!(cache.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_cancelled() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let len = 64 * 4096;
+        File::create(&from)?.write_all(&vec![0x5a; len as usize])?;
+
+        let cancel = std::sync::Arc::new(AtomicBool::new(false));
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (set_tx, set_rx) = std::sync::mpsc::channel();
+        let canceller = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                started_rx.recv().unwrap();
+                cancel.store(true, Ordering::Relaxed);
+                set_tx.send(()).unwrap();
+            })
+        };
+
+        // Hand over to the cancelling thread after the second chunk.
+        let r = copy_chunks(&File::open(&from)?, &File::create(&to)?, len, 4096,
+                            Some(&cancel), &mut |copied| if copied == 2 * 4096 {
+                                started_tx.send(()).unwrap();
+                                set_rx.recv().unwrap();
+                            });
+        canceller.join().unwrap();
+
+        match r.unwrap_err().downcast::<XcpError>()? {
+            XcpError::Cancelled { copied } => assert_eq!(copied, 2 * 4096),
+            e => panic!("Expected Cancelled, got {}", e),
+        }
+        assert_eq!(to.metadata()?.len(), 2 * 4096);
+
+        // An unset flag doesn't interfere.
+        let n = copy_file_chunked_cancellable(&File::open(&from)?, &File::create(&to)?, len,
+                                              4096, &AtomicBool::new(false))?;
+        assert_eq!(n, len);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,206 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::ptr::null_mut;
+use std::time::SystemTime;
+
+use super::{
+    copy_clamped, fs_type_from_name, fstat, fstatfs, result_or_errno, system_time, to_off64,
+    to_size, Extent, FsType, MAX_CFR_LEN,
+};
+use crate::errors::Result;
+
+// off_t is always 64 bits on FreeBSD.
+pub(super) use libc::{ftruncate as ftruncate64, lseek as lseek64, lutimes, off_t as off64_t};
+pub(super) use libc::{SEEK_DATA, SEEK_HOLE};
+pub(super) use self::ffi::futimens;
+
+mod ffi {
+    // FreeBSD 13+ has a native copy_file_range(2) in libc; there's no
+    // separate syscall binding, so the `kernel_copy_file_range`
+    // feature doesn't apply here.
+    extern "C" {
+        pub fn copy_file_range(
+            fd_in: libc::c_int,
+            off_in: *mut libc::off_t,
+            fd_out: libc::c_int,
+            off_out: *mut libc::off_t,
+            len: libc::size_t,
+            flags: libc::c_uint,
+        ) -> libc::ssize_t;
+
+        pub fn futimens(fd: libc::c_int, times: *const libc::timespec) -> libc::c_int;
+    }
+}
+
+fn unsupported<T>() -> Result<T> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP).into())
+}
+
+/// Version of copy_file_range that defers offset-management to the
+/// syscall. see copy_file_range(2) for details.
+pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
+    copy_file_bytes_flags(infd, outfd, bytes, 0)
+}
+
+/// As `copy_file_bytes`, passing `flags` to copy_file_range(2).
+/// Unknown flags are rejected by the kernel with `EINVAL`.
+pub fn copy_file_bytes_flags(infd: &File, outfd: &File, bytes: u64,
+                             flags: u32) -> Result<u64> {
+    copy_clamped(bytes, MAX_CFR_LEN, |len| {
+        let r = unsafe {
+            ffi::copy_file_range(
+                infd.as_raw_fd(),
+                null_mut(),
+                outfd.as_raw_fd(),
+                null_mut(),
+                to_size(len)?,
+                flags,
+            ) as i64
+        };
+        result_or_errno(r, r as u64)
+    })
+}
+
+/// FreeBSD has no file cloning.
+pub fn reflink(_infd: &File, _outfd: &File) -> Result<()> {
+    unsupported()
+}
+
+// FreeBSD uses the namespaced extattr(2) API rather than xattrs;
+// these are not yet mapped onto it.
+
+pub fn list_xattrs(_fd: &File) -> Result<Vec<CString>> {
+    unsupported()
+}
+
+pub fn get_xattr(_fd: &File, _name: &CStr) -> Result<Vec<u8>> {
+    unsupported()
+}
+
+pub fn set_xattr(_fd: &File, _name: &CStr, _value: &[u8]) -> Result<()> {
+    unsupported()
+}
+
+/// There's no per-filesystem sync, so this syncs all filesystems
+/// with sync(2).
+pub fn syncfs(_fd: &File) -> Result<()> {
+    unsafe { libc::sync() };
+    Ok(())
+}
+
+/// The file's creation (birth) time. Filesystems that don't record it
+/// (e.g. UFS1) report -1.
+#[allow(dead_code)]
+pub fn birth_time(fd: &File) -> Result<Option<SystemTime>> {
+    let stat = fstat(fd)?;
+    if stat.st_birthtime == -1 {
+        return Ok(None);
+    }
+    Ok(Some(system_time(stat.st_birthtime as i64, stat.st_birthtime_nsec as u32)))
+}
+
+/// The type of filesystem the file is on, from the name fstatfs(2)
+/// reports.
+pub fn fs_type(fd: &File) -> Result<FsType> {
+    let st = fstatfs(fd)?;
+    let name = unsafe { CStr::from_ptr(st.f_fstypename.as_ptr()) };
+    Ok(fs_type_from_name(name.to_bytes(), st.f_type))
+}
+
+/// Per-file encryption (fscrypt) is Linux-only.
+pub fn is_encrypted(_fd: &File) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn missing_key(_err: &io::Error) -> bool {
+    false
+}
+
+/// There's no /proc/self/maps; only the executable itself is known.
+pub fn mapped_files() -> Result<Vec<PathBuf>> {
+    Ok(Vec::new())
+}
+
+/// FIEMAP is Linux-only.
+pub fn fiemap(_fd: &File) -> Result<Vec<Extent>> {
+    unsupported()
+}
+
+pub fn extent_count(_fd: &File) -> Result<u64> {
+    unsupported()
+}
+
+/// Only needed for the unwritten extents reported by FIEMAP.
+pub fn allocate_range(_fd: &File, _off: u64, _len: u64) -> Result<()> {
+    unsupported()
+}
+
+/// Copying inode flags is only implemented with Linux's
+/// FS_IOC_GETFLAGS ioctl.
+pub fn copy_inode_flags(_infd: &File, _outfd: &File) -> Result<()> {
+    unsupported()
+}
+
+/// Allocate blocks for the first `len` bytes of the file with
+/// posix_fallocate(2), extending it if necessary.
+pub fn preallocate(fd: &File, len: u64) -> Result<()> {
+    // Returns the error rather than setting errno.
+    match unsafe { libc::posix_fallocate(fd.as_raw_fd(), 0, to_off64(len)?) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err).into()),
+    }
+}
+
+/// Ask the kernel to drop the file's cached pages with
+/// posix_fadvise(2).
+pub fn drop_cache(fd: &File) -> Result<()> {
+    match unsafe { libc::posix_fadvise(fd.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err).into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::probably_sparse;
+    use std::fs::read;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_file_bytes() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        File::create(&from)?.write_all(b"test data")?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        assert_eq!(copy_file_bytes(&infd, &outfd, 1024)?, 9);
+        assert_eq!(read(&to)?, b"test data");
+        assert!(!probably_sparse(&infd)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,916 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::cmp;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::ptr::null_mut;
+use std::time::SystemTime;
+
+use super::{
+    check_range, copy_clamped, fstatfs, result_or_errno, system_time, to_off64, to_size,
+    xattr_buffer, Extent, FsType, MAX_CFR_LEN,
+};
+use crate::errors::{io_err, Result};
+
+// These have 64-bit variants on Linux so large files work on 32-bit
+// targets.
+pub(super) use libc::{ftruncate64, lseek64, off64_t};
+pub(super) use libc::{futimens, SEEK_DATA, SEEK_HOLE};
+pub(super) use self::ffi::lutimes;
+
+
+mod ffi {
+    // Returns ENOSYS on Linux kernel < 4.5; see copy_bytes().
+    #[cfg(feature = "kernel_copy_file_range")]
+    pub unsafe fn copy_file_range(
+        fd_in: libc::c_int,
+        off_in: *mut libc::loff_t,
+        fd_out: libc::c_int,
+        off_out: *mut libc::loff_t,
+        len: libc::size_t,
+        flags: libc::c_uint,
+    ) -> libc::ssize_t {
+        libc::syscall(
+            libc::SYS_copy_file_range,
+            fd_in,
+            off_in,
+            fd_out,
+            off_out,
+            len,
+            flags,
+        ) as libc::ssize_t
+    }
+
+    // Requires GlibC >= 2.27
+    #[cfg(not(feature = "kernel_copy_file_range"))]
+    extern "C" {
+        pub fn copy_file_range(
+            fd_in: libc::c_int,
+            off_in: libc::loff_t,
+            fd_out: libc::c_int,
+            off_out: libc::loff_t,
+            len: libc::size_t,
+            flags: libc::c_uint,
+        ) -> libc::ssize_t;
+    }
+
+    extern "C" {
+        // Requires GlibC >= 2.14
+        pub fn syncfs(fd: libc::c_int) -> libc::c_int;
+        pub fn lutimes(file: *const libc::c_char, times: *const libc::timeval) -> libc::c_int;
+        // Requires GlibC >= 2.28
+        pub fn statx(dirfd: libc::c_int, pathname: *const libc::c_char, flags: libc::c_int,
+                     mask: libc::c_uint, statxbuf: *mut statx) -> libc::c_int;
+    }
+
+    // See linux/fiemap.h and linux/fs.h; not exported by libc.
+    pub const FICLONE: libc::c_ulong = 0x4004_9409;
+    pub const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    pub const FIEMAP_FLAG_SYNC: u32 = 0x0000_0001;
+    pub const FIEMAP_MAX_OFFSET: u64 = !0;
+
+    // The ioctls are declared as taking a long, but the kernel only
+    // reads and writes an int.
+    #[cfg(target_pointer_width = "64")]
+    pub const FS_IOC_GETFLAGS: libc::c_ulong = 0x8008_6601;
+    #[cfg(target_pointer_width = "64")]
+    pub const FS_IOC_SETFLAGS: libc::c_ulong = 0x4008_6602;
+    #[cfg(target_pointer_width = "32")]
+    pub const FS_IOC_GETFLAGS: libc::c_ulong = 0x8004_6601;
+    #[cfg(target_pointer_width = "32")]
+    pub const FS_IOC_SETFLAGS: libc::c_ulong = 0x4004_6602;
+    pub const FS_SYNC_FL: libc::c_int = 0x0000_0008;
+    pub const FS_IMMUTABLE_FL: libc::c_int = 0x0000_0010;
+    pub const FS_APPEND_FL: libc::c_int = 0x0000_0020;
+    pub const FS_NODUMP_FL: libc::c_int = 0x0000_0040;
+    pub const FS_NOATIME_FL: libc::c_int = 0x0000_0080;
+
+    // See linux/magic.h; only some are exported by libc.
+    pub const BTRFS_SUPER_MAGIC: u32 = 0x9123_683E;
+    pub const XFS_SUPER_MAGIC: u32 = 0x5846_5342;
+    pub const EXT4_SUPER_MAGIC: u32 = 0x0000_EF53;
+    pub const ZFS_SUPER_MAGIC: u32 = 0x2FC1_2FC1;
+    pub const TMPFS_MAGIC: u32 = 0x0102_1994;
+    pub const NFS_SUPER_MAGIC: u32 = 0x0000_6969;
+    pub const OVERLAYFS_SUPER_MAGIC: u32 = 0x794C_7630;
+
+    // See linux/stat.h.
+    pub const STATX_BTIME: libc::c_uint = 0x0000_0800;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct statx_timestamp {
+        pub tv_sec: i64,
+        pub tv_nsec: u32,
+        pub __reserved: i32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct statx {
+        pub stx_mask: u32,
+        pub stx_blksize: u32,
+        pub stx_attributes: u64,
+        pub stx_nlink: u32,
+        pub stx_uid: u32,
+        pub stx_gid: u32,
+        pub stx_mode: u16,
+        pub __spare0: [u16; 1],
+        pub stx_ino: u64,
+        pub stx_size: u64,
+        pub stx_blocks: u64,
+        pub stx_attributes_mask: u64,
+        pub stx_atime: statx_timestamp,
+        pub stx_btime: statx_timestamp,
+        pub stx_ctime: statx_timestamp,
+        pub stx_mtime: statx_timestamp,
+        pub stx_rdev_major: u32,
+        pub stx_rdev_minor: u32,
+        pub stx_dev_major: u32,
+        pub stx_dev_minor: u32,
+        pub __spare2: [u64; 14],
+    }
+
+    // See linux/fscrypt.h.
+    pub const FS_IOC_GET_ENCRYPTION_POLICY: libc::c_ulong = 0x400C_6615;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct fscrypt_policy_v1 {
+        pub version: u8,
+        pub contents_encryption_mode: u8,
+        pub filenames_encryption_mode: u8,
+        pub flags: u8,
+        pub master_key_descriptor: [u8; 8],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct fiemap_extent {
+        pub fe_logical: u64,
+        pub fe_physical: u64,
+        pub fe_length: u64,
+        pub fe_reserved64: [u64; 2],
+        pub fe_flags: u32,
+        pub fe_reserved: [u32; 3],
+    }
+
+    /// Header only; the kernel expects `fm_extent_count` instances
+    /// of `fiemap_extent` to immediately follow it in memory.
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct fiemap {
+        pub fm_start: u64,
+        pub fm_length: u64,
+        pub fm_flags: u32,
+        pub fm_mapped_extents: u32,
+        pub fm_extent_count: u32,
+        pub fm_reserved: u32,
+    }
+}
+
+/// Full mapping of copy_file_range(2). Not used directly, as we
+/// always want to copy the same range to the same offset. See
+/// wrappers below.
+pub fn copy_file_range(infd: &File, in_off: i64,
+                       outfd: &File, out_off: i64,
+                       bytes: u64) -> Result<u64>
+{
+    copy_file_range_flags(infd, in_off, outfd, out_off, bytes, 0)
+}
+
+/// As `copy_file_range`, passing `flags` to the syscall. No flags are
+/// currently defined, so the kernel rejects anything other than 0
+/// with `EINVAL`; this is for experimenting with ones added later.
+pub fn copy_file_range_flags(infd: &File, mut in_off: i64,
+                             outfd: &File, mut out_off: i64,
+                             bytes: u64, flags: u32) -> Result<u64>
+{
+    check_range(in_off, bytes)?;
+    check_range(out_off, bytes)?;
+
+    // The syscall advances the offsets. A request past the end of the
+    // source is a short copy of what remains: the call that reaches
+    // EOF returns 0, which copy_clamped() takes as the end.
+    copy_clamped(bytes, MAX_CFR_LEN, |len| {
+        let r = unsafe {
+            ffi::copy_file_range(
+                infd.as_raw_fd(),
+                &mut in_off as *mut i64,
+                outfd.as_raw_fd(),
+                &mut out_off as *mut i64,
+                to_size(len)?,
+                flags,
+            ) as i64
+        };
+        result_or_errno(r, r as u64)
+    })
+}
+
+/// Version of copy_file_range(2) that copies the give range to the
+/// same place in the target file.
+#[allow(dead_code)]
+pub fn copy_file_chunk(infd: &File, outfd: &File,
+                       off: i64, bytes: u64) -> Result<u64>
+{
+    copy_file_range(infd, off, outfd, off, bytes)
+}
+
+/// Version of copy_file_range that defers offset-management to the
+/// syscall. see copy_file_range(2) for details.
+pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
+    copy_file_bytes_flags(infd, outfd, bytes, 0)
+}
+
+/// As `copy_file_bytes`, passing `flags` to copy_file_range(2).
+/// Unknown flags are rejected by the kernel with `EINVAL`.
+pub fn copy_file_bytes_flags(infd: &File, outfd: &File, bytes: u64,
+                             flags: u32) -> Result<u64> {
+    copy_clamped(bytes, MAX_CFR_LEN, |len| {
+        let r = unsafe {
+            ffi::copy_file_range(
+                infd.as_raw_fd(),
+                null_mut(),
+                outfd.as_raw_fd(),
+                null_mut(),
+                to_size(len)?,
+                flags,
+            ) as i64
+        };
+        result_or_errno(r, r as u64)
+    })
+}
+
+/// Commit all pending writes on the filesystem containing the file
+/// with syncfs(2).
+pub fn syncfs(fd: &File) -> Result<()> {
+    let r = unsafe { ffi::syncfs(fd.as_raw_fd()) };
+    result_or_errno(r as i64, ())
+}
+
+/// Whether the file is encrypted with fscrypt, per the
+/// FS_IOC_GET_ENCRYPTION_POLICY ioctl. Filesystems without fscrypt
+/// support report unencrypted.
+pub fn is_encrypted(fd: &File) -> Result<bool> {
+    let mut policy = ffi::fscrypt_policy_v1::default();
+    let r = unsafe {
+        libc::ioctl(fd.as_raw_fd(), ffi::FS_IOC_GET_ENCRYPTION_POLICY, &mut policy)
+    };
+    if r == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // The file has a v2 policy, which this ioctl can't return.
+        Some(libc::EINVAL) => Ok(true),
+        Some(libc::ENODATA) | Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) => Ok(false),
+        _ => Err(err.into()),
+    }
+}
+
+/// The inode flags (see chattr(1)) that are copied to the
+/// destination. Others either describe how the data is stored
+/// (e.g. compression or no-CoW, which can only be set on an empty
+/// file) or can't be set by userspace at all.
+const COPYABLE_FLAGS: libc::c_int = ffi::FS_SYNC_FL | ffi::FS_IMMUTABLE_FL | ffi::FS_APPEND_FL
+    | ffi::FS_NODUMP_FL | ffi::FS_NOATIME_FL;
+
+/// The file's inode flags, per the FS_IOC_GETFLAGS ioctl.
+pub fn inode_flags(fd: &File) -> Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    let r = unsafe { libc::ioctl(fd.as_raw_fd(), ffi::FS_IOC_GETFLAGS, &mut flags) };
+    result_or_errno(r as i64, flags)
+}
+
+/// Copy the source's immutable, append-only and similar inode flags
+/// to the destination, leaving its other flags as they are. Setting
+/// the immutable and append-only flags requires CAP_LINUX_IMMUTABLE,
+/// so this fails with EPERM unprivileged; filesystems without inode
+/// flags fail with ENOTTY. Nothing is set if the flags already match.
+pub fn copy_inode_flags(infd: &File, outfd: &File) -> Result<()> {
+    let src = inode_flags(infd)? & COPYABLE_FLAGS;
+    let dst = inode_flags(outfd)?;
+    let flags = (dst & !COPYABLE_FLAGS) | src;
+    if flags == dst {
+        return Ok(());
+    }
+    let r = unsafe { libc::ioctl(outfd.as_raw_fd(), ffi::FS_IOC_SETFLAGS, &flags) };
+    result_or_errno(r as i64, ())
+}
+
+/// Whether an error is the ENOKEY open(2) fails with for an encrypted
+/// file whose key isn't loaded.
+pub fn missing_key(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOKEY)
+}
+
+/// The files mapped into this process, e.g. its executable and shared
+/// libraries, from /proc/self/maps.
+pub fn mapped_files() -> Result<Vec<PathBuf>> {
+    let maps = fs::read("/proc/self/maps")?;
+    let mut files = maps.split(|b| *b == b'\n')
+        // Only the pathname field can contain a slash.
+        .filter_map(|line| line.iter().position(|b| *b == b'/').map(|i| &line[i..]))
+        .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+        .collect::<Vec<PathBuf>>();
+    files.dedup();
+    Ok(files)
+}
+
+/// Clone the whole of `infd` into `outfd` with the FICLONE ioctl,
+/// sharing the underlying extents. Only supported within a single
+/// CoW filesystem (e.g. btrfs, XFS); otherwise fails with
+/// EOPNOTSUPP, EXDEV or EINVAL.
+pub fn reflink(infd: &File, outfd: &File) -> Result<()> {
+    let r = unsafe {
+        libc::ioctl(outfd.as_raw_fd(), ffi::FICLONE, infd.as_raw_fd())
+    };
+    result_or_errno(r as i64, ())
+}
+
+/// The names of the file's extended attributes.
+pub fn list_xattrs(fd: &File) -> Result<Vec<CString>> {
+    let buf = xattr_buffer(|buf, size| unsafe {
+        libc::flistxattr(fd.as_raw_fd(), buf as *mut libc::c_char, size)
+    })?;
+    Ok(buf.split(|b| *b == 0)
+       .filter(|name| !name.is_empty())
+       .map(|name| CString::new(name).unwrap())
+       .collect())
+}
+
+pub fn get_xattr(fd: &File, name: &CStr) -> Result<Vec<u8>> {
+    xattr_buffer(|buf, size| unsafe {
+        libc::fgetxattr(fd.as_raw_fd(), name.as_ptr(), buf, size)
+    })
+}
+
+pub fn set_xattr(fd: &File, name: &CStr, value: &[u8]) -> Result<()> {
+    let r = unsafe {
+        libc::fsetxattr(fd.as_raw_fd(), name.as_ptr(),
+                        value.as_ptr() as *const libc::c_void, value.len(), 0)
+    };
+    result_or_errno(r as i64, ())
+}
+
+/// The number of extents fetched by each FIEMAP call.
+const FIEMAP_BATCH: u32 = 256;
+
+fn fiemap_call(fd: &File, start: u64, count: u32)
+               -> Result<(ffi::fiemap, Vec<ffi::fiemap_extent>)> {
+    let hsize = mem::size_of::<ffi::fiemap>();
+    let esize = mem::size_of::<ffi::fiemap_extent>();
+    let header = ffi::fiemap {
+        fm_start: start,
+        fm_length: ffi::FIEMAP_MAX_OFFSET,
+        fm_flags: ffi::FIEMAP_FLAG_SYNC,
+        fm_extent_count: count,
+        ..Default::default()
+    };
+
+    // Back the variable-length struct with u64s to get the alignment
+    // the kernel expects.
+    let words = (hsize + esize * count as usize) / mem::size_of::<u64>();
+    let mut buf = vec![0u64; words];
+    let r = unsafe {
+        let base = buf.as_mut_ptr() as *mut u8;
+        (base as *mut ffi::fiemap).write(header);
+        libc::ioctl(fd.as_raw_fd(), ffi::FS_IOC_FIEMAP, base)
+    };
+    result_or_errno(r as i64, ())?;
+
+    let header = unsafe { (buf.as_ptr() as *const ffi::fiemap).read() };
+    let mapped = cmp::min(header.fm_mapped_extents, count) as usize;
+    let extents = (0..mapped)
+        .map(|i| unsafe {
+            let base = (buf.as_ptr() as *const u8).add(hsize + i * esize);
+            (base as *const ffi::fiemap_extent).read()
+        })
+        .collect();
+
+    Ok((header, extents))
+}
+
+/// Return the precise extent map of a file via the FS_IOC_FIEMAP
+/// ioctl. This is more accurate than SEEK_DATA/SEEK_HOLE on some
+/// filesystems, which may report the whole file as data. Returns an
+/// error (typically EOPNOTSUPP) where the filesystem doesn't support
+/// the ioctl.
+pub fn fiemap(fd: &File) -> Result<Vec<Extent>> {
+    // The extents are fetched in batches, each starting where the
+    // last ended, until one comes back flagged as the last. A batch
+    // with no extents means there's no data past the previous one.
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let (_, batch) = fiemap_call(fd, start, FIEMAP_BATCH)?;
+        let batch = batch.iter()
+            .map(|fe| Extent {
+                logical: fe.fe_logical,
+                physical: fe.fe_physical,
+                length: fe.fe_length,
+                flags: fe.fe_flags,
+            })
+            .collect::<Vec<Extent>>();
+        let last = match batch.last() {
+            Some(last) => last.clone(),
+            None => break,
+        };
+        extents.extend(batch);
+        if last.is_last() {
+            break;
+        }
+        // Guard against looping forever on a batch that doesn't move
+        // on; the caller can fall back to lseek.
+        if last.end() <= start {
+            return Err(io_err(io::ErrorKind::Other, "FIEMAP extents didn't advance."));
+        }
+        start = last.end();
+    }
+
+    Ok(extents)
+}
+
+/// The number of extents backing the file, as reported by FIEMAP. A
+/// high count for a large file indicates fragmentation.
+pub fn extent_count(fd: &File) -> Result<u64> {
+    // A count of 0 asks the kernel how many extents there are
+    // without returning them.
+    let (header, _) = fiemap_call(fd, 0, 0)?;
+    Ok(u64::from(header.fm_mapped_extents))
+}
+
+/// Allocate blocks for the first `len` bytes of the file with
+/// fallocate(2), extending it if necessary. Allocating up-front
+/// allows the filesystem to choose contiguous extents.
+pub fn preallocate(fd: &File, len: u64) -> Result<()> {
+    let r = unsafe {
+        libc::fallocate(fd.as_raw_fd(), 0, 0, to_off64(len)?)
+    };
+    result_or_errno(r as i64, ())
+}
+
+/// Allocate blocks for `len` bytes of the file from `off` without
+/// writing them or changing the file's length, so the range reads as
+/// zeros but isn't a hole.
+pub fn allocate_range(fd: &File, off: u64, len: u64) -> Result<()> {
+    let r = unsafe {
+        libc::fallocate(fd.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, to_off64(off)?, to_off64(len)?)
+    };
+    result_or_errno(r as i64, ())
+}
+
+/// The file's creation (birth) time, from statx(2). This is `None` if
+/// the filesystem doesn't record it or the kernel is too old to report
+/// it. There's no way to set it, so it can't be preserved by a copy.
+#[allow(dead_code)]
+pub fn birth_time(fd: &File) -> Result<Option<SystemTime>> {
+    let mut stx = ffi::statx::default();
+    let r = unsafe {
+        ffi::statx(fd.as_raw_fd(), b"\0".as_ptr() as *const libc::c_char, libc::AT_EMPTY_PATH,
+                   ffi::STATX_BTIME, &mut stx)
+    };
+    if r != 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOSYS) => Ok(None),
+            _ => Err(err.into()),
+        };
+    }
+
+    // The mask says which of the requested fields were filled in.
+    if stx.stx_mask & ffi::STATX_BTIME == 0 {
+        return Ok(None);
+    }
+    Ok(Some(system_time(stx.stx_btime.tv_sec, stx.stx_btime.tv_nsec)))
+}
+
+/// The type of filesystem the file is on, from the magic number
+/// fstatfs(2) reports.
+pub fn fs_type(fd: &File) -> Result<FsType> {
+    // The magic numbers are 32 bits, but f_type is a signed word.
+    let magic = fstatfs(fd)?.f_type as u32;
+    Ok(match magic {
+        ffi::BTRFS_SUPER_MAGIC => FsType::Btrfs,
+        ffi::XFS_SUPER_MAGIC => FsType::Xfs,
+        ffi::EXT4_SUPER_MAGIC => FsType::Ext4,
+        ffi::ZFS_SUPER_MAGIC => FsType::Zfs,
+        ffi::TMPFS_MAGIC => FsType::Tmpfs,
+        ffi::NFS_SUPER_MAGIC => FsType::Nfs,
+        ffi::OVERLAYFS_SUPER_MAGIC => FsType::Overlay,
+        _ => FsType::Other(i64::from(magic)),
+    })
+}
+
+/// Ask the kernel to drop the file's cached pages with
+/// posix_fadvise(2). Dirty pages are written back first, but only
+/// pages that are already clean are dropped immediately.
+pub fn drop_cache(fd: &File) -> Result<()> {
+    // Returns the error rather than setting errno.
+    match unsafe { libc::posix_fadvise(fd.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+        0 => Ok(()),
+        err => Err(std::io::Error::from_raw_os_error(err).into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::{allocate_file, lseek, probably_sparse, sparseness, SeekOff, Wence};
+    use std::fs::{read, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::FileExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_clamped() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..64 * 1024).map(|i| i as u8).collect::<Vec<u8>>();
+        std::fs::write(&from, &data)?;
+
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+        let mut calls = 0;
+        let n = copy_clamped(data.len() as u64 * 2, 4096, |len| {
+            assert!(len <= 4096);
+            calls += 1;
+            copy_file_bytes(&infd, &outfd, len)
+        })?;
+
+        // Stops at EOF, after one more call to find it.
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(calls, 64 * 1024 / 4096 + 1);
+        assert_eq!(read(&to)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_bytes_flags() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        std::fs::write(&from, &data)?;
+
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+        assert_eq!(copy_file_bytes_flags(&infd, &outfd, data.len() as u64, 0)?, data.len() as u64);
+        assert_eq!(read(&to)?, data);
+
+        // No flags are defined yet.
+        let err = copy_file_range_flags(&infd, 0, &outfd, 0, 1, 1).unwrap_err();
+        assert_eq!(crate::os::errno(&err), Some(libc::EINVAL));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unencrypted_file() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file.txt");
+        std::fs::write(&file, "test data")?;
+
+        assert!(!is_encrypted(&File::open(&file)?)?);
+        assert!(missing_key(&io::Error::from_raw_os_error(libc::ENOKEY)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_inode_flags() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        std::fs::write(&from, "test data")?;
+        std::fs::write(&to, "test data")?;
+        let (infd, outfd) = (File::open(&from)?, File::open(&to)?);
+
+        let flags = match inode_flags(&infd) {
+            Ok(flags) => flags,
+            // No inode flags on this filesystem (e.g. older tmpfs).
+            Err(ref e) if crate::os::errno(e) == Some(libc::ENOTTY) => {
+                eprintln!("Skipping test_copy_inode_flags; no inode flags: {}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        assert_eq!(flags & COPYABLE_FLAGS, 0);
+        copy_inode_flags(&infd, &outfd)?;
+        assert_eq!(inode_flags(&outfd)? & COPYABLE_FLAGS, 0);
+
+        // The owner can set no-dump without privilege.
+        let nodump = flags | ffi::FS_NODUMP_FL;
+        let r = unsafe { libc::ioctl(infd.as_raw_fd(), ffi::FS_IOC_SETFLAGS, &nodump) };
+        if r != 0 {
+            eprintln!("Skipping the rest of test_copy_inode_flags; can't set no-dump: {}",
+                      io::Error::last_os_error());
+            return Ok(());
+        }
+        copy_inode_flags(&infd, &outfd)?;
+        assert_eq!(inode_flags(&outfd)? & COPYABLE_FLAGS, ffi::FS_NODUMP_FL);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_range_sparse() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+        let from = dir.path().join("from.txt");
+        let data = "test data";
+
+        {
+            let mut fd = File::create(&from)?;
+            write!(fd, "{}", data)?;
+        }
+
+        allocate_file(&File::create(&file)?, 1024 * 1024)?;
+
+        {
+            let infd = File::open(&from)?;
+            let outfd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&file)?;
+            copy_file_bytes(&infd, &outfd, data.len() as u64)?;
+        }
+
+        assert!(probably_sparse(&File::open(file)?)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_copy_middle() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+        let from = dir.path().join("from.txt");
+        let data = "test data";
+
+        {
+            let mut fd = File::create(&from)?;
+            write!(fd, "{}", data)?;
+        }
+
+        allocate_file(&File::create(&file)?, 1024 * 1024)?;
+
+        let offset: usize = 512*1024;
+        {
+            let infd = File::open(&from)?;
+            let outfd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&file)?;
+            copy_file_range(&infd, 0,
+                            &outfd, offset as i64,
+                            data.len() as u64)?;
+        }
+
+        assert!(probably_sparse(&File::open(&file)?)?);
+
+        let bytes = read(&file)?;
+        assert!(bytes.len() == 1024*1024);
+        assert!(bytes[offset] == b't');
+        assert!(bytes[offset+1] == b'e');
+        assert!(bytes[offset+2] == b's');
+        assert!(bytes[offset+3] == b't');
+        assert!(bytes[offset+data.len()] == 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lseek_data() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+        let from = dir.path().join("from.txt");
+        let data = "test data";
+        let offset = 512*1024;
+
+        {
+            let mut fd = File::create(&from)?;
+            write!(fd, "{}", data)?;
+        }
+
+        allocate_file(&File::create(&file)?, 1024 * 1024)?;
+        {
+            let infd = File::open(&from)?;
+            let outfd: File = OpenOptions::new()
+                .write(true)
+                .append(false)
+                .open(&file)?;
+            copy_file_range(&infd, 0,
+                            &outfd, offset as i64,
+                            data.len() as u64)?;
+        }
+
+        assert!(probably_sparse(&File::open(&file)?)?);
+
+        let off = lseek(&File::open(&file)?, 0, Wence::Data)?;
+        assert_eq!(off, SeekOff::Offset(offset));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fiemap_sparse() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+        let data = "c00lc0d3";
+
+        {
+            let mut fd = File::create(&file)?;
+            write!(fd, "{}", data)?;
+            fd.seek(SeekFrom::Start(1024*1024))?;
+            write!(fd, "{}", data)?;
+        }
+
+        let extents = match fiemap(&File::open(&file)?) {
+            Ok(extents) => extents,
+            // Not all filesystems support FIEMAP (e.g. tmpfs).
+            Err(e) => {
+                eprintln!("Skipping test_fiemap_sparse; FIEMAP unsupported: {}", e);
+                return Ok(());
+            }
+        };
+
+        assert_eq!(extents.len(), 2);
+        assert_eq!(extents[0].logical, 0);
+        assert!(!extents[0].is_last());
+        assert_eq!(extents[1].logical, 1024*1024);
+        assert!(extents[1].is_last());
+
+        // The gap between the extents is the hole.
+        assert!(extents[0].end() < extents[1].logical);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fiemap_many_extents() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("fragmented.bin");
+        let count = FIEMAP_BATCH as u64 * 2 + 10;
+
+        // A block of data every other block, so none can merge.
+        {
+            let fd = File::create(&file)?;
+            for i in 0..count {
+                fd.write_all_at(&[0x5a; 4096], i * 8192)?;
+            }
+        }
+
+        let extents = match fiemap(&File::open(&file)?) {
+            Ok(extents) => extents,
+            Err(e) => {
+                eprintln!("Skipping test_fiemap_many_extents; FIEMAP unsupported: {}", e);
+                return Ok(());
+            }
+        };
+
+        assert_eq!(extents.len() as u64, count);
+        assert_eq!(extents.last().unwrap().logical, (count - 1) * 8192);
+        assert!(extents.last().unwrap().is_last());
+        assert!(extents.iter().rev().skip(1).all(|e| !e.is_last()));
+        assert!(extents.windows(2).all(|w| w[0].end() < w[1].logical));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extent_count_preallocated() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let plain = dir.path().join("plain.bin");
+        let prealloc = dir.path().join("prealloc.bin");
+        let chunk = 64 * 1024;
+        let chunks = 64;
+
+        File::create(&from)?.write_all(&vec![0x5a; chunk * chunks])?;
+        let infd = File::open(&from)?;
+        let plainfd = File::create(&plain)?;
+        let preallocfd = File::create(&prealloc)?;
+        let mut noise = File::create(dir.path().join("noise.bin"))?;
+
+        if preallocate(&preallocfd, (chunk * chunks) as u64).is_err()
+            || extent_count(&preallocfd).is_err() {
+            eprintln!("Skipping test_extent_count_preallocated; no preallocation or FIEMAP");
+            return Ok(());
+        }
+
+        // Interleave the writes with another file to encourage the
+        // filesystem to fragment the non-preallocated copy.
+        for i in 0..chunks {
+            let off = (i * chunk) as i64;
+            for fd in &[&plainfd, &preallocfd] {
+                copy_file_range(&infd, off, fd, off, chunk as u64)?;
+                fd.sync_data()?;
+            }
+            noise.write_all(&vec![0; chunk])?;
+            noise.sync_data()?;
+        }
+
+        assert_eq!(read(&plain)?, read(&from)?);
+        assert_eq!(read(&prealloc)?, read(&from)?);
+        assert!(extent_count(&plainfd)? > extent_count(&preallocfd)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparseness_preallocated() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("prealloc.bin");
+        let fd = File::create(&file)?;
+        (&fd).write_all(&[0x5a; 4096])?;
+        let r = unsafe {
+            libc::fallocate(fd.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, 1024 * 1024)
+        };
+        if r != 0 {
+            eprintln!("Skipping test_sparseness_preallocated; no fallocate support");
+            return Ok(());
+        }
+
+        assert!(sparseness(&fd)? > 100.0);
+        assert!(!probably_sparse(&fd)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_range() {
+        assert!(check_range(0, 0).is_ok());
+        assert!(check_range(i64::MAX - 1, 1).is_ok());
+        assert!(check_range(i64::MAX, 1).is_err());
+        assert!(check_range(0, u64::MAX).is_err());
+        assert!(check_range(0, i64::MAX as u64 + 1).is_err());
+        assert!(check_range(-1, 0).is_err());
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_to_size_32bit() {
+        assert!(to_size(u64::from(u32::MAX)).is_ok());
+        assert!(to_size(u64::from(u32::MAX) + 1).is_err());
+    }
+
+    #[test]
+    fn test_copy_file_range_overflow() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        std::fs::write(&from, vec![0x5a; 4096])?;
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+
+        for &(in_off, out_off, bytes) in &[(i64::MAX - 10, 0, 100),
+                                           (0, i64::MAX, 1),
+                                           (0, 0, u64::MAX),
+                                           (-1, 0, 10)] {
+            let err = copy_file_range(&infd, in_off, &outfd, out_off, bytes).unwrap_err();
+            let err = err.downcast::<io::Error>().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(outfd.metadata()?.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_range_past_eof() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = (0..4096).map(|i| i as u8).collect::<Vec<u8>>();
+        std::fs::write(&from, &data)?;
+        let (infd, outfd) = (File::open(&from)?, File::create(&to)?);
+
+        assert_eq!(copy_file_range(&infd, 1000, &outfd, 0, 10_000)?, 3096);
+        assert_eq!(read(&to)?, &data[1000..]);
+        // Nothing left to copy is an empty copy, not an error.
+        assert_eq!(copy_file_range(&infd, 4096, &outfd, 3096, 100)?, 0);
+        assert_eq!(copy_file_range(&infd, 8192, &outfd, 3096, 100)?, 0);
+        assert_eq!(outfd.metadata()?.len(), 3096);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use std::time::SystemTime;
+
+use super::{
+    cursor, fs_type_from_name, fstat, fstatfs, lseek, result_or_errno, system_time, to_off64,
+    xattr_buffer, Extent, FsType, Wence,
+};
+use crate::errors::Result;
+
+// macOS has no separate 64-bit variants; off_t is always 64 bits.
+pub(super) use libc::{ftruncate as ftruncate64, lseek as lseek64, lutimes, off_t as off64_t};
+pub(super) use self::ffi::{futimens, SEEK_DATA, SEEK_HOLE};
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    pub const SEEK_HOLE: libc::c_int = 3;
+    pub const SEEK_DATA: libc::c_int = 4;
+
+    pub const COPYFILE_DATA: u32 = 1 << 3;
+
+    pub const F_PREALLOCATE: libc::c_int = 42;
+    pub const F_ALLOCATEALL: libc::c_uint = 0x4;
+    pub const F_PEOFPOSMODE: libc::c_int = 3;
+
+    #[repr(C)]
+    pub struct fstore_t {
+        pub fst_flags: libc::c_uint,
+        pub fst_posmode: libc::c_int,
+        pub fst_offset: libc::off_t,
+        pub fst_length: libc::off_t,
+        pub fst_bytesalloc: libc::off_t,
+    }
+
+    extern "C" {
+        pub fn futimens(fd: libc::c_int, times: *const libc::timespec) -> libc::c_int;
+        pub fn fcopyfile(from: libc::c_int, to: libc::c_int,
+                         state: *mut libc::c_void, flags: u32) -> libc::c_int;
+        pub fn clonefile(src: *const libc::c_char, dst: *const libc::c_char,
+                         flags: u32) -> libc::c_int;
+    }
+}
+
+fn unsupported<T>() -> Result<T> {
+    Err(io::Error::from_raw_os_error(libc::ENOTSUP).into())
+}
+
+/// Copy the file data with fcopyfile(3), which uses the most efficient
+/// method the filesystem supports. It always copies the whole file, so
+/// this is only used when the request is for the whole file from the
+/// start; otherwise it fails with ENOTSUP and the caller falls back to
+/// another method. On success both file cursors are left at the end of
+/// the copied data, as with copy_file_range on Linux.
+pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
+    let len = fstat(infd)?.st_size as u64;
+    if cursor(infd)? != 0 || cursor(outfd)? != 0 || bytes < len {
+        return unsupported();
+    }
+
+    let r = unsafe {
+        ffi::fcopyfile(infd.as_raw_fd(), outfd.as_raw_fd(), null_mut(), ffi::COPYFILE_DATA)
+    };
+    result_or_errno(r as i64, ())?;
+
+    lseek(infd, len as i64, Wence::Set)?;
+    lseek(outfd, len as i64, Wence::Set)?;
+    Ok(len)
+}
+
+/// fcopyfile(3) has no equivalent of copy_file_range's flags, so only
+/// 0 is accepted; anything else fails with `EINVAL`.
+#[allow(dead_code)]
+pub fn copy_file_bytes_flags(infd: &File, outfd: &File, bytes: u64,
+                             flags: u32) -> Result<u64> {
+    if flags != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+    }
+    copy_file_bytes(infd, outfd, bytes)
+}
+
+/// There is no fd-based clone on macOS; see `clone_file()`.
+pub fn reflink(_infd: &File, _outfd: &File) -> Result<()> {
+    unsupported()
+}
+
+/// Clone `from` to a new file at `to` with clonefile(2), sharing the
+/// underlying blocks. Only supported on APFS, and only if `to` doesn't
+/// already exist; otherwise fails with ENOTSUP, EXDEV or EEXIST.
+pub fn clone_file(from: &Path, to: &Path) -> Result<()> {
+    let src = CString::new(from.as_os_str().as_bytes())?;
+    let dst = CString::new(to.as_os_str().as_bytes())?;
+    let r = unsafe { ffi::clonefile(src.as_ptr(), dst.as_ptr(), 0) };
+    result_or_errno(r as i64, ())
+}
+
+/// The names of the file's extended attributes.
+pub fn list_xattrs(fd: &File) -> Result<Vec<CString>> {
+    let buf = xattr_buffer(|buf, size| unsafe {
+        libc::flistxattr(fd.as_raw_fd(), buf as *mut libc::c_char, size, 0)
+    })?;
+    Ok(buf.split(|b| *b == 0)
+       .filter(|name| !name.is_empty())
+       .map(|name| CString::new(name).unwrap())
+       .collect())
+}
+
+pub fn get_xattr(fd: &File, name: &CStr) -> Result<Vec<u8>> {
+    xattr_buffer(|buf, size| unsafe {
+        libc::fgetxattr(fd.as_raw_fd(), name.as_ptr(), buf, size, 0, 0)
+    })
+}
+
+pub fn set_xattr(fd: &File, name: &CStr, value: &[u8]) -> Result<()> {
+    let r = unsafe {
+        libc::fsetxattr(fd.as_raw_fd(), name.as_ptr(),
+                        value.as_ptr() as *const libc::c_void, value.len(), 0, 0)
+    };
+    result_or_errno(r as i64, ())
+}
+
+/// There's no per-filesystem sync, so this syncs all filesystems
+/// with sync(2).
+pub fn syncfs(_fd: &File) -> Result<()> {
+    unsafe { libc::sync() };
+    Ok(())
+}
+
+/// The file's creation (birth) time, which APFS and HFS+ always
+/// record.
+#[allow(dead_code)]
+pub fn birth_time(fd: &File) -> Result<Option<SystemTime>> {
+    let stat = fstat(fd)?;
+    Ok(Some(system_time(stat.st_birthtime as i64, stat.st_birthtime_nsec as u32)))
+}
+
+/// The type of filesystem the file is on, from the name fstatfs(2)
+/// reports.
+pub fn fs_type(fd: &File) -> Result<FsType> {
+    let st = fstatfs(fd)?;
+    let name = unsafe { CStr::from_ptr(st.f_fstypename.as_ptr()) };
+    Ok(fs_type_from_name(name.to_bytes(), st.f_type))
+}
+
+/// Per-file encryption (fscrypt) is Linux-only.
+pub fn is_encrypted(_fd: &File) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn missing_key(_err: &io::Error) -> bool {
+    false
+}
+
+/// There's no /proc/self/maps; only the executable itself is known.
+pub fn mapped_files() -> Result<Vec<PathBuf>> {
+    Ok(Vec::new())
+}
+
+/// There's no posix_fadvise(2); the page cache can only be bypassed
+/// up-front with F_NOCACHE.
+pub fn drop_cache(_fd: &File) -> Result<()> {
+    Ok(())
+}
+
+/// FIEMAP is Linux-only.
+pub fn fiemap(_fd: &File) -> Result<Vec<Extent>> {
+    unsupported()
+}
+
+pub fn extent_count(_fd: &File) -> Result<u64> {
+    unsupported()
+}
+
+/// Only needed for the unwritten extents reported by FIEMAP.
+pub fn allocate_range(_fd: &File, _off: u64, _len: u64) -> Result<()> {
+    unsupported()
+}
+
+/// Copying inode flags is only implemented with Linux's
+/// FS_IOC_GETFLAGS ioctl.
+pub fn copy_inode_flags(_infd: &File, _outfd: &File) -> Result<()> {
+    unsupported()
+}
+
+/// Allocate blocks for the first `len` bytes of the file with
+/// F_PREALLOCATE, then extend it to `len` to match fallocate(2) on
+/// Linux.
+pub fn preallocate(fd: &File, len: u64) -> Result<()> {
+    let size = fstat(fd)?.st_size as u64;
+    if len <= size {
+        return Ok(());
+    }
+
+    let mut store = ffi::fstore_t {
+        fst_flags: ffi::F_ALLOCATEALL,
+        fst_posmode: ffi::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: to_off64(len - size)?,
+        fst_bytesalloc: 0,
+    };
+    let r = unsafe { libc::fcntl(fd.as_raw_fd(), ffi::F_PREALLOCATE, &mut store) };
+    result_or_errno(r as i64, ())?;
+
+    let r = unsafe { libc::ftruncate(fd.as_raw_fd(), to_off64(len)?) };
+    result_or_errno(r as i64, ())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::errno;
+    use std::fs::read;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_file_bytes() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        File::create(&from)?.write_all(b"test data")?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        assert_eq!(copy_file_bytes(&infd, &outfd, 1024)?, 9);
+        assert_eq!(cursor(&infd)?, 9);
+        assert_eq!(read(&to)?, b"test data");
+
+        // Partial copies aren't supported by fcopyfile.
+        let err = copy_file_bytes(&infd, &outfd, 1024).unwrap_err();
+        assert_eq!(errno(&err), Some(libc::ENOTSUP));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preallocate_extends() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("prealloc.bin");
+        let fd = File::create(&file)?;
+
+        preallocate(&fd, 1024 * 1024)?;
+        assert_eq!(fd.metadata()?.len(), 1024 * 1024);
+
+        Ok(())
+    }
+}
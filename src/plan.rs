@@ -0,0 +1,138 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use indicatif::HumanBytes;
+use std::fs::{File, Metadata};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::{Result, XcpError};
+use crate::options::{CopyMethod, CopyOptions};
+use crate::os::{fs_type, is_small_file, FsType, MMAP_THRESHOLD};
+
+
+/// What a copy would do with a single entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// Copy the file's data, expected to be with this method.
+    Copy(CopyMethod),
+    /// Leave the existing destination alone (e.g. `--no-clobber`).
+    Skip,
+    /// Apply the source's metadata to an identical destination.
+    SyncMetadata,
+    /// Recreate the device, FIFO or socket node.
+    CreateNode,
+    /// Recreate the symlink.
+    Symlink,
+    CreateDir,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlannedCopy {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// The number of bytes of data that would be copied.
+    pub size: u64,
+    pub decision: Decision,
+}
+
+/// The result of a dry run: what a copy would do, without any
+/// destination having been touched. Entries that couldn't be planned,
+/// e.g. as the source is unreadable, are kept as `errors` rather than
+/// ending the run, as the copy itself would carry on past them.
+#[derive(Clone, Debug, Default)]
+pub struct CopyPlan {
+    pub entries: Vec<PlannedCopy>,
+    pub errors: Vec<String>,
+}
+
+impl CopyPlan {
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    /// Write the plan to `out`, one entry per line, followed by the
+    /// total.
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        for e in &self.entries {
+            let action = match e.decision {
+                Decision::Copy(method) => format!("copy ({:?})", method),
+                Decision::Skip => "skip".to_string(),
+                Decision::SyncMetadata => "sync-metadata".to_string(),
+                Decision::CreateNode => "mknod".to_string(),
+                Decision::Symlink => "symlink".to_string(),
+                Decision::CreateDir => "mkdir".to_string(),
+            };
+            writeln!(out, "{}\t{}\t{:?} -> {:?}", action, e.size, e.from, e.to)?;
+        }
+        for e in &self.errors {
+            writeln!(out, "error\t{}", e)?;
+        }
+        write!(out, "{} entries, {} to copy", self.entries.len(), HumanBytes(self.total_bytes()))?;
+        if !self.errors.is_empty() {
+            write!(out, ", {} failed", self.errors.len())?;
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+
+    /// Print the plan, failing once it's written if any entry
+    /// couldn't be planned.
+    pub fn print(&self) -> Result<()> {
+        self.write(&mut io::stdout().lock())?;
+        if !self.errors.is_empty() {
+            return Err(XcpError::PlanFailed { count: self.errors.len() }.into());
+        }
+        Ok(())
+    }
+}
+
+/// Filesystems that never support reflinks.
+fn lacks_reflinks(fs: FsType) -> bool {
+    matches!(fs, FsType::Ext4 | FsType::Tmpfs | FsType::Hfs | FsType::Ufs)
+}
+
+/// The method `copy_data` is expected to copy a source with. This
+/// can't be known for certain without trying it, so it assumes that
+/// reflinks and copy_file_range only work within a filesystem, and
+/// reflinks only on one that may support them (see `fs_type()`); the
+/// copy itself may still fall back to a later method. As in the copy,
+/// small files skip copy_file_range.
+pub fn predict_method(meta: &Metadata, to: &Path, opts: &CopyOptions) -> CopyMethod {
+    if opts.skip_zeros || opts.inspect.is_some() {
+        return CopyMethod::Userspace;
+    }
+    let dest = to.ancestors()
+        .map(|p| if p.as_os_str().is_empty() { Path::new(".") } else { p })
+        .find(|p| p.exists());
+    let same_fs = dest.and_then(|p| p.metadata().ok()).map(|m| m.dev()) == Some(meta.dev());
+    let reflinks = same_fs && !dest.and_then(|p| File::open(p).ok())
+        .and_then(|fd| fs_type(&fd).ok())
+        .is_some_and(lacks_reflinks);
+
+    let methods = &opts.methods;
+    let small = is_small_file(meta) && methods.contains(CopyMethod::Userspace);
+    if methods.contains(CopyMethod::Reflink) && reflinks {
+        CopyMethod::Reflink
+    } else if methods.contains(CopyMethod::Mmap) && meta.len() < MMAP_THRESHOLD {
+        CopyMethod::Mmap
+    } else {
+        methods.byte_methods()
+            .find(|m| *m != CopyMethod::CopyFileRange || (same_fs && !small))
+            .unwrap_or(CopyMethod::Userspace)
+    }
+}
@@ -14,16 +14,22 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use indicatif::HumanBytes;
+use log::info;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
+use std::time::Duration;
 
-use indicatif;
-
-use crate::errors::Result;
+use crate::errors::{Error, Result};
+use crate::operations::CopyKind;
 
 #[derive(Debug, Clone)]
 pub enum StatusUpdate {
     Copied(u64),
     Size(u64),
+    /// Bytes to remove from the total, for sources that disappeared
+    /// after they were scanned.
+    Vanished(u64),
 }
 
 impl StatusUpdate {
@@ -31,12 +37,68 @@ impl StatusUpdate {
         match self {
             StatusUpdate::Copied(_) => StatusUpdate::Copied(bytes),
             StatusUpdate::Size(_) => StatusUpdate::Size(bytes),
+            StatusUpdate::Vanished(_) => StatusUpdate::Vanished(bytes),
         }
     }
     fn value(&self) -> u64 {
         match self {
             StatusUpdate::Copied(v) => *v,
             StatusUpdate::Size(v) => *v,
+            StatusUpdate::Vanished(v) => *v,
+        }
+    }
+}
+
+/// Accumulated timings for the userspace copy path. The read and
+/// write halves of each chunk are timed separately, so on asymmetric
+/// storage the summary shows which side is the bottleneck. Also
+/// counts the files that needed re-copying with `--self-heal`, those
+/// skipped because the destination exists or the source vanished,
+/// and those fsynced.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CopyStats {
+    pub read_bytes: u64,
+    pub read_time: Duration,
+    pub write_bytes: u64,
+    pub write_time: Duration,
+    pub healed: u64,
+    pub skipped: u64,
+    pub vanished: u64,
+    pub synced: u64,
+}
+
+fn throughput(bytes: u64, time: Duration) -> f64 {
+    bytes as f64 / time.as_secs_f64().max(1e-9)
+}
+
+impl CopyStats {
+    /// Read throughput in bytes/second.
+    pub fn read_throughput(&self) -> f64 {
+        throughput(self.read_bytes, self.read_time)
+    }
+
+    /// Write throughput in bytes/second.
+    pub fn write_throughput(&self) -> f64 {
+        throughput(self.write_bytes, self.write_time)
+    }
+
+    pub fn log_summary(&self) {
+        if self.read_bytes > 0 {
+            info!("Userspace copy: read {} at {}/s, wrote {} at {}/s",
+                  HumanBytes(self.read_bytes), HumanBytes(self.read_throughput() as u64),
+                  HumanBytes(self.write_bytes), HumanBytes(self.write_throughput() as u64));
+        }
+        if self.healed > 0 {
+            info!("Re-copied {} file(s) that failed verification", self.healed);
+        }
+        if self.skipped > 0 {
+            info!("Skipped {} existing file(s)", self.skipped);
+        }
+        if self.vanished > 0 {
+            info!("Skipped {} source file(s) that vanished", self.vanished);
+        }
+        if self.synced > 0 {
+            info!("Synced {} file(s)", self.synced);
         }
     }
 }
@@ -47,14 +109,25 @@ pub trait Updater<T> {
     fn update(&mut self, update: T) -> Result<()>;
 }
 
-pub struct BatchUpdater {
-    pub sender: Box<Updater<Result<StatusUpdate>> + Send>,
+pub struct BatchUpdater<'a> {
+    pub sender: Box<dyn Updater<Result<StatusUpdate>> + Send + 'a>,
     pub stat: StatusUpdate,
     pub batch_size: u64,
 }
 
+impl BatchUpdater<'_> {
+    /// Send any bytes accumulated since the last full batch.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.stat.value() > 0 {
+            self.sender.update(Ok(self.stat.clone()))?;
+            self.stat = self.stat.set(0);
+        }
+        Ok(())
+    }
+}
+
 
-impl Updater<Result<u64>> for BatchUpdater {
+impl Updater<Result<u64>> for BatchUpdater<'_> {
     fn update(&mut self, status: Result<u64>) -> Result<()> {
         match status {
             Ok(bytes) => {
@@ -91,6 +164,41 @@ impl Updater<Result<StatusUpdate>> for NopUpdater {
 }
 
 
+/// Callbacks for following a copy file by file, e.g. to drive a
+/// progress display other than the built-in bar. They are called from
+/// the thread doing the copying, which may not be the one that
+/// started it, hence `Send + Sync`. Every method defaults to doing
+/// nothing.
+pub trait CopyObserver: Send + Sync {
+    /// A file of `size` bytes is about to be copied.
+    fn on_file_start(&self, _path: &Path, _size: u64) {}
+    /// Another `bytes` of the file have been copied.
+    fn on_progress(&self, _path: &Path, _bytes: u64) {}
+    /// The file was copied, or skipped as `CopyKind::Skipped`.
+    fn on_file_done(&self, _path: &Path, _kind: CopyKind) {}
+    fn on_file_error(&self, _path: &Path, _err: &Error) {}
+}
+
+pub struct NopObserver {}
+
+impl CopyObserver for NopObserver {}
+
+/// Passes the copied bytes of a single file on to an observer.
+pub struct ObserverUpdater<'a> {
+    pub observer: &'a dyn CopyObserver,
+    pub path: PathBuf,
+}
+
+impl Updater<Result<StatusUpdate>> for ObserverUpdater<'_> {
+    fn update(&mut self, update: Result<StatusUpdate>) -> Result<()> {
+        if let Ok(StatusUpdate::Copied(bytes)) = update {
+            self.observer.on_progress(&self.path, bytes);
+        }
+        Ok(())
+    }
+}
+
+
 pub struct ProgressUpdater {
     pub pb: ProgressBar,
     pub written: u64,
@@ -0,0 +1,160 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::null;
+use std::sync::{Mutex, OnceLock};
+
+use crate::errors::Result;
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    pub enum selabel_handle {}
+
+    pub const SELABEL_CTX_FILE: libc::c_uint = 0;
+
+    #[link(name = "selinux")]
+    extern "C" {
+        pub fn is_selinux_enabled() -> libc::c_int;
+        pub fn selabel_open(backend: libc::c_uint,
+                            opts: *const libc::c_void,
+                            nopts: libc::c_uint) -> *mut selabel_handle;
+        pub fn selabel_lookup(handle: *mut selabel_handle,
+                              con: *mut *mut libc::c_char,
+                              key: *const libc::c_char,
+                              mode: libc::c_int) -> libc::c_int;
+        pub fn fsetfilecon(fd: libc::c_int, con: *const libc::c_char) -> libc::c_int;
+        pub fn freecon(con: *mut libc::c_char);
+    }
+}
+
+pub fn enabled() -> bool {
+    unsafe { ffi::is_selinux_enabled() > 0 }
+}
+
+/// The file-context labelling handle, which loads and compiles the
+/// policy's file contexts, so is opened once and kept for the run.
+struct Handle(*mut ffi::selabel_handle);
+
+// Lookups are serialised by the mutex around it.
+unsafe impl Send for Handle {}
+
+/// The handle, or the errno it failed to open with.
+fn handle() -> &'static std::result::Result<Mutex<Handle>, i32> {
+    static HANDLE: OnceLock<std::result::Result<Mutex<Handle>, i32>> = OnceLock::new();
+    HANDLE.get_or_init(|| {
+        let handle = unsafe { ffi::selabel_open(ffi::SELABEL_CTX_FILE, null(), 0) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO));
+        }
+        Ok(Mutex::new(Handle(handle)))
+    })
+}
+
+/// Look up the context the loaded policy assigns to `path` (which
+/// should be absolute) for a file of the given mode, as `matchpathcon`
+/// and `restorecon` do.
+pub fn policy_context(path: &Path, mode: u32) -> Result<CString> {
+    let key = CString::new(path.as_os_str().as_bytes())?;
+    let handle = match handle() {
+        Ok(handle) => handle.lock().unwrap_or_else(|e| e.into_inner()),
+        Err(errno) => return Err(io::Error::from_raw_os_error(*errno).into()),
+    };
+    unsafe {
+        let mut con = std::ptr::null_mut();
+        let r = ffi::selabel_lookup(handle.0, &mut con, key.as_ptr(), mode as libc::c_int);
+        if r != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let context = CStr::from_ptr(con).to_owned();
+        ffi::freecon(con);
+        Ok(context)
+    }
+}
+
+/// Apply the policy-assigned context for the destination path to the
+/// open file, rather than copying the source's context verbatim. The
+/// path needn't exist yet.
+pub fn relabel(fd: &File, path: &Path) -> Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+
+    // The destination may still be under a temporary name, so only
+    // its directory is resolved.
+    let path = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) if dir != Path::new("") => dir.canonicalize()?.join(name),
+        (_, Some(name)) => std::env::current_dir()?.join(name),
+        _ => path.canonicalize()?,
+    };
+    let mode = fd.metadata()?.mode();
+    let context = policy_context(&path, mode)?;
+
+    let r = unsafe { ffi::fsetfilecon(fd.as_raw_fd(), context.as_ptr()) };
+    if r != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::copy_file;
+    use crate::options::{Atomic, CopyOptions};
+    use tempfile::tempdir_in;
+
+    #[test]
+    fn test_relabel_destination() -> Result<()> {
+        if !enabled() {
+            return Ok(());
+        }
+
+        // Policies label /tmp and the user's home differently.
+        let src_dir = tempdir_in("/tmp")?;
+        let dest_dir = tempdir_in(".")?;
+        let from = src_dir.path().join("from.txt");
+        std::fs::write(&from, "test data")?;
+
+        // Atomic copies are written under a temporary name first.
+        for (name, atomic) in &[("to.txt", Atomic::Never), ("atomic.txt", Atomic::Always)] {
+            let to = dest_dir.path().join(name);
+            let opts = CopyOptions::new().preserve_context(true).atomic(*atomic);
+            copy_file(&from, &to, &opts)?;
+
+            let expected = policy_context(&to.canonicalize()?, to.metadata()?.mode())?;
+
+            let mut buf = vec![0u8; 256];
+            let fd = File::open(&to)?;
+            let n = unsafe {
+                libc::fgetxattr(fd.as_raw_fd(), b"security.selinux\0".as_ptr() as *const libc::c_char,
+                                buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+            };
+            assert!(n > 0);
+            let actual = buf[..n as usize].split(|b| *b == 0).next().unwrap();
+            assert_eq!(actual, expected.as_bytes());
+        }
+
+        Ok(())
+    }
+}
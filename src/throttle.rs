@@ -0,0 +1,166 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::cmp;
+use std::fs::File;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::errors::Result;
+
+/// Number of times per second the bucket is refilled; the chunk size
+/// is derived from this so the rate is smooth at the scale of a
+/// progress bar.
+const REFILLS_PER_SEC: u64 = 10;
+
+/// Lower bound on the chunk size so very low limits don't degrade
+/// into a syscall per byte.
+const MIN_CHUNK: u64 = 4096;
+
+/// Where a throttle gets the time from and how it waits, so tests can
+/// run it against a simulated clock.
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// A token-bucket rate limiter. Tokens (bytes) accrue at `rate` per
+/// second, up to a maximum of one chunk, so an idle period can't be
+/// used to burst past the limit.
+pub struct Throttle {
+    rate: u64,
+    chunk: u64,
+    tokens: f64,
+    last: Instant,
+    clock: Box<dyn Clock>,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Throttle {
+        Throttle::with_clock(rate, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(rate: u64, clock: Box<dyn Clock>) -> Throttle {
+        Throttle {
+            rate,
+            chunk: cmp::max(rate / REFILLS_PER_SEC, MIN_CHUNK),
+            tokens: 0.0,
+            last: clock.now(),
+            clock,
+        }
+    }
+
+    /// Block until `bytes` can be consumed.
+    fn acquire(&mut self, bytes: u64) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.chunk as f64);
+        self.last = now;
+
+        let needed = bytes as f64 - self.tokens;
+        if needed > 0.0 {
+            self.clock.sleep(Duration::from_secs_f64(needed / self.rate as f64));
+            self.last = self.clock.now();
+            self.tokens = 0.0;
+        } else {
+            self.tokens -= bytes as f64;
+        }
+    }
+}
+
+/// Rate-limited wrapper around a byte-copy function such as
+/// `copy_bytes`; copies in chunks and sleeps between them to keep the
+/// average throughput under the throttle's limit.
+pub fn copy_bytes_throttled<F>(infd: &File, outfd: &File, bytes: u64,
+                               throttle: &mut Throttle, mut copy: F) -> Result<u64>
+    where F: FnMut(&File, &File, u64) -> Result<u64>
+{
+    let mut written = 0u64;
+    while written < bytes {
+        let len = cmp::min(bytes - written, throttle.chunk);
+        throttle.acquire(len);
+        let r = copy(infd, outfd, len)?;
+        if r == 0 {
+            break;
+        }
+        written += r;
+    }
+    Ok(written)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::copy_bytes;
+    use std::fs::read;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    /// A clock that only advances when slept on.
+    struct FakeClock {
+        start: Instant,
+        elapsed: Arc<Mutex<Duration>>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+    }
+
+    #[test]
+    fn test_throttled_copy() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let len = 64 * 1024;
+        let rate = 128 * 1024;
+
+        File::create(&from)?.write_all(&vec![0x5a; len])?;
+
+        let elapsed = Arc::new(Mutex::new(Duration::default()));
+        let clock = FakeClock { start: Instant::now(), elapsed: elapsed.clone() };
+        let mut throttle = Throttle::with_clock(rate, Box::new(clock));
+        let n = copy_bytes_throttled(&File::open(&from)?, &File::create(&to)?,
+                                     len as u64, &mut throttle, copy_bytes)?;
+
+        assert_eq!(n, len as u64);
+        assert_eq!(read(&from)?, read(&to)?);
+        // With no time passing otherwise, all of it is spent waiting.
+        let expected = len as f64 / rate as f64;
+        assert!((elapsed.lock().unwrap().as_secs_f64() - expected).abs() < 1e-6);
+
+        Ok(())
+    }
+}
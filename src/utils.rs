@@ -14,18 +14,28 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Component, Path, PathBuf};
+use std::process;
 use std::result;
 
 use glob::{glob, Paths};
 
-use crate::errors::Result;
+use crate::errors::{Result, XcpError};
 
 pub enum FileType {
     File,
     Dir,
     Symlink,
+    /// A block or character device.
+    Device,
+    /// A named pipe.
+    Fifo,
+    /// A unix domain socket.
+    Socket,
     Unknown,
 }
 
@@ -40,6 +50,12 @@ fn to_enum(ft: &fs::FileType) -> FileType {
         FileType::File
     } else if ft.is_symlink() {
         FileType::Symlink
+    } else if ft.is_block_device() || ft.is_char_device() {
+        FileType::Device
+    } else if ft.is_fifo() {
+        FileType::Fifo
+    } else if ft.is_socket() {
+        FileType::Socket
     } else {
         FileType::Unknown
     }
@@ -51,6 +67,103 @@ impl ToFileType for fs::FileType {
     }
 }
 
+/// Parse a size with an optional K/M/G suffix (powers of 1024).
+pub fn parse_size(s: &str) -> result::Result<u64, String> {
+    let (num, mult) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| format!("Invalid size: {}", s))
+}
+
+/// Parse an octal file mode, e.g. `0644`.
+pub fn parse_mode(s: &str) -> result::Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+        .ok_or_else(|| format!("Invalid mode: {}", s))
+}
+
+/// Check whether the filesystem holding `dir` treats names
+/// case-insensitively, by creating a probe file and looking it up
+/// with different case.
+pub fn is_case_insensitive(dir: &Path) -> Result<bool> {
+    let probe = dir.join(format!(".xcp-case-probe-{}", process::id()));
+    let upper = dir.join(format!(".XCP-CASE-PROBE-{}", process::id()));
+
+    fs::File::create(&probe)?;
+    let insensitive = upper.symlink_metadata().is_ok();
+    fs::remove_file(&probe)?;
+
+    Ok(insensitive)
+}
+
+/// Days since the epoch of a proleptic Gregorian date; see
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Parse a timestamp as seconds since the epoch, either `@<seconds>`
+/// or RFC 3339 (`YYYY-MM-DDTHH:MM:SS` followed by `Z` or a `+HH:MM`
+/// offset).
+pub fn parse_timestamp(s: &str) -> result::Result<i64, String> {
+    let err = || format!("Invalid timestamp: {}", s);
+
+    if let Some(epoch) = s.strip_prefix('@') {
+        return epoch.parse().map_err(|_| err());
+    }
+
+    let num = |range: std::ops::Range<usize>| -> result::Result<i64, String> {
+        let field = s.get(range).ok_or_else(err)?;
+        if !field.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(err());
+        }
+        field.parse().map_err(|_| err())
+    };
+    let sep = |i: usize, c: &[u8]| s.as_bytes().get(i).filter(|b| c.contains(b)).ok_or_else(err);
+
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, min, sec) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    sep(4, b"-")?;
+    sep(7, b"-")?;
+    sep(10, b"Tt ")?;
+    sep(13, b":")?;
+    sep(16, b":")?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || hour > 23 || min > 59 || sec > 60
+    {
+        return Err(err());
+    }
+
+    let offset = match s.get(19..) {
+        Some("Z") | Some("z") => 0,
+        Some(tz) if tz.len() == 6 => {
+            let sign = match *sep(19, b"+-")? {
+                b'+' => 1,
+                _ => -1,
+            };
+            sep(22, b":")?;
+            sign * (num(20..22)? * 3600 + num(23..25)? * 60)
+        }
+        _ => return Err(err()),
+    };
+
+    let days = days_from_civil(year, month, day);
+    Ok(days * 86400 + hour * 3600 + min * 60 + sec - offset)
+}
+
 // Expand a list of file-paths or glob-patterns into a list of concrete paths.
 //
 // Note: This is probably iterator overkill, but it took me a whole
@@ -59,17 +172,24 @@ impl ToFileType for fs::FileType {
 // FIXME: This currently eats non-existent files that are not
 // globs. Should we convert empty glob results into errors?
 //
-pub fn expand_globs(patterns: &Vec<String>) -> Result<Vec<PathBuf>> {
+pub fn expand_globs(patterns: &[String]) -> Result<Vec<PathBuf>> {
     let mut globs = patterns
         .iter()
-        .map(|s| glob(&*s.as_str())) // -> Vec<Result<Paths>>
+        .map(|s| glob(s.as_str())) // -> Vec<Result<Paths>>
         .collect::<result::Result<Vec<Paths>, _>>()?; // -> Result<Vec<Paths>>
-    let path_vecs = globs
+    let mut path_vecs = globs
         .iter_mut()
         // Force resolve each glob Paths iterator into a vector of the results...
         .map::<result::Result<Vec<PathBuf>, _>, _>(|p| p.collect())
         // And lift all the results up to the top.
         .collect::<result::Result<Vec<Vec<PathBuf>>, _>>()?;
+    // Glob skips broken symlinks, which are valid sources when
+    // copying links as links.
+    for (pattern, paths) in patterns.iter().zip(path_vecs.iter_mut()) {
+        if paths.is_empty() && Path::new(pattern).symlink_metadata().is_ok() {
+            paths.push(PathBuf::from(pattern));
+        }
+    }
     // And finally flatten the nested paths into a single collection of the results
     let paths = path_vecs
         .iter()
@@ -78,3 +198,131 @@ pub fn expand_globs(patterns: &Vec<String>) -> Result<Vec<PathBuf>> {
 
     Ok(paths)
 }
+
+/// Resolve `.` and `..` components without touching the filesystem.
+/// Leading `..`s are kept in relative paths, and dropped at the root
+/// of absolute ones.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => { out.pop(); }
+                Some(Component::RootDir) => {}
+                _ => out.push(".."),
+            },
+            _ => out.push(comp),
+        }
+    }
+    out
+}
+
+/// `path` made absolute against the current directory, and
+/// normalised.
+pub fn absolute(path: &Path) -> Result<PathBuf> {
+    Ok(normalize(&env::current_dir()?.join(path)))
+}
+
+/// The relative path from the directory `base` to `path`; both must
+/// be absolute and normalised.
+pub fn relative_path(base: &Path, path: &Path) -> PathBuf {
+    let (mut base, mut path) = (base.components().peekable(), path.components().peekable());
+    while base.peek().is_some() && base.peek() == path.peek() {
+        base.next();
+        path.next();
+    }
+    base.map(|_| Component::ParentDir).chain(path).collect()
+}
+
+/// The path a copy of `from` to `to` writes to. As with `cp`, if `to`
+/// is an existing directory the copy goes inside it, under the
+/// source's name. A `to` ending in a slash must be an existing
+/// directory; it isn't created.
+pub fn target_path(from: &Path, to: &Path) -> Result<PathBuf> {
+    if to.is_dir() {
+        // Made absolute so that e.g. `.` and `dir/..` have a name.
+        let from = absolute(from)?;
+        let name = from.file_name().ok_or(XcpError::UnknownFilename)?;
+        return Ok(to.join(name));
+    }
+    if to.as_os_str().as_bytes().ends_with(b"/") {
+        return Err(XcpError::InvalidDestination {
+            msg: "Destination ends with a slash but isn't an existing directory",
+        }.into());
+    }
+    Ok(to.to_path_buf())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0644"), Ok(0o644));
+        assert_eq!(parse_mode("4755"), Ok(0o4755));
+        assert!(parse_mode("0868").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("@0"), Ok(0));
+        assert_eq!(parse_timestamp("@-86400"), Ok(-86400));
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Ok(0));
+        assert_eq!(parse_timestamp("2020-01-01T00:00:00Z"), Ok(1_577_836_800));
+        assert_eq!(parse_timestamp("2020-01-01T01:30:00+01:30"), Ok(1_577_836_800));
+        assert_eq!(parse_timestamp("2000-02-29T12:00:00-00:00"), Ok(951_825_600));
+
+        assert!(parse_timestamp("2020-01-01").is_err());
+        assert!(parse_timestamp("2020-13-01T00:00:00Z").is_err());
+        assert!(parse_timestamp("2020-01-01T00:00:00").is_err());
+        assert!(parse_timestamp("@yesterday").is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("a/./b/../c")), PathBuf::from("a/c"));
+        assert_eq!(normalize(Path::new("a/../../b")), PathBuf::from("../b"));
+        assert_eq!(normalize(Path::new("/a/../../b")), PathBuf::from("/b"));
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(relative_path(Path::new("/a/b/c"), Path::new("/a/x")),
+                   PathBuf::from("../../x"));
+        assert_eq!(relative_path(Path::new("/a"), Path::new("/a/b")), PathBuf::from("b"));
+        assert_eq!(relative_path(Path::new("/a"), Path::new("/a")), PathBuf::new());
+    }
+
+    #[test]
+    fn test_target_path() -> Result<()> {
+        let dir = tempdir()?;
+        let dest = dir.path().join("dest");
+        fs::create_dir(&dest)?;
+        let from = dir.path().join("src/file.txt");
+
+        // Into a directory, with or without a trailing slash.
+        assert_eq!(target_path(&from, &dest)?, dest.join("file.txt"));
+        let slashed = PathBuf::from(format!("{}/", dest.display()));
+        assert_eq!(target_path(&from, &slashed)?, dest.join("file.txt"));
+        let from_slashed = PathBuf::from(format!("{}/", dir.path().join("src").display()));
+        assert_eq!(target_path(&from_slashed, &dest)?, dest.join("src"));
+
+        // An explicit file, whether or not it exists.
+        let to = dest.join("other.txt");
+        assert_eq!(target_path(&from, &to)?, to);
+        fs::write(&to, "data")?;
+        assert_eq!(target_path(&from, &to)?, to);
+
+        // A missing directory isn't created.
+        let missing = PathBuf::from(format!("{}/", dir.path().join("missing").display()));
+        assert!(target_path(&from, &missing).is_err());
+        assert!(!dir.path().join("missing").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A simple framing for streaming a file through a pipe without
+//! losing its holes. The stream starts with `MAGIC`, followed by
+//! records of a one-byte tag and a big-endian u64 length:
+//!
+//! ```text
+//! 'D' <len> <len bytes of data>
+//! 'H' <len>                       a hole of len bytes
+//! 'E' 0                           end of file
+//! ```
+
+use log::debug;
+use std::cmp;
+use std::fs::File;
+use std::io::{ErrorKind as IOKind, Read, Write};
+
+use crate::errors::{io_err, Result, XcpError};
+use crate::operations::data_segments;
+use crate::options::Sparse;
+use crate::os::{copy_stream, cursor, is_regular, lseek, probably_sparse, Wence};
+use crate::progress::{BatchUpdater, CopyStats, Updater};
+
+const MAGIC: &[u8; 8] = b"XCPWIRE1";
+
+const DATA: u8 = b'D';
+const HOLE: u8 = b'H';
+const END: u8 = b'E';
+
+/// Chunk size for non-seekable sources, and for holes written to
+/// non-seekable destinations.
+const CHUNK: u64 = 1024 * 1024;
+
+fn write_header<W: Write>(out: &mut W, tag: u8, len: u64) -> Result<()> {
+    out.write_all(&[tag])?;
+    out.write_all(&len.to_be_bytes())?;
+    Ok(())
+}
+
+fn read_header<R: Read>(input: &mut R) -> Result<(u8, u64)> {
+    let mut buf = [0u8; 9];
+    input.read_exact(&mut buf)?;
+    let mut len = [0u8; 8];
+    len.copy_from_slice(&buf[1..]);
+    Ok((buf[0], u64::from_be_bytes(len)))
+}
+
+fn write_data<W: Write>(mut infd: &File, out: &mut W, len: u64,
+                        stats: &mut CopyStats) -> Result<()> {
+    write_header(out, DATA, len)?;
+    if copy_stream(&mut infd, out, len, stats)? != len {
+        return Err(io_err(IOKind::UnexpectedEof, "Source shrank while encoding."));
+    }
+    Ok(())
+}
+
+/// Encode the remainder of `infd` to `out`. Holes in a regular source
+/// are sent as markers if `sparse` selects the sparse copier; other
+/// sources are sent as data until EOF.
+pub fn encode<W: Write>(infd: &File, out: &mut W, sparse: Sparse,
+                        stats: &mut CopyStats, updates: &mut BatchUpdater) -> Result<u64>
+{
+    out.write_all(MAGIC)?;
+
+    let mut sent = 0;
+    if is_regular(infd)? {
+        let start = cursor(infd)?;
+        let len = infd.metadata()?.len();
+        let segments = match sparse {
+            Sparse::Always => data_segments(infd, len)?,
+            Sparse::Auto if probably_sparse(infd)? => data_segments(infd, len)?,
+            _ => vec![(0, len)],
+        };
+        debug!("Encoding {} data segments", segments.len());
+
+        let mut pos = start;
+        for (seg_start, seg_end) in segments {
+            let seg_start = cmp::max(seg_start, pos);
+            if seg_end <= seg_start {
+                continue;
+            }
+            if seg_start > pos {
+                write_header(out, HOLE, seg_start - pos)?;
+            }
+            lseek(infd, seg_start as i64, Wence::Set)?;
+            write_data(infd, out, seg_end - seg_start, stats)?;
+            updates.update(Ok(seg_end - pos))?;
+            pos = seg_end;
+        }
+        if len > pos {
+            write_header(out, HOLE, len - pos)?;
+            updates.update(Ok(len - pos))?;
+        }
+        sent = len - start;
+    } else {
+        let mut buf = vec![0u8; CHUNK as usize];
+        let mut reader = infd;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            write_header(out, DATA, n as u64)?;
+            out.write_all(&buf[..n])?;
+            updates.update(Ok(n as u64))?;
+            sent += n as u64;
+        }
+    }
+
+    write_header(out, END, 0)?;
+    out.flush()?;
+    Ok(sent)
+}
+
+/// Decode a stream produced by `encode()` into `outfd`. Holes are
+/// skipped over if the destination is a regular file, leaving it
+/// sparse; otherwise they are written out as zeros.
+pub fn decode<R: Read>(input: &mut R, outfd: &File,
+                       stats: &mut CopyStats, updates: &mut BatchUpdater) -> Result<u64>
+{
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(XcpError::InvalidSource { msg: "Input is not in xcp wire format." }.into());
+    }
+
+    let seekable = is_regular(outfd)?;
+    let start = if seekable { cursor(outfd)? } else { 0 };
+    let mut writer = outfd;
+    let mut received = 0;
+
+    loop {
+        let (tag, len) = read_header(input)?;
+        match tag {
+            DATA => {
+                if copy_stream(input, &mut writer, len, stats)? != len {
+                    return Err(io_err(IOKind::UnexpectedEof, "Truncated wire-format data."));
+                }
+            }
+            HOLE if seekable => {
+                lseek(outfd, len as i64, Wence::Cur)?;
+            }
+            HOLE => {
+                let zeros = vec![0u8; cmp::min(len, CHUNK) as usize];
+                let mut left = len;
+                while left > 0 {
+                    let n = cmp::min(left, CHUNK) as usize;
+                    writer.write_all(&zeros[..n])?;
+                    left -= n as u64;
+                }
+            }
+            END => break,
+            _ => return Err(XcpError::InvalidSource { msg: "Corrupt wire-format stream." }.into()),
+        }
+        updates.update(Ok(len))?;
+        received += len;
+    }
+
+    // A trailing hole leaves the cursor past the end of the file.
+    if seekable && outfd.metadata()?.len() < start + received {
+        outfd.set_len(start + received)?;
+    }
+    Ok(received)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{NopUpdater, StatusUpdate};
+    use std::fs::read;
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::io::FromRawFd;
+    use tempfile::tempdir;
+
+    fn pipe() -> Result<(File, File)> {
+        let mut fds = [0; 2];
+        let r = unsafe { libc::pipe(fds.as_mut_ptr()) };
+        if r != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+    }
+
+    fn nop_updater() -> BatchUpdater<'static> {
+        BatchUpdater {
+            sender: Box::new(NopUpdater {}),
+            stat: StatusUpdate::Copied(0),
+            batch_size: 64 * 1024,
+        }
+    }
+
+    #[test]
+    fn test_sparse_round_trip() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("copy.bin");
+        {
+            let mut fd = File::create(&from)?;
+            fd.seek(SeekFrom::Start(1024 * 1024))?;
+            fd.write_all(b"middle")?;
+            fd.set_len(4 * 1024 * 1024)?;
+        }
+
+        let mut stream = Vec::new();
+        let mut stats = CopyStats::default();
+        let sent = encode(&File::open(&from)?, &mut stream, Sparse::Auto,
+                          &mut stats, &mut nop_updater())?;
+        assert_eq!(sent, 4 * 1024 * 1024);
+        // Only the block containing the data is sent.
+        assert!(stream.len() < 64 * 1024);
+
+        let outfd = File::create(&to)?;
+        let received = decode(&mut stream.as_slice(), &outfd, &mut stats, &mut nop_updater())?;
+        assert_eq!(received, sent);
+        assert_eq!(read(&from)?, read(&to)?);
+        assert!(probably_sparse(&outfd)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_to_pipe_fills_holes() -> Result<()> {
+        let mut stream = MAGIC.to_vec();
+        for (tag, len) in &[(DATA, 3u64), (HOLE, 5), (DATA, 2), (END, 0)] {
+            write_header(&mut stream, *tag, *len)?;
+            if *tag == DATA {
+                stream.extend(vec![b'x'; *len as usize]);
+            }
+        }
+
+        let (rx, tx) = pipe()?;
+        let n = decode(&mut stream.as_slice(), &tx, &mut CopyStats::default(),
+                       &mut nop_updater())?;
+        drop(tx);
+        assert_eq!(n, 10);
+
+        let mut out = Vec::new();
+        (&rx).read_to_end(&mut out)?;
+        assert_eq!(out, b"xxx\0\0\0\0\0xx");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_rejects_raw_data() {
+        let r = decode(&mut &b"not the wire format"[..], &tempfile::tempfile().unwrap(),
+                       &mut CopyStats::default(), &mut nop_updater());
+        assert!(r.is_err());
+    }
+}
@@ -17,11 +17,13 @@
 use failure::Error;
 
 use escargot::CargoBuild;
-use std::fs::{create_dir_all, read, write, File, OpenOptions};
+use std::fs::{create_dir_all, hard_link, read, read_link, remove_file, write, File, OpenOptions};
 use std::io::{Seek, SeekFrom, Read, Write};
-use std::os::unix::fs::symlink;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
+use std::process::{Command, Output, Stdio};
 use std::result;
 use tempfile::tempdir;
 use uuid::Uuid;
@@ -47,8 +49,8 @@ fn tempdir_rel() -> Result<PathBuf, Error> {
 }
 
 fn create_file(path: &Path, text: &str) -> Result<(), Error> {
-    let file = File::create(&path)?;
-    write!(&file, "{}", text);
+    let file = File::create(path)?;
+    write!(&file, "{}", text)?;
     Ok(())
 }
 
@@ -64,32 +66,28 @@ fn create_sparse(file: &Path, head: u64, tail: u64) -> Result<u64, Error> {
     let data = "c00lc0d3";
     let len = 4096u64 * 4096 + data.len() as u64 + tail;
 
-    let out = Command::new("/usr/bin/truncate")
-        .args(&["-s", len.to_string().as_str(),
-                file.to_str().unwrap()])
-        .output()?;
-    assert!(out.status.success());
-
     let mut fd = OpenOptions::new()
         .write(true)
-        .append(false)
-        .open(&file)?;
+        .create(true)
+        .truncate(false)
+        .open(file)?;
+    fd.set_len(len)?;
 
     fd.seek(SeekFrom::Start(head))?;
-    write!(fd, "{}", data);
+    write!(fd, "{}", data)?;
 
     fd.seek(SeekFrom::Start(1024*4096))?;
-    write!(fd, "{}", data);
+    write!(fd, "{}", data)?;
 
     fd.seek(SeekFrom::Start(4096*4096))?;
-    write!(fd, "{}", data);
+    write!(fd, "{}", data)?;
 
-    Ok(len as u64)
+    Ok(len)
 }
 
 fn quickstat(file: &Path) -> Result<(i32, i32, i32), Error> {
     let out = Command::new("stat")
-        .args(&["--format", "%s %b %B",
+        .args(["--format", "%s %b %B",
                 file.to_str().unwrap()])
         .output()?;
     assert!(out.status.success());
@@ -155,19 +153,17 @@ fn dest_file_exists() -> TResult {
     let source_path = dir.path().join("source.txt");
     let dest_path = dir.path().join("dest.txt");
 
-    {
-        File::create(&source_path)?;
-        File::create(&dest_path)?;
-    }
+    create_file(&source_path, "new")?;
+    create_file(&dest_path, "orig")?;
     let out = run(&[
         "--no-clobber",
         source_path.to_str().unwrap(),
         dest_path.to_str().unwrap(),
     ])?;
 
-    assert!(!out.status.success());
-    let stderr = String::from_utf8(out.stderr)?;
-    assert!(stderr.contains("Destination file exists"));
+    // The copy is skipped rather than failed.
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "orig")?);
 
     Ok(())
 }
@@ -178,10 +174,8 @@ fn dest_file_in_dir_exists() -> TResult {
     let source_path = dir.path().join("source.txt");
     let dest_path = dir.path().join("dest.txt");
 
-    {
-        File::create(&source_path)?;
-        File::create(&dest_path)?;
-    }
+    create_file(&source_path, "new")?;
+    create_file(&dest_path, "orig")?;
 
     let out = run(&[
         "--no-clobber",
@@ -189,9 +183,38 @@ fn dest_file_in_dir_exists() -> TResult {
         dir.path().to_str().unwrap(),
     ])?;
 
-    assert!(!out.status.success());
-    let stderr = String::from_utf8(out.stderr)?;
-    assert!(stderr.contains("Destination file exists"));
+    // The copy is skipped rather than failed.
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "orig")?);
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_update() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new")?;
+    create_file(&dest_path, "orig")?;
+
+    // Destination is newer, so is kept.
+    let out = Command::new("touch")
+        .args(["-d", "2001-02-03 04:05:06", source_path.to_str().unwrap()])
+        .output()?;
+    assert!(out.status.success());
+    let out = run(&["--update", source_path.to_str().unwrap(), dest_path.to_str().unwrap()])?;
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "orig")?);
+
+    // Source is newer, so is copied.
+    let out = Command::new("touch")
+        .args(["-d", "2000-01-01 00:00:00", dest_path.to_str().unwrap()])
+        .output()?;
+    assert!(out.status.success());
+    let out = run(&["--update", source_path.to_str().unwrap(), dest_path.to_str().unwrap()])?;
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "new")?);
 
     Ok(())
 }
@@ -213,6 +236,24 @@ fn file_copy() -> TResult {
     Ok(())
 }
 
+#[test]
+fn file_copy_to_missing_dir() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("missing");
+    create_file(&source_path, "data")?;
+
+    let out = run(&[
+        source_path.to_str().unwrap(),
+        &format!("{}/", dest_path.to_str().unwrap()),
+    ])?;
+
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+
+    Ok(())
+}
+
 #[test]
 fn file_copy_rel() -> TResult {
     let dir = tempdir_rel()?;
@@ -456,7 +497,8 @@ fn dir_overwrite_with_noclobber() -> TResult {
         dest_base.to_str().unwrap(),
     ])?;
 
-    assert!(!out.status.success());
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "orig")?);
 
     Ok(())
 }
@@ -713,10 +755,7 @@ fn test_empty_sparse() -> TResult {
     let from = dir.path().join("sparse.bin");
     let to = dir.path().join("target.bin");
 
-    let out = Command::new("/usr/bin/truncate")
-        .args(&["-s", "1M", from.to_str().unwrap()])
-        .output()?;
-    assert!(out.status.success());
+    File::create(&from)?.set_len(1024*1024)?;
     assert_eq!(from.metadata()?.len(), 1024*1024);
 
     let out = run(&[
@@ -735,3 +774,1256 @@ fn test_empty_sparse() -> TResult {
 
     Ok(())
 }
+
+#[test]
+fn dir_copy_resume_with_manifest() -> TResult {
+    let dir = tempdir()?;
+    let source = dir.path().join("mydir");
+    create_dir_all(&source)?;
+    // Newlines are valid in filenames.
+    for name in &["one.txt", "two.txt", "new\nline.txt"] {
+        create_file(&source.join(name), name)?;
+    }
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base)?;
+    let dest = dest_base.join("mydir");
+    let manifest = dir.path().join("manifest");
+
+    let out = run(&[
+        "-r",
+        "--manifest", manifest.to_str().unwrap(),
+        source.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    // Simulate an interruption before the last file completed; drop
+    // its manifest entry and its destination.
+    let entries = String::from_utf8(read(&manifest)?)?;
+    let mut lines = entries.split_terminator('\0').collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 3);
+    let last = lines.pop().unwrap();
+    let unfinished = Path::new(last.splitn(4, '\t').last().unwrap()).file_name().unwrap();
+    write(&manifest, lines.iter().map(|l| format!("{}\0", l)).collect::<String>())?;
+    std::fs::remove_file(dest.join(unfinished))?;
+
+    // Mark the completed files so we can tell if they're recopied.
+    for line in &lines {
+        let name = Path::new(line.splitn(4, '\t').last().unwrap()).file_name().unwrap();
+        create_file(&dest.join(name), "completed")?;
+    }
+
+    let out = run(&[
+        "-r",
+        "--manifest", manifest.to_str().unwrap(),
+        source.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    for line in &lines {
+        let name = Path::new(line.splitn(4, '\t').last().unwrap()).file_name().unwrap();
+        assert!(file_contains(&dest.join(name), "completed")?);
+    }
+    assert!(file_contains(&dest.join(unfinished), unfinished.to_str().unwrap())?);
+    assert_eq!(String::from_utf8(read(&manifest)?)?.split_terminator('\0').count(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_bwlimit() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, vec![0x5a; 64 * 1024])?;
+
+    let start = std::time::Instant::now();
+    let out = run(&[
+        "--bwlimit", "128K",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+    assert!(start.elapsed() >= std::time::Duration::from_millis(500));
+    assert_eq!(read(&source_path)?, read(&dest_path)?);
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_methods_userspace_only() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+    create_file(&source_path, text)?;
+
+    let out = run(&[
+        "-vvv",
+        "--methods", "userspace",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, text)?);
+
+    let log = String::from_utf8(out.stdout)? + &String::from_utf8(out.stderr)?;
+    assert!(log.contains("Userspace: copied"));
+    assert!(!log.contains("CopyFileRange: copied"));
+    assert!(!log.contains("Reflink"));
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_methods_invalid() -> TResult {
+    let out = run(&["--methods", "carrier-pigeon", "/etc/hosts", "/dev/null"])?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("Unknown copy method"));
+
+    Ok(())
+}
+
+fn create_pattern(path: &Path, len: usize) -> Result<Vec<u8>, Error> {
+    let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    write(path, &data)?;
+    Ok(data)
+}
+
+#[test]
+fn file_copy_resume() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let data = create_pattern(&source_path, 1024 * 1024)?;
+
+    // Simulate an interrupted copy.
+    write(&dest_path, &data[..512 * 1024])?;
+
+    let out = run(&[
+        "-v",
+        "--resume",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+    let log = String::from_utf8(out.stdout)? + &String::from_utf8(out.stderr)?;
+    assert!(log.contains("Resuming copy"));
+
+    assert_eq!(read(&dest_path)?, data);
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_verify_resume_mismatch() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let data = create_pattern(&source_path, 1024 * 1024)?;
+
+    // A partial destination that doesn't match the source.
+    write(&dest_path, vec![0xff; 512 * 1024])?;
+
+    let out = run(&[
+        "-v",
+        "--verify-resume",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+    let log = String::from_utf8(out.stdout)? + &String::from_utf8(out.stderr)?;
+    assert!(!log.contains("Resuming copy"));
+
+    assert_eq!(read(&dest_path)?, data);
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_report_fragmentation() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    create_pattern(&source_path, 1024 * 1024)?;
+
+    let out = run(&[
+        "-v",
+        "--preallocate",
+        "--report-fragmentation", "1000",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+    assert_eq!(read(&source_path)?, read(&dest_path)?);
+
+    let log = String::from_utf8(out.stdout)? + &String::from_utf8(out.stderr)?;
+    assert!(log.contains(" extents"));
+    assert!(!log.contains("is fragmented"));
+
+    Ok(())
+}
+
+/// Mounts a small tmpfs over the directory for the lifetime of the
+/// guard. Requires root; returns `None` if the mount fails.
+struct TmpfsMount(PathBuf);
+
+impl TmpfsMount {
+    fn new(dir: &Path, size: &str) -> Option<TmpfsMount> {
+        let status = Command::new("mount")
+            .args(["-t", "tmpfs", "-o", &format!("size={}", size), "tmpfs"])
+            .arg(dir)
+            .stderr(std::process::Stdio::null())
+            .status()
+            .ok()?;
+        if status.success() {
+            Some(TmpfsMount(dir.to_path_buf()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for TmpfsMount {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(&self.0).status();
+    }
+}
+
+#[test]
+fn file_copy_out_of_space_removes_dest() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.bin");
+    let mount_dir = dir.path().join("small");
+    create_dir_all(&mount_dir)?;
+    create_pattern(&source_path, 1024 * 1024)?;
+
+    let _mount = match TmpfsMount::new(&mount_dir, "64k") {
+        Some(mount) => mount,
+        None => return Ok(()),
+    };
+    let dest_path = mount_dir.join("dest.bin");
+
+    let out = run(&[source_path.to_str().unwrap(), dest_path.to_str().unwrap()])?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("OutOfSpace"));
+    assert!(!dest_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_out_of_space_keeps_existing_dest() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.bin");
+    let mount_dir = dir.path().join("small");
+    create_dir_all(&mount_dir)?;
+    create_pattern(&source_path, 1024 * 1024)?;
+
+    let _mount = match TmpfsMount::new(&mount_dir, "64k") {
+        Some(mount) => mount,
+        None => return Ok(()),
+    };
+    let dest_path = mount_dir.join("dest.bin");
+    create_file(&dest_path, "existing")?;
+
+    let out = run(&[source_path.to_str().unwrap(), dest_path.to_str().unwrap()])?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("OutOfSpace"));
+    assert!(dest_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn copy_stdin_to_file() -> TResult {
+    let dir = tempdir()?;
+    let dest_path = dir.path().join("dest.bin");
+    let data = (0..512 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+
+    let mut child = get_command()?
+        .args(["-", dest_path.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(&data)?;
+    assert!(child.wait()?.success());
+
+    assert_eq!(read(&dest_path)?, data);
+
+    Ok(())
+}
+
+#[test]
+fn copy_file_to_stdout() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.bin");
+    let data = create_pattern(&source_path, 512 * 1024)?;
+
+    let out = run(&["-v", source_path.to_str().unwrap(), "-"])?;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, data);
+
+    Ok(())
+}
+
+#[test]
+fn copy_sparse_through_wire_pipe() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("sparse.bin");
+    let dest_path = dir.path().join("dest.bin");
+    {
+        let mut fd = File::create(&source_path)?;
+        fd.seek(SeekFrom::Start(1024 * 1024))?;
+        write!(fd, "data")?;
+        fd.set_len(8 * 1024 * 1024)?;
+    }
+
+    let mut sender = get_command()?
+        .args(["--wire", source_path.to_str().unwrap(), "-"])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let receiver = get_command()?
+        .args(["--wire", "-", dest_path.to_str().unwrap()])
+        .stdin(sender.stdout.take().unwrap())
+        .status()?;
+    assert!(sender.wait()?.success());
+    assert!(receiver.success());
+
+    assert_eq!(read(&source_path)?, read(&dest_path)?);
+    let meta = dest_path.metadata()?;
+    assert!(meta.blocks() * 512 < meta.len());
+
+    Ok(())
+}
+
+fn create_link_tree(dir: &Path) -> Result<PathBuf, Error> {
+    let source_path = dir.join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("file.txt"), "orig")?;
+    symlink("file.txt", source_path.join("link.txt"))?;
+    symlink("nonexistent.txt", source_path.join("broken.txt"))?;
+    Ok(source_path)
+}
+
+#[test]
+fn dir_copy_symlinks_copy() -> TResult {
+    let dir = tempdir()?;
+    let source_path = create_link_tree(dir.path())?;
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "-r",
+        "--symlinks", "copy",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let link = dest_base.join("link.txt");
+    assert!(link.symlink_metadata()?.file_type().is_symlink());
+    assert_eq!(read_link(&link)?, PathBuf::from("file.txt"));
+    assert!(file_contains(&link, "orig")?);
+
+    // Broken links are recreated as-is.
+    let broken = dest_base.join("broken.txt");
+    assert!(broken.symlink_metadata()?.file_type().is_symlink());
+    assert_eq!(read_link(&broken)?, PathBuf::from("nonexistent.txt"));
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_symlinks_follow() -> TResult {
+    let dir = tempdir()?;
+    let source_path = create_link_tree(dir.path())?;
+    remove_file(source_path.join("broken.txt"))?;
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "-r",
+        "--symlinks", "follow",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let link = dest_base.join("link.txt");
+    assert!(link.symlink_metadata()?.file_type().is_file());
+    assert!(file_contains(&link, "orig")?);
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_symlinks_skip() -> TResult {
+    let dir = tempdir()?;
+    let source_path = create_link_tree(dir.path())?;
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "-r",
+        "--symlinks", "skip",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest_base.join("file.txt"), "orig")?);
+    assert!(dest_base.join("link.txt").symlink_metadata().is_err());
+    assert!(dest_base.join("broken.txt").symlink_metadata().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn copy_broken_symlink_source() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("broken.txt");
+    let dest_path = dir.path().join("dest.txt");
+    symlink("nonexistent.txt", &source_path)?;
+
+    let out = run(&[
+        "--symlinks", "copy",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+    assert_eq!(read_link(&dest_path)?, PathBuf::from("nonexistent.txt"));
+
+    Ok(())
+}
+
+#[test]
+fn copy_symlink_source_follows_by_default() -> TResult {
+    let dir = tempdir()?;
+    let source_file = dir.path().join("source.txt");
+    let source_path = dir.path().join("link.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_file, "orig")?;
+    symlink(&source_file, &source_path)?;
+
+    let out = run(&[source_path.to_str().unwrap(), dest_path.to_str().unwrap()])?;
+    assert!(out.status.success());
+    assert!(dest_path.symlink_metadata()?.file_type().is_file());
+    assert!(file_contains(&dest_path, "orig")?);
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_fixed_mtime() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_base = dir.path().join("dest");
+    create_dir_all(source_path.join("sub"))?;
+    create_file(&source_path.join("a.txt"), "a")?;
+    create_file(&source_path.join("sub/b.txt"), "b")?;
+
+    let out = run(&[
+        "-r",
+        "--mtime", "@0",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    for file in &["a.txt", "sub/b.txt"] {
+        let meta = dest_base.join(file).metadata()?;
+        assert_eq!(meta.mtime(), 0);
+        assert_eq!(meta.mtime_nsec(), 0);
+        assert_eq!(meta.atime(), 0);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_preserve_timestamps() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data")?;
+
+    // Backdate the source so the copy's own timestamps would differ.
+    let out = Command::new("touch")
+        .args(["-d", "2001-02-03 04:05:06.789", source_path.to_str().unwrap()])
+        .output()?;
+    assert!(out.status.success());
+
+    let out = run(&[
+        "--preserve", "mode,timestamps",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let (src, dest) = (source_path.metadata()?, dest_path.metadata()?);
+    assert_eq!(src.mtime(), dest.mtime());
+    assert_eq!(src.mtime_nsec(), dest.mtime_nsec());
+
+    Ok(())
+}
+
+#[test]
+fn file_copy_without_preserve_mode() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.sh");
+    create_file(&source_path, "data")?;
+    std::fs::set_permissions(&source_path, PermissionsExt::from_mode(0o751))?;
+
+    let dest_path = dir.path().join("mode.sh");
+    let out = run(&[source_path.to_str().unwrap(), dest_path.to_str().unwrap()])?;
+    assert!(out.status.success());
+    assert_eq!(dest_path.metadata()?.mode() & 0o7777, 0o751);
+
+    // New files get the default mode, which is never executable.
+    let dest_path = dir.path().join("no-mode.sh");
+    let out = run(&[
+        "--preserve", "timestamps",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+    assert_eq!(dest_path.metadata()?.mode() & 0o111, 0);
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_preserve_timestamps() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub"))?;
+    create_file(&source_path.join("file.txt"), "data")?;
+    create_file(&source_path.join("sub/nested.txt"), "more data")?;
+
+    // Backdate the directories after their contents are written.
+    for d in &[source_path.join("sub"), source_path.clone()] {
+        let out = Command::new("touch")
+            .args(["-d", "2001-02-03 04:05:06.789", d.to_str().unwrap()])
+            .output()?;
+        assert!(out.status.success());
+    }
+
+    let out = run(&[
+        "-r",
+        "--preserve", "mode,timestamps",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    for sub in &["", "sub"] {
+        let (src, dest) = (source_path.join(sub).metadata()?, dest_path.join(sub).metadata()?);
+        assert_eq!(src.mtime(), dest.mtime());
+        assert_eq!(src.mtime_nsec(), dest.mtime_nsec());
+    }
+    assert!(file_contains(&dest_path.join("sub/nested.txt"), "more data")?);
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_under_low_fd_limit() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path)?;
+    for i in 0..2000 {
+        create_file(&source_path.join(format!("file{}.txt", i)), &i.to_string())?;
+    }
+
+    // Copying must not hold on to file descriptors; the limit is well
+    // under the number of files.
+    let xcp = CargoBuild::new().run()?.path().to_path_buf();
+    let out = Command::new("sh")
+        .args(["-c", "ulimit -n 32 && exec \"$@\"", "sh", xcp.to_str().unwrap(),
+               "-r", "--no-progress",
+               source_path.to_str().unwrap(),
+               dest_path.to_str().unwrap()])
+        .output()?;
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    for i in (0..2000).step_by(97) {
+        assert!(file_contains(&dest_path.join(format!("file{}.txt", i)), &i.to_string())?);
+    }
+    assert_eq!(dest_path.read_dir()?.count(), 2000);
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_recreates_fifos_and_sockets() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("file.txt"), "data")?;
+    let out = Command::new("mkfifo")
+        .args(["-m", "0640", source_path.join("pipe").to_str().unwrap()])
+        .output()?;
+    assert!(out.status.success());
+    UnixListener::bind(source_path.join("socket"))?;
+
+    // Reading the FIFO would block forever.
+    let out = run(&[
+        "-r",
+        "--no-progress",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let pipe = dest_path.join("pipe").symlink_metadata()?;
+    assert!(pipe.file_type().is_fifo());
+    assert_eq!(pipe.mode() & 0o777, 0o640);
+    assert!(dest_path.join("socket").symlink_metadata()?.file_type().is_socket());
+    assert!(file_contains(&dest_path.join("file.txt"), "data")?);
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_uniform_mode_and_owner() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub"))?;
+    create_file(&source_path.join("one.txt"), "one")?;
+    create_file(&source_path.join("sub/two.sh"), "two")?;
+    std::fs::set_permissions(source_path.join("sub/two.sh"),
+                             std::fs::Permissions::from_mode(0o755))?;
+
+    let out = run(&[
+        "-r",
+        "--uniform-mode", "0640",
+        "--uniform-dir-mode", "0750",
+        "--uniform-owner", "1234:5678",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    for (path, mode) in &[("", 0o750), ("sub", 0o750), ("one.txt", 0o640), ("sub/two.sh", 0o640)] {
+        let meta = dest_path.join(path).metadata()?;
+        assert_eq!(meta.mode() & 0o7777, *mode, "{}", path);
+        // Changing the owner requires root.
+        if unsafe { libc::geteuid() } == 0 {
+            assert_eq!((meta.uid(), meta.gid()), (1234, 5678), "{}", path);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_self_heal() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("one.txt"), "one")?;
+    create_file(&source_path.join("two.txt"), "two")?;
+
+    let out = run(&[
+        "-r", "--self-heal", "3",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    assert_eq!(read(dest_path.join("one.txt"))?, b"one");
+    assert_eq!(read(dest_path.join("two.txt"))?, b"two");
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_preserve_hardlinks() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_base = dir.path().join("dest");
+    create_dir_all(source_path.join("sub"))?;
+    create_file(&source_path.join("a.txt"), "linked")?;
+    hard_link(source_path.join("a.txt"), source_path.join("sub/b.txt"))?;
+    // Only one of these links is in the copy set.
+    create_file(&source_path.join("c.txt"), "outside")?;
+    hard_link(source_path.join("c.txt"), dir.path().join("c.txt"))?;
+
+    let out = run(&[
+        "-r",
+        "--preserve", "mode,links",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let a = dest_base.join("a.txt").metadata()?;
+    let b = dest_base.join("sub/b.txt").metadata()?;
+    assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+    assert_eq!(a.nlink(), 2);
+    assert!(file_contains(&dest_base.join("sub/b.txt"), "linked")?);
+
+    let c = dest_base.join("c.txt").metadata()?;
+    assert_eq!(c.nlink(), 1);
+    assert!(file_contains(&dest_base.join("c.txt"), "outside")?);
+
+    Ok(())
+}
+
+#[test]
+fn preserve_hardlinks_across_sources() -> TResult {
+    let dir = tempdir()?;
+    let first = dir.path().join("first");
+    let second = dir.path().join("second");
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&first)?;
+    create_dir_all(&dest_base)?;
+    create_file(&first.join("a.txt"), "linked")?;
+    hard_link(first.join("a.txt"), &second)?;
+
+    let out = run(&[
+        "-r",
+        "--preserve", "links",
+        first.to_str().unwrap(),
+        second.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let a = dest_base.join("first/a.txt").metadata()?;
+    let b = dest_base.join("second").metadata()?;
+    assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+    assert_eq!(a.nlink(), 2);
+
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> Result<(), Error> {
+    use std::ffi::CString;
+    let cpath = CString::new(path.to_str().unwrap())?;
+    let cname = CString::new(name)?;
+    let value_ptr = value.as_ptr() as *const libc::c_void;
+    #[cfg(target_os = "linux")]
+    let r = unsafe { libc::setxattr(cpath.as_ptr(), cname.as_ptr(), value_ptr, value.len(), 0) };
+    #[cfg(target_os = "macos")]
+    let r = unsafe { libc::setxattr(cpath.as_ptr(), cname.as_ptr(), value_ptr, value.len(), 0, 0) };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    use std::ffi::CString;
+    let cpath = CString::new(path.to_str().unwrap()).unwrap();
+    let cname = CString::new(name).unwrap();
+    let mut buf = vec![0u8; 1024];
+    let buf_ptr = buf.as_mut_ptr() as *mut libc::c_void;
+    #[cfg(target_os = "linux")]
+    let r = unsafe { libc::getxattr(cpath.as_ptr(), cname.as_ptr(), buf_ptr, buf.len()) };
+    #[cfg(target_os = "macos")]
+    let r = unsafe { libc::getxattr(cpath.as_ptr(), cname.as_ptr(), buf_ptr, buf.len(), 0, 0) };
+    if r < 0 {
+        return None;
+    }
+    buf.truncate(r as usize);
+    Some(buf)
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn file_copy_named_xattrs() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data")?;
+    for name in &["user.foo", "user.bar", "user.baz", "user.volatile"] {
+        set_xattr(&source_path, name, name.as_bytes())?;
+    }
+
+    let out = run(&[
+        "--xattr-name", "user.foo,user.bar,user.volatile",
+        "--xattr-exclude", "user.volatile",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    assert_eq!(get_xattr(&dest_path, "user.foo"), Some(b"user.foo".to_vec()));
+    assert_eq!(get_xattr(&dest_path, "user.bar"), Some(b"user.bar".to_vec()));
+    assert_eq!(get_xattr(&dest_path, "user.baz"), None);
+    assert_eq!(get_xattr(&dest_path, "user.volatile"), None);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn file_copy_all_xattrs() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data")?;
+    set_xattr(&source_path, "user.foo", b"foo")?;
+    set_xattr(&source_path, "user.bar", b"")?;
+
+    let out = run(&[
+        "--preserve", "mode,xattr",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    assert_eq!(get_xattr(&dest_path, "user.foo"), Some(b"foo".to_vec()));
+    assert_eq!(get_xattr(&dest_path, "user.bar"), Some(Vec::new()));
+
+    Ok(())
+}
+
+/// Create a character device with the same number as /dev/null,
+/// returning false if we lack the privilege to do so.
+#[cfg(target_os = "linux")]
+fn make_null_device(path: &Path) -> result::Result<bool, Error> {
+    let cpath = std::ffi::CString::new(path.to_str().unwrap())?;
+    let r = unsafe { libc::mknod(cpath.as_ptr(), libc::S_IFCHR | 0o644, libc::makedev(1, 3)) };
+    if r != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EPERM) {
+            return Ok(false);
+        }
+        return Err(err.into());
+    }
+    Ok(true)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn device_node_recreated() -> TResult {
+    use std::os::unix::fs::FileTypeExt;
+
+    let dir = tempdir()?;
+    let source_path = dir.path().join("null");
+    let dest_path = dir.path().join("dest");
+    if !make_null_device(&source_path)? {
+        return Ok(());
+    }
+
+    let out = run(&[
+        "--no-progress",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let meta = dest_path.symlink_metadata()?;
+    assert!(meta.file_type().is_char_device());
+    assert_eq!(meta.rdev(), source_path.metadata()?.rdev());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn device_contents_copied() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("null");
+    let dest_path = dir.path().join("dest");
+    if !make_null_device(&source_path)? {
+        return Ok(());
+    }
+
+    let out = run(&[
+        "--no-progress",
+        "--device-contents",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let meta = dest_path.symlink_metadata()?;
+    assert!(meta.file_type().is_file());
+    assert_eq!(meta.len(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_fsync_modes() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join("one.txt"), "one")?;
+    create_file(&source_path.join("two.txt"), "two")?;
+
+    for mode in &["file", "batch", "syncfs"] {
+        let dest_path = dir.path().join(mode);
+        let out = run(&[
+            "-r",
+            &format!("--fsync={}", mode),
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])?;
+        assert!(out.status.success(), "{}", mode);
+        assert!(file_contains(&dest_path.join("one.txt"), "one")?);
+        assert!(file_contains(&dest_path.join("two.txt"), "two")?);
+    }
+
+    let out = run(&[
+        "--fsync=often",
+        source_path.join("one.txt").to_str().unwrap(),
+        dir.path().join("three.txt").to_str().unwrap(),
+    ])?;
+    assert!(!out.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_metadata_only() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let backup = dir.path().join("backup");
+    let dest_path = backup.join("mydir");
+    create_dir_all(&source_path)?;
+    create_dir_all(&backup)?;
+    create_file(&source_path.join("one.txt"), "one")?;
+    create_file(&source_path.join("two.txt"), "two")?;
+
+    let out = run(&[
+        "-r",
+        "--preserve", "mode,timestamps",
+        source_path.to_str().unwrap(),
+        backup.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    // Drift the metadata, change one file's contents, and add another.
+    std::fs::set_permissions(source_path.join("one.txt"), std::fs::Permissions::from_mode(0o600))?;
+    std::fs::set_permissions(source_path.join("two.txt"), std::fs::Permissions::from_mode(0o751))?;
+    create_file(&source_path.join("two.txt"), "changed")?;
+    create_file(&source_path.join("three.txt"), "three")?;
+    let before = dest_path.join("one.txt").metadata()?;
+
+    let out = run(&[
+        "-r",
+        "--metadata-only",
+        source_path.to_str().unwrap(),
+        backup.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success());
+
+    let after = dest_path.join("one.txt").metadata()?;
+    assert_eq!(after.mode() & 0o7777, 0o600);
+    assert_eq!(after.ino(), before.ino());
+    assert_eq!((after.mtime(), after.mtime_nsec()), (before.mtime(), before.mtime_nsec()));
+    assert!(file_contains(&dest_path.join("one.txt"), "one")?);
+
+    // Files with different contents, or none, are left alone.
+    assert!(file_contains(&dest_path.join("two.txt"), "two")?);
+    assert_ne!(dest_path.join("two.txt").metadata()?.mode() & 0o7777, 0o751);
+    assert!(!dest_path.join("three.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_parents_relative_symlinks() -> TResult {
+    let dir = tempdir()?;
+    let work = dir.path().join("work");
+    let dest = dir.path().join("backup/dest");
+    create_dir_all(work.join("a/b"))?;
+    create_dir_all(&dest)?;
+    create_file(&work.join("a/b/file.txt"), "inside")?;
+    create_file(&dir.path().join("outside.txt"), "outside")?;
+    symlink("file.txt", work.join("a/b/in_link"))?;
+    symlink("../../../outside.txt", work.join("a/b/out_link"))?;
+
+    let out = get_command()?
+        .current_dir(&work)
+        .args(["-r", "--parents", "a/b", dest.to_str().unwrap()])
+        .output()?;
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest.join("a/b/file.txt"), "inside")?);
+    // Links within the copy are unchanged.
+    assert_eq!(read_link(dest.join("a/b/in_link"))?, PathBuf::from("file.txt"));
+    // Links out of it would break at the new depth, so are rewritten.
+    assert_eq!(read_link(dest.join("a/b/out_link"))?, PathBuf::from("../../../../outside.txt"));
+    assert!(file_contains(&dest.join("a/b/out_link"), "outside")?);
+
+    Ok(())
+}
+
+#[test]
+fn dest_dir_symlink_followed() -> TResult {
+    let dir = tempdir()?;
+    let real = dir.path().join("real");
+    let link = dir.path().join("link");
+    let src = dir.path().join("src");
+    create_dir_all(&real)?;
+    create_dir_all(src.join("sub"))?;
+    symlink(&real, &link)?;
+    create_file(&dir.path().join("file.txt"), "single")?;
+    create_file(&src.join("sub/nested.txt"), "nested")?;
+
+    let out = run(&[dir.path().join("file.txt").to_str().unwrap(), link.to_str().unwrap()])?;
+    assert!(out.status.success());
+    let out = run(&["-r", src.to_str().unwrap(), link.to_str().unwrap()])?;
+    assert!(out.status.success());
+
+    // The link is untouched, and the files are in the real directory.
+    assert_eq!(read_link(&link)?, real);
+    assert!(file_contains(&real.join("file.txt"), "single")?);
+    assert!(file_contains(&real.join("src/sub/nested.txt"), "nested")?);
+
+    Ok(())
+}
+
+#[test]
+fn no_target_directory() -> TResult {
+    let dir = tempdir()?;
+    let real = dir.path().join("real");
+    let link = dir.path().join("link");
+    let src = dir.path().join("src");
+    create_dir_all(&real)?;
+    create_dir_all(&src)?;
+    symlink(&real, &link)?;
+    create_file(&src.join("file.txt"), "contents")?;
+
+    // The source's contents are copied into the existing directory.
+    let out = run(&["-r", "-T", src.to_str().unwrap(), real.to_str().unwrap()])?;
+    assert!(out.status.success());
+    assert!(file_contains(&real.join("file.txt"), "contents")?);
+    assert!(!real.join("src").exists());
+
+    // A symlinked destination isn't followed.
+    let out = run(&["-r", "-T", src.to_str().unwrap(), link.to_str().unwrap()])?;
+    assert!(!out.status.success());
+
+    // Nor is a file copied over a directory.
+    let out = run(&["-T", src.join("file.txt").to_str().unwrap(), real.to_str().unwrap()])?;
+    assert!(!out.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn dry_run_writes_nothing() -> TResult {
+    let dir = tempdir()?;
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(source.join("sub"))?;
+    create_file(&source.join("file.txt"), "contents")?;
+    create_file(&source.join("sub/nested.txt"), "nested")?;
+    symlink("file.txt", source.join("link"))?;
+
+    let out = run(&["-r", "--dry-run", source.to_str().unwrap(), dest.to_str().unwrap()])?;
+    assert!(out.status.success());
+    assert!(!dest.exists());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("sub/nested.txt"));
+    assert!(stdout.contains("symlink"));
+    assert!(stdout.contains("5 entries, 14B to copy"));
+
+    // Into an existing directory, which is left empty.
+    create_dir_all(&dest)?;
+    let out = run(&["-r", "--dry-run", "--parents", source.to_str().unwrap(), dest.to_str().unwrap()])?;
+    assert!(out.status.success());
+    assert_eq!(std::fs::read_dir(&dest)?.count(), 0);
+
+    Ok(())
+}
+
+/// Relative path, contents, mode, uid, gid, mtime and mtime_nsec.
+type EntryState = (PathBuf, Vec<u8>, u32, u32, u32, i64, i64);
+
+/// The contents and metadata of every entry under `root`, by
+/// relative path.
+fn tree_state(root: &Path) -> Result<Vec<EntryState>, Error> {
+    let mut entries = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let meta = path.symlink_metadata()?;
+        let contents = if meta.file_type().is_symlink() {
+            read_link(&path)?.into_os_string().into_vec()
+        } else if meta.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                pending.push(entry?.path());
+            }
+            Vec::new()
+        } else {
+            read(&path)?
+        };
+        entries.push((path.strip_prefix(root)?.to_path_buf(), contents, meta.mode(),
+                      meta.uid(), meta.gid(), meta.mtime(), meta.mtime_nsec()));
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+#[test]
+fn copy_reproducible() -> TResult {
+    let dir = tempdir()?;
+    let source = dir.path().join("source");
+    create_dir_all(source.join("sub"))?;
+    create_file(&source.join("file.txt"), "contents")?;
+    create_file(&source.join("sub/run.sh"), "#!/bin/sh")?;
+    std::fs::set_permissions(source.join("file.txt"), PermissionsExt::from_mode(0o600))?;
+    std::fs::set_permissions(source.join("sub/run.sh"), PermissionsExt::from_mode(0o750))?;
+    symlink("../file.txt", source.join("sub/link"))?;
+
+    let first = dir.path().join("first");
+    let second = dir.path().join("second");
+    for dest in &[&first, &second] {
+        let out = get_command()?
+            .env("SOURCE_DATE_EPOCH", "1500000000")
+            .args(["-r", "--reproducible", source.to_str().unwrap(), dest.to_str().unwrap()])
+            .output()?;
+        assert!(out.status.success());
+        // Give the clock a chance to differ between the two copies.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+
+    let state = tree_state(&first)?;
+    assert_eq!(state, tree_state(&second)?);
+    assert_eq!(state.len(), 5);
+    assert_eq!(first.join("file.txt").metadata()?.mode() & 0o7777, 0o644);
+    assert_eq!(first.join("sub/run.sh").metadata()?.mode() & 0o7777, 0o755);
+    assert_eq!(first.join("sub").metadata()?.mode() & 0o7777, 0o755);
+    assert!(state.iter().all(|e| e.5 == 1_500_000_000 && e.6 == 0));
+
+    Ok(())
+}
+
+#[test]
+fn map_file_copies_to_mapped_destinations() -> TResult {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    let dest = dir.path().join("dest");
+    create_dir_all(src.join("sub"))?;
+    create_file(&src.join("a.txt"), "file a")?;
+    create_file(&src.join("sub/b.txt"), "file b")?;
+    create_file(&src.join("c d.txt"), "file c d")?;
+
+    let pairs = [
+        (src.join("a.txt"), dest.join("one/a-renamed.txt")),
+        (src.join("sub/b.txt"), dest.join("two/deeper/b.txt")),
+        (src.join("c d.txt"), dest.join("c d.txt")),
+    ];
+    let map = dir.path().join("map.txt");
+    let mut content = String::new();
+    for (from, to) in &pairs {
+        content.push_str(&format!("{}\t{}\n", from.display(), to.display()));
+    }
+    create_file(&map, &content)?;
+
+    let out = run(&["--map-file", map.to_str().unwrap()])?;
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest.join("one/a-renamed.txt"), "file a")?);
+    assert!(file_contains(&dest.join("two/deeper/b.txt"), "file b")?);
+    assert!(file_contains(&dest.join("c d.txt"), "file c d")?);
+    assert!(!dest.join("a.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn map_file_malformed_line() -> TResult {
+    let dir = tempdir()?;
+    let map = dir.path().join("map.txt");
+    create_file(&map, "no-destination-here\n")?;
+
+    let out = run(&["--map-file", map.to_str().unwrap()])?;
+    assert!(!out.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn dry_run_carries_on_past_errors() -> TResult {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    let dest = dir.path().join("dest");
+    create_dir_all(&src)?;
+    create_file(&src.join("a.txt"), "file a")?;
+    create_file(&src.join("c.txt"), "file c")?;
+
+    let map = dir.path().join("map.txt");
+    let mut content = String::new();
+    for name in &["a.txt", "missing.txt", "c.txt"] {
+        content.push_str(&format!("{}\t{}\n", src.join(name).display(), dest.join(name).display()));
+    }
+    create_file(&map, &content)?;
+
+    let out = run(&["--dry-run", "--map-file", map.to_str().unwrap()])?;
+    assert!(!out.status.success());
+    assert!(!dest.exists());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("a.txt"));
+    assert!(stdout.contains("c.txt"));
+    assert!(stdout.contains("2 entries, 12B to copy, 1 failed"));
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_long_filename() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    let name = "x".repeat(250);
+    create_dir_all(&source_path)?;
+    create_file(&source_path.join(&name), "data")?;
+
+    // New files in recursive copies go via a temporary name.
+    let out = run(&[
+        "-r",
+        "--no-progress",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(file_contains(&dest_path.join(&name), "data")?);
+
+    Ok(())
+}
+
+#[test]
+fn dir_copy_keeps_hardlinked_destinations() -> TResult {
+    let dir = tempdir()?;
+    let source_path = dir.path().join("mydir");
+    let dest_base = dir.path().join("dest");
+    let dest_file = dest_base.join("mydir/file.txt");
+    let link = dir.path().join("link.txt");
+    create_dir_all(&source_path)?;
+    create_dir_all(dest_base.join("mydir"))?;
+    create_file(&source_path.join("file.txt"), "new content")?;
+    create_file(&dest_file, "orig")?;
+    hard_link(&dest_file, &link)?;
+
+    // Existing files are written in place rather than renamed over,
+    // so the link still shares the destination's inode.
+    let out = run(&[
+        "-r",
+        "--no-progress",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])?;
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(file_contains(&dest_file, "new content")?);
+    assert!(file_contains(&link, "new content")?);
+    assert_eq!(dest_file.metadata()?.ino(), link.metadata()?.ino());
+
+    Ok(())
+}